@@ -3,16 +3,19 @@ use std::collections::HashMap;
 use amimono::{config::ComponentConfig, rpc::RpcResult};
 use rand::seq::IndexedRandom;
 
-use crate::shared::Ad;
+use crate::shared::{Ad, Health, RpcContext, serve_rpc};
 
 mod ops {
-    use crate::shared::Ad;
+    use crate::shared::{Ad, Health, RpcContext};
 
-    amimono::rpc_ops! {
-        fn get_ads(context_keys: Vec<String>) -> Vec<Ad>;
+    crate::rpc_ops! {
+        fn get_ads(cx: RpcContext, context_keys: Vec<String>) -> Vec<Ad>;
+        fn health(cx: RpcContext) -> Health;
     }
 }
 
+pub const LABEL: &str = "adservice";
+
 const MAX_ADS_TO_SERVE: usize = 2;
 
 pub struct AdService {
@@ -73,27 +76,34 @@ impl ops::Handler for AdService {
         AdService::new()
     }
 
-    async fn get_ads(&self, context_keys: Vec<String>) -> RpcResult<Vec<Ad>> {
-        log::info!("received ad request (context_words={:?})", context_keys);
-        let ads = if context_keys.len() > 0 {
-            context_keys
-                .iter()
-                .flat_map(|k| self.get_ads_by_category(k).into_iter())
-                .collect()
-        } else {
-            self.get_random_ads()
-        };
-        let ads = if ads.len() == 0 {
-            self.get_random_ads()
-        } else {
-            ads
-        };
-        Ok(ads)
+    async fn get_ads(&self, cx: RpcContext, context_keys: Vec<String>) -> RpcResult<Vec<Ad>> {
+        serve_rpc(LABEL, "get_ads", cx, async {
+            log::info!("received ad request (context_words={:?})", context_keys);
+            let ads = if context_keys.len() > 0 {
+                context_keys
+                    .iter()
+                    .flat_map(|k| self.get_ads_by_category(k).into_iter())
+                    .collect()
+            } else {
+                self.get_random_ads()
+            };
+            let ads = if ads.len() == 0 {
+                self.get_random_ads()
+            } else {
+                ads
+            };
+            Ok(ads)
+        })
+        .await
+    }
+
+    async fn health(&self, cx: RpcContext) -> RpcResult<Health> {
+        serve_rpc(LABEL, "health", cx, async { Ok(Health::new(LABEL)) }).await
     }
 }
 
 pub type AdClient = ops::Client<AdService>;
 
 pub fn component() -> ComponentConfig {
-    ops::component::<AdService>("adservice".to_owned())
+    ops::component::<AdService>(LABEL.to_owned())
 }
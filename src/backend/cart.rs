@@ -2,14 +2,18 @@ use std::collections::HashMap;
 
 use amimono::{config::ComponentConfig, rpc::RpcResult};
 use amimono_haze::crdt::{
-    Crdt, CrdtClient, StoredCrdt,
+    Crdt, StoredCrdt,
     crdt::{Max, Version},
 };
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
-use crate::shared::CartItem;
+use crate::{
+    backend::InventoryClient,
+    shared::{CartItem, CrdtClient, ErrorKind, Health, RpcContext, metrics, serve_rpc},
+};
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, JsonSchema)]
 pub struct Cart {
     pub user_id: String,
     pub items: Vec<CartItem>,
@@ -53,53 +57,141 @@ impl CartData {
 
 mod ops {
     use super::Cart;
-    use crate::shared::CartItem;
-
-    amimono::rpc_ops! {
-        fn add_item(user_id: String, item: CartItem) -> ();
-        fn get_cart(user_id: String) -> Cart;
-        fn empty_cart(user_id: String) -> ();
+    use crate::shared::{CartItem, Health, RpcContext};
+
+    crate::rpc_ops! {
+        fn add_item(cx: RpcContext, user_id: String, item: CartItem) -> ();
+        fn update_item(cx: RpcContext, user_id: String, item: CartItem) -> ();
+        fn get_cart(cx: RpcContext, user_id: String) -> Cart;
+        fn empty_cart(cx: RpcContext, user_id: String) -> ();
+        fn merge_cart(cx: RpcContext, from_user_id: String, into_user_id: String) -> ();
+        fn health(cx: RpcContext) -> Health;
     }
 }
 
+pub const LABEL: &str = "cartservice";
+
 pub struct CartService {
     crdt: CrdtClient<CartData>,
+    inventory: InventoryClient,
 }
 
 impl ops::Handler for CartService {
     async fn new() -> CartService {
         CartService {
             crdt: CrdtClient::new("cart".to_owned()),
+            inventory: InventoryClient::new(),
         }
     }
 
-    async fn add_item(&self, user_id: String, item: CartItem) -> RpcResult<()> {
-        log::info!("add_item({}, {})", user_id, item.product_id);
-        let cart = {
-            let mut cart = self.crdt.get_or_default(&user_id).await?;
-            let qty = cart.items.1.entry(item.product_id).or_insert(Max(0));
-            qty.0 += item.quantity;
-            cart
-        };
-        self.crdt.put(&user_id, cart).await?;
-        Ok(())
+    async fn add_item(&self, cx: RpcContext, user_id: String, item: CartItem) -> RpcResult<()> {
+        serve_rpc(LABEL, "add_item", cx, async {
+            log::info!("add_item({})", item.product_id);
+            let quantity = item.quantity;
+            let available = self
+                .inventory
+                .get_stock(RpcContext::current(), item.product_id.clone())
+                .await?;
+            let cart = {
+                let mut cart = self.crdt.get_or_default(&user_id).await?;
+                let qty = cart.items.1.entry(item.product_id).or_insert(Max(0));
+                if qty.0 + item.quantity > available {
+                    return Err(ErrorKind::OutOfStock.error(format!(
+                        "cannot add {} to cart: only {} available",
+                        item.quantity, available
+                    )));
+                }
+                qty.0 += item.quantity;
+                cart
+            };
+            self.crdt.put(&user_id, cart).await?;
+            metrics::cart_added(quantity);
+            Ok(())
+        })
+        .await
+    }
+
+    /// Sets the quantity of an item outright, removing it at zero. Lowering a
+    /// quantity can't be expressed by merging the per-item maximums, so the
+    /// whole cart is rewritten under a new version, the same as emptying it.
+    async fn update_item(&self, cx: RpcContext, user_id: String, item: CartItem) -> RpcResult<()> {
+        serve_rpc(LABEL, "update_item", cx, async {
+            log::info!("update_item({}, {})", item.product_id, item.quantity);
+            let cart = {
+                let mut cart = self.crdt.get_or_default(&user_id).await?;
+                if item.quantity == 0 {
+                    cart.items.1.remove(&item.product_id);
+                } else {
+                    let available = self
+                        .inventory
+                        .get_stock(RpcContext::current(), item.product_id.clone())
+                        .await?;
+                    if item.quantity > available {
+                        return Err(ErrorKind::OutOfStock.error(format!(
+                            "cannot set quantity to {}: only {} available",
+                            item.quantity, available
+                        )));
+                    }
+                    cart.items.1.insert(item.product_id, Max(item.quantity));
+                }
+                cart.items.0 += 1;
+                cart
+            };
+            self.crdt.put(&user_id, cart).await?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn get_cart(&self, cx: RpcContext, user_id: String) -> RpcResult<Cart> {
+        serve_rpc(LABEL, "get_cart", cx, async {
+            let cart = self.crdt.get_or_default(&user_id).await?;
+            Ok(cart.to_cart(user_id))
+        })
+        .await
+    }
+
+    async fn empty_cart(&self, cx: RpcContext, user_id: String) -> RpcResult<()> {
+        serve_rpc(LABEL, "empty_cart", cx, async {
+            log::info!("empty_cart()");
+            let cart = {
+                let mut cart = self.crdt.get_or_default(&user_id).await?;
+                cart.items.0 += 1;
+                cart.items.1.clear();
+                cart
+            };
+            self.crdt.put(&user_id, cart).await?;
+            Ok(())
+        })
+        .await
     }
 
-    async fn get_cart(&self, user_id: String) -> RpcResult<Cart> {
-        let cart = self.crdt.get_or_default(&user_id).await?;
-        Ok(cart.to_cart(user_id))
+    async fn merge_cart(
+        &self,
+        cx: RpcContext,
+        from_user_id: String,
+        into_user_id: String,
+    ) -> RpcResult<()> {
+        serve_rpc(LABEL, "merge_cart", cx, async {
+            log::info!("merge_cart({}, {})", from_user_id, into_user_id);
+            let from = self.crdt.get_or_default(&from_user_id).await?;
+            if from.items.1.is_empty() {
+                return Ok(());
+            }
+            // Only the item maps are merged. Merging the whole `Version` would
+            // drop one side's items whenever the other has been emptied more
+            // often. The item maps merge per product by taking the larger
+            // quantity, so logging in twice never double-counts.
+            let mut into = self.crdt.get_or_default(&into_user_id).await?;
+            into.items.1.merge_from(from.items.1);
+            self.crdt.put(&into_user_id, into).await?;
+            self.empty_cart(RpcContext::current(), from_user_id).await
+        })
+        .await
     }
 
-    async fn empty_cart(&self, user_id: String) -> RpcResult<()> {
-        log::info!("empty_cart({})", user_id);
-        let cart = {
-            let mut cart = self.crdt.get_or_default(&user_id).await?;
-            cart.items.0 += 1;
-            cart.items.1.clear();
-            cart
-        };
-        self.crdt.put(&user_id, cart).await?;
-        Ok(())
+    async fn health(&self, cx: RpcContext) -> RpcResult<Health> {
+        serve_rpc(LABEL, "health", cx, async { Ok(Health::new(LABEL)) }).await
     }
 }
 
@@ -107,5 +199,5 @@ pub type CartClient = ops::Client<CartService>;
 
 pub fn component() -> ComponentConfig {
     CartData::bind("cart");
-    ops::component::<CartService>("cartservice".to_owned())
+    ops::component::<CartService>(LABEL.to_owned())
 }
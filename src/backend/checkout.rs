@@ -1,36 +1,188 @@
+use std::{
+    collections::{HashMap, HashSet},
+    time::Duration,
+};
+
 use amimono::{config::ComponentConfig, rpc::RpcResult};
+use amimono_haze::crdt::{Crdt, StoredCrdt, crdt::Max};
+use futures::{StreamExt, TryStreamExt, stream};
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
 
 use crate::{
     backend::{
-        CartClient, CurrencyClient, EmailClient, PaymentClient, ProductCatalogClient,
-        ShippingClient,
+        self, CartClient, CurrencyClient, EmailClient, InventoryClient, PaymentClient,
+        ProductCatalogClient, PromotionClient, ShippingClient, TaxClient, UserClient,
+        promotion::AppliedPromotions,
+    },
+    shared::{
+        Address, CartItem, CrdtClient, CreditCardInfo, ErrorKind, Health, Money, OrderItem,
+        OrderQuote, OrderResult, Product, RpcContext, TaxLine,
+        cache::{Cache, Source},
+        current_deadline, logging, metrics, now_secs,
+        resilience::{Policy, Resilient},
+        serve_rpc, with_deadline,
     },
-    shared::{Address, CartItem, CreditCardInfo, Money, OrderItem, OrderResult},
 };
 
+// `checkout` takes a context on top of its six fields.
+#[allow(clippy::too_many_arguments)]
 mod ops {
-    use crate::shared::{Address, CreditCardInfo, OrderResult};
+    use crate::shared::{Address, CreditCardInfo, Health, OrderQuote, OrderResult, RpcContext};
 
-    amimono::rpc_ops! {
+    crate::rpc_ops! {
         fn checkout(
+            cx: RpcContext,
+            user_id: String,
+            user_currency: String,
+            address: Address,
+            email: String,
+            credit_card: CreditCardInfo,
+            coupon_code: Option<String>
+        ) -> OrderResult;
+        fn quote_order(
+            cx: RpcContext,
+            user_id: String,
+            user_currency: String,
+            address: Address,
+            coupon_code: Option<String>
+        ) -> OrderQuote;
+        fn checkout_with_token(
+            cx: RpcContext,
             user_id: String,
             user_currency: String,
             address: Address,
             email: String,
-            credit_card: CreditCardInfo
+            payment_token: String,
+            coupon_code: Option<String>
         ) -> OrderResult;
+        fn checkout_with_saved(
+            cx: RpcContext,
+            user_id: String,
+            user_currency: String,
+            address_id: String,
+            payment_token: String,
+            coupon_code: Option<String>
+        ) -> OrderResult;
+        fn list_orders(cx: RpcContext, user_id: String) -> Vec<OrderResult>;
+        fn get_order(cx: RpcContext, user_id: String, order_id: String) -> OrderResult;
+        fn health(cx: RpcContext) -> Health;
+    }
+}
+
+pub const LABEL: &str = "checkoutservice";
+
+/// An order as kept in a user's history. Orders never change once placed,
+/// so each replica has the same value and merging is just a union.
+#[derive(Serialize, Deserialize)]
+struct StoredOrder {
+    placed_at: Max<u64>,
+    /// The `OrderResult` as JSON.
+    order: Max<String>,
+}
+
+impl Crdt for StoredOrder {
+    fn merge_from(&mut self, other: Self) {
+        self.placed_at.merge_from(other.placed_at);
+        self.order.merge_from(other.order);
+    }
+}
+
+/// A user's past orders, keyed by order ID.
+#[derive(Default, Serialize, Deserialize)]
+struct OrderHistory {
+    orders: HashMap<String, StoredOrder>,
+}
+
+impl Crdt for OrderHistory {
+    fn merge_from(&mut self, other: Self) {
+        self.orders.merge_from(other.orders);
+    }
+}
+
+impl StoredCrdt for OrderHistory {}
+
+impl OrderHistory {
+    /// Returns the orders, newest first.
+    fn into_orders(self) -> RpcResult<Vec<OrderResult>> {
+        let mut orders: Vec<_> = self.orders.into_values().collect();
+        orders.sort_by_key(|x| std::cmp::Reverse(x.placed_at.0));
+        orders
+            .into_iter()
+            .map(|x| {
+                serde_json::from_str(&x.order.0)
+                    .map_err(|e| ErrorKind::Internal.error(format!("corrupt order: {}", e)))
+            })
+            .collect()
     }
 }
 
 pub struct CheckoutService {
-    productcatalog: ProductCatalogClient,
-    cart: CartClient,
-    currency: CurrencyClient,
-    shipping: ShippingClient,
-    email: EmailClient,
-    payment: PaymentClient,
+    productcatalog: Resilient<ProductCatalogClient>,
+    cart: Resilient<CartClient>,
+    currency: Resilient<CurrencyClient>,
+    shipping: Resilient<ShippingClient>,
+    email: Resilient<EmailClient>,
+    payment: Resilient<PaymentClient>,
+    inventory: Resilient<InventoryClient>,
+    promotion: Resilient<PromotionClient>,
+    tax: Resilient<TaxClient>,
+    user: Resilient<UserClient>,
+    orders: CrdtClient<OrderHistory>,
+    products: Cache<String, Product>,
+    /// Keyed by the amount, then the target currency.
+    conversions: Cache<(Money, String), Money>,
 }
 
+/// How an order is paid for: a card entered at checkout, or a token for a
+/// card saved by the payment service.
+enum Payment {
+    Card(CreditCardInfo),
+    Token(String),
+}
+
+/// Maximum number of catalog or currency calls in flight while preparing a
+/// single order.
+const PREP_CONCURRENCY: usize = 8;
+
+/// Overall budget for pricing an order, shared by every call made. It is
+/// passed on as the deadline of each of those calls.
+pub const QUOTE_DEADLINE: Duration = Duration::from_secs(10);
+
+/// Reads, which are retried within the caller's deadline.
+const READ: Policy = Policy::idempotent(Duration::from_secs(1));
+/// Calls with side effects elsewhere, which must not be repeated.
+const WRITE: Policy = Policy::once(Duration::from_secs(3));
+/// Writes that are safe to repeat, such as settling a stock reservation.
+const SETTLE: Policy = Policy::idempotent(Duration::from_secs(3));
+/// Charging may wait on the card network.
+const CHARGE: Policy = Policy::once(Duration::from_secs(10));
+
+/// What is left to do once the card is charged: shipping, then either
+/// emptying the cart and sending the confirmation, or refunding and releasing
+/// the stock. Releasing takes the longest of those. A card is only charged
+/// with at least this much time left after the charge itself, so a paid
+/// order is never cut off by the caller's deadline.
+const AFTER_CHARGE: Duration = WRITE
+    .budget()
+    .saturating_mul(2)
+    .saturating_add(SETTLE.budget());
+
+/// How long placing an order can take when every step uses all of its time:
+/// looking up saved details, pricing, reserving and selling the stock,
+/// charging and what comes after. Callers should wait at least this long.
+pub const PLACE_ORDER_BUDGET: Duration = READ
+    .budget()
+    .saturating_add(QUOTE_DEADLINE)
+    .saturating_add(WRITE.budget())
+    .saturating_add(SETTLE.budget())
+    .saturating_add(CHARGE.budget())
+    .saturating_add(AFTER_CHARGE);
+
+/// How long products and conversions are cached. Reloading the catalog or
+/// the rates empties the caches sooner.
+const CATALOG_TTL: Duration = Duration::from_secs(300);
+
 struct OrderPrep {
     order_items: Vec<OrderItem>,
     cart_items: Vec<CartItem>,
@@ -45,11 +197,13 @@ impl CheckoutService {
         address: &Address,
     ) -> RpcResult<OrderPrep> {
         let cart_items = self.get_user_cart(user_id).await?;
-        let order_items = self
-            .prep_order_items(cart_items.as_slice(), user_currency)
-            .await?;
-        let shipping_usd = self.quote_shipping(address, cart_items.as_slice()).await?;
-        let shipping_price = self.convert_currency(&shipping_usd, user_currency).await?;
+        let (order_items, shipping_price) = futures::try_join!(
+            self.prep_order_items(cart_items.as_slice(), user_currency),
+            async {
+                let shipping_usd = self.quote_shipping(address, cart_items.as_slice()).await?;
+                self.convert_currency(&shipping_usd, user_currency).await
+            },
+        )?;
 
         Ok(OrderPrep {
             order_items,
@@ -58,19 +212,94 @@ impl CheckoutService {
         })
     }
 
+    /// Prepares and prices the order within `QUOTE_DEADLINE`.
+    async fn quote(
+        &self,
+        user_id: &str,
+        user_currency: &str,
+        address: &Address,
+        coupon_code: Option<String>,
+    ) -> RpcResult<(OrderPrep, OrderQuote)> {
+        with_deadline(Instant::now() + QUOTE_DEADLINE, async {
+            let prep = self
+                .prepare_order_items_and_shipping_quote_from_cart(user_id, user_currency, address)
+                .await?;
+            let quote = self
+                .price_order(&prep, user_currency, address, coupon_code)
+                .await?;
+            Ok((prep, quote))
+        })
+        .await
+    }
+
+    /// Prices the order without any side effects. Both `checkout` and
+    /// `quote_order` go through here so a quote always matches the amount
+    /// that would be charged.
+    async fn price_order(
+        &self,
+        prep: &OrderPrep,
+        user_currency: &str,
+        address: &Address,
+        coupon_code: Option<String>,
+    ) -> RpcResult<OrderQuote> {
+        let promotions = self
+            .apply_promotions(&prep.order_items, coupon_code)
+            .await?;
+        let discounts = promotions.discounts;
+
+        let subtotal = prep
+            .order_items
+            .iter()
+            .fold(Money::zero(user_currency), |acc, x| {
+                acc + x.item.quantity * x.cost.clone()
+            });
+        let discount_total = discounts
+            .iter()
+            .fold(Money::zero(user_currency), |acc, x| acc + x.amount.clone());
+
+        let tax_lines = self
+            .calculate_tax(address, &prep.order_items, &promotions.line_discounts)
+            .await?;
+        let tax = tax_lines
+            .iter()
+            .fold(Money::zero(user_currency), |acc, x| acc + x.amount.clone());
+
+        let total = prep.shipping_cost_localized.clone() + subtotal - discount_total + tax.clone();
+
+        Ok(OrderQuote {
+            items: prep.order_items.clone(),
+            shipping_cost: prep.shipping_cost_localized.clone(),
+            discounts,
+            tax_lines,
+            tax,
+            total,
+        })
+    }
+
     async fn quote_shipping(&self, address: &Address, cart_items: &[CartItem]) -> RpcResult<Money> {
         self.shipping
-            .get_quote(address.clone(), cart_items.to_vec())
+            .call("get_quote", READ, |c| {
+                c.get_quote(RpcContext::current(), address.clone(), cart_items.to_vec())
+            })
             .await
     }
 
     async fn get_user_cart(&self, user_id: &str) -> RpcResult<Vec<CartItem>> {
-        let cart = self.cart.get_cart(user_id.to_owned()).await?;
+        let cart = self
+            .cart
+            .call("get_cart", READ, |c| {
+                c.get_cart(RpcContext::current(), user_id.to_owned())
+            })
+            .await?;
         Ok(cart.items)
     }
 
     async fn empty_user_cart(&self, user_id: &str) -> RpcResult<()> {
-        self.cart.empty_cart(user_id.to_owned()).await
+        self.cart
+            .call("empty_cart", WRITE, |c| {
+                c.empty_cart(RpcContext::current(), user_id.to_owned())
+            })
+            .await
     }
 
     async fn prep_order_items(
@@ -78,105 +307,265 @@ impl CheckoutService {
         items: &[CartItem],
         user_currency: &str,
     ) -> RpcResult<Vec<OrderItem>> {
-        let mut res: Vec<OrderItem> = Vec::new();
-        for item in items.iter() {
-            let product = self
-                .productcatalog
-                .get_product(item.product_id.to_string())
-                .await?;
-            let price = self
-                .currency
-                .convert(product.price_usd.clone(), user_currency.to_owned())
-                .await?;
-            res.push(OrderItem {
+        let ids: Vec<String> = items.iter().map(|x| x.product_id.clone()).collect();
+        let products: Vec<_> = stream::iter(ids)
+            .map(|id| self.get_product(id))
+            .buffered(PREP_CONCURRENCY)
+            .try_collect()
+            .await?;
+
+        // Many products share a price, so each distinct price is converted
+        // only once per order.
+        let prices: HashSet<Money> = products.iter().map(|x| x.price_usd.clone()).collect();
+        let conversions: HashMap<Money, Money> = stream::iter(prices)
+            .map(|price| async move {
+                let converted = self.convert_currency(&price, user_currency).await?;
+                RpcResult::Ok((price, converted))
+            })
+            .buffered(PREP_CONCURRENCY)
+            .try_collect()
+            .await?;
+
+        let res = items
+            .iter()
+            .zip(products.iter())
+            .map(|(item, product)| OrderItem {
                 item: item.clone(),
-                cost: price,
-            });
-        }
+                cost: conversions[&product.price_usd].clone(),
+            })
+            .collect();
         Ok(res)
     }
 
-    async fn convert_currency(&self, from: &Money, to: &str) -> RpcResult<Money> {
-        self.currency.convert(from.clone(), to.to_owned()).await
+    async fn apply_promotions(
+        &self,
+        items: &[OrderItem],
+        coupon_code: Option<String>,
+    ) -> RpcResult<AppliedPromotions> {
+        self.promotion
+            .call("apply_promotions", READ, |c| {
+                c.apply_promotions(RpcContext::current(), items.to_vec(), coupon_code.clone())
+            })
+            .await
     }
 
-    async fn charge_card(
+    async fn calculate_tax(
         &self,
-        amount: &Money,
-        payment_info: &CreditCardInfo,
-    ) -> RpcResult<String> {
+        address: &Address,
+        items: &[OrderItem],
+        line_discounts: &[Money],
+    ) -> RpcResult<Vec<TaxLine>> {
+        self.tax
+            .call("calculate_tax", READ, |c| {
+                c.calculate_tax(
+                    RpcContext::current(),
+                    address.clone(),
+                    items.to_vec(),
+                    line_discounts.to_vec(),
+                )
+            })
+            .await
+    }
+
+    async fn get_product(&self, id: String) -> RpcResult<Product> {
+        self.products
+            .get_or_load(id.clone(), || {
+                self.productcatalog.call("get_product", READ, |c| {
+                    c.get_product(RpcContext::current(), id.clone())
+                })
+            })
+            .await
+    }
+
+    async fn convert_currency(&self, from: &Money, to: &str) -> RpcResult<Money> {
+        self.conversions
+            .get_or_load((from.clone(), to.to_owned()), || {
+                self.currency.call("convert", READ, |c| {
+                    c.convert(RpcContext::current(), from.clone(), to.to_owned())
+                })
+            })
+            .await
+    }
+
+    /// Charges `payment`. A token is only charged if it was made for
+    /// `user_id`, so nobody can pay with somebody else's card.
+    async fn charge(&self, amount: &Money, user_id: &str, payment: &Payment) -> RpcResult<String> {
+        match payment {
+            Payment::Card(card) => {
+                self.payment
+                    .call("charge", CHARGE, |c| {
+                        c.charge(RpcContext::current(), amount.clone(), card.clone())
+                    })
+                    .await
+            }
+            Payment::Token(token) => {
+                self.payment
+                    .call("charge_token", CHARGE, |c| {
+                        c.charge_token(
+                            RpcContext::current(),
+                            amount.clone(),
+                            user_id.to_owned(),
+                            token.clone(),
+                        )
+                    })
+                    .await
+            }
+        }
+    }
+
+    async fn refund(&self, tx_id: &str, amount: &Money) -> RpcResult<()> {
         self.payment
-            .charge(amount.clone(), payment_info.clone())
+            .call("refund", WRITE, |c| {
+                c.refund(RpcContext::current(), tx_id.to_owned(), amount.clone())
+            })
             .await
     }
 
     async fn send_order_confirmation(&self, email: &str, order: &OrderResult) -> RpcResult<()> {
         self.email
-            .send_order_confirmation(email.to_string(), order.clone())
+            .call("send_order_confirmation", WRITE, |c| {
+                c.send_order_confirmation(RpcContext::current(), email.to_string(), order.clone())
+            })
             .await
     }
 
     async fn ship_order(&self, address: &Address, items: &[CartItem]) -> RpcResult<String> {
         self.shipping
-            .ship_order(address.clone(), items.to_vec())
+            .call("ship_order", WRITE, |c| {
+                c.ship_order(RpcContext::current(), address.clone(), items.to_vec())
+            })
             .await
     }
-}
 
-impl ops::Handler for CheckoutService {
-    async fn new() -> Self {
-        CheckoutService {
-            productcatalog: ProductCatalogClient::new(),
-            cart: CartClient::new(),
-            currency: CurrencyClient::new(),
-            shipping: ShippingClient::new(),
-            email: EmailClient::new(),
-            payment: PaymentClient::new(),
+    async fn reserve_stock(&self, order_id: &str, items: &[CartItem]) -> RpcResult<()> {
+        self.inventory
+            .call("reserve", WRITE, |c| {
+                c.reserve(RpcContext::current(), order_id.to_owned(), items.to_vec())
+            })
+            .await
+    }
+
+    async fn commit_stock(&self, order_id: &str, items: &[CartItem]) -> RpcResult<()> {
+        self.inventory
+            .call("commit", SETTLE, |c| {
+                c.commit(RpcContext::current(), order_id.to_owned(), items.to_vec())
+            })
+            .await
+    }
+
+    async fn release_stock(&self, order_id: &str, items: &[CartItem]) {
+        if let Err(e) = self
+            .inventory
+            .call("release", SETTLE, |c| {
+                c.release(RpcContext::current(), order_id.to_owned(), items.to_vec())
+            })
+            .await
+        {
+            log::warn!("failed to release stock: {:?}", e);
         }
     }
 
-    async fn checkout(
+    /// Adds a placed order to the user's history. The order has already been
+    /// paid for by now, so a failure here is logged rather than returned.
+    async fn record_order(&self, user_id: &str, order: &OrderResult) {
+        let stored = StoredOrder {
+            placed_at: Max(now_secs()),
+            order: Max(serde_json::to_string(order).unwrap()),
+        };
+        let res: RpcResult<()> = async {
+            let mut history = self.orders.get_or_default(user_id).await?;
+            history.orders.insert(order.order_id.clone(), stored);
+            self.orders.put(user_id, history).await?;
+            Ok(())
+        }
+        .await;
+        if let Err(e) = res {
+            log::warn!("failed to record order: {:?}", e);
+        }
+    }
+
+    /// Runs an order from the user's cart through to confirmation. Shared by
+    /// both checkout RPCs, which differ only in where the address and payment
+    /// come from.
+    async fn place_order(
         &self,
-        user_id: String,
-        user_currency: String,
+        user_id: &str,
+        user_currency: &str,
         address: Address,
-        email: String,
-        credit_card: CreditCardInfo,
+        email: &str,
+        payment: Payment,
+        coupon_code: Option<String>,
     ) -> RpcResult<OrderResult> {
-        log::info!(
-            "[PlaceOrder] user_id={} user_currency={}",
-            user_id,
-            user_currency
-        );
-
         let order_id = uuid::Uuid::new_v4().to_string();
-        let prep = self
-            .prepare_order_items_and_shipping_quote_from_cart(&user_id, &user_currency, &address)
+        logging::set_order_id(&order_id);
+        let (prep, quote) = self
+            .quote(user_id, user_currency, &address, coupon_code)
             .await?;
 
-        let total = prep.shipping_cost_localized.clone()
-            + prep
-                .order_items
-                .iter()
-                .map(|x| x.item.quantity * x.cost.clone())
-                .sum();
+        // Stock is sold before the card is charged, so that units that have
+        // been paid for can't be sold to anyone else. If anything fails
+        // before the charge, or the charge is refunded, releasing the
+        // reservation returns them.
+        self.reserve_stock(&order_id, &prep.cart_items[..]).await?;
+        if let Err(e) = self.commit_stock(&order_id, &prep.cart_items[..]).await {
+            self.release_stock(&order_id, &prep.cart_items[..]).await;
+            return Err(e);
+        }
 
-        let tx_id = self.charge_card(&total, &credit_card).await?;
+        // Once charged, the order has to be seen through, so the charge is
+        // only made if there is time for it and for everything after it.
+        let needed = CHARGE.budget() + AFTER_CHARGE;
+        if current_deadline().is_some_and(|x| x < Instant::now() + needed) {
+            self.release_stock(&order_id, &prep.cart_items[..]).await;
+            return Err(ErrorKind::Unavailable.error("too little time left to charge the card"));
+        }
+        let tx_id = match self.charge(&quote.total, user_id, &payment).await {
+            Ok(x) => x,
+            Err(e) => {
+                self.release_stock(&order_id, &prep.cart_items[..]).await;
+                return Err(e);
+            }
+        };
         log::info!("payment went through (transaction_id: {})", tx_id);
 
-        let shipping_tracking_id = self.ship_order(&address, &prep.cart_items[..]).await?;
+        let shipping_tracking_id = match self.ship_order(&address, &prep.cart_items[..]).await {
+            Ok(x) => x,
+            Err(e) => {
+                match self.refund(&tx_id, &quote.total).await {
+                    Ok(()) => self.release_stock(&order_id, &prep.cart_items[..]).await,
+                    // The units stay sold until someone sorts the order out.
+                    Err(refund) => log::error!(
+                        "order {} was charged (transaction_id: {}) but neither shipped nor refunded: {:?}",
+                        order_id,
+                        tx_id,
+                        refund
+                    ),
+                }
+                return Err(e);
+            }
+        };
 
-        self.empty_user_cart(&user_id).await?;
+        // The order is paid for and on its way, so it stands even if the
+        // cart can't be emptied.
+        if let Err(e) = self.empty_user_cart(user_id).await {
+            log::warn!("failed to empty cart: {:?}", e);
+        }
 
         let order = OrderResult {
             order_id,
             shipping_tracking_id,
-            shipping_cost: prep.shipping_cost_localized,
+            shipping_cost: quote.shipping_cost,
             shipping_address: address.clone(),
-            items: prep.order_items,
+            items: quote.items,
+            discounts: quote.discounts,
+            tax_lines: quote.tax_lines,
+            tax: quote.tax,
+            total: quote.total,
         };
+        self.record_order(user_id, &order).await;
+        metrics::order_placed(&order.total);
 
-        match self.send_order_confirmation(&email, &order).await {
+        match self.send_order_confirmation(email, &order).await {
             Ok(_) => log::info!("order confirmation email sent to {}", email),
             Err(_) => log::warn!("failed to send order confirmation to {}", email),
         }
@@ -185,8 +574,207 @@ impl ops::Handler for CheckoutService {
     }
 }
 
+impl ops::Handler for CheckoutService {
+    async fn new() -> Self {
+        CheckoutService {
+            productcatalog: Resilient::new(
+                backend::productcatalog::LABEL,
+                ProductCatalogClient::new(),
+            ),
+            cart: Resilient::new(backend::cart::LABEL, CartClient::new()),
+            currency: Resilient::new(backend::currency::LABEL, CurrencyClient::new()),
+            shipping: Resilient::new(backend::shipping::LABEL, ShippingClient::new()),
+            email: Resilient::new(backend::email::LABEL, EmailClient::new()),
+            payment: Resilient::new(backend::payment::LABEL, PaymentClient::new()),
+            inventory: Resilient::new(backend::inventory::LABEL, InventoryClient::new()),
+            promotion: Resilient::new(backend::promotion::LABEL, PromotionClient::new()),
+            tax: Resilient::new(backend::tax::LABEL, TaxClient::new()),
+            user: Resilient::new(backend::user::LABEL, UserClient::new()),
+            orders: CrdtClient::new("orders".to_owned()),
+            products: Cache::new(
+                "checkout.products",
+                Some(Source::Catalog),
+                1000,
+                CATALOG_TTL,
+            ),
+            conversions: Cache::new(
+                "checkout.conversions",
+                Some(Source::Rates),
+                10_000,
+                CATALOG_TTL,
+            ),
+        }
+    }
+
+    async fn checkout(
+        &self,
+        cx: RpcContext,
+        user_id: String,
+        user_currency: String,
+        address: Address,
+        email: String,
+        credit_card: CreditCardInfo,
+        coupon_code: Option<String>,
+    ) -> RpcResult<OrderResult> {
+        serve_rpc(LABEL, "checkout", cx, async {
+            log::info!(
+                "placing order (user_currency={}, coupon_code={:?})",
+                user_currency,
+                coupon_code
+            );
+
+            self.place_order(
+                &user_id,
+                &user_currency,
+                address,
+                &email,
+                Payment::Card(credit_card),
+                coupon_code,
+            )
+            .await
+        })
+        .await
+    }
+
+    async fn quote_order(
+        &self,
+        cx: RpcContext,
+        user_id: String,
+        user_currency: String,
+        address: Address,
+        coupon_code: Option<String>,
+    ) -> RpcResult<OrderQuote> {
+        serve_rpc(LABEL, "quote_order", cx, async {
+            log::info!(
+                "quoting order (user_currency={}, coupon_code={:?})",
+                user_currency,
+                coupon_code
+            );
+
+            let (_, quote) = self
+                .quote(&user_id, &user_currency, &address, coupon_code)
+                .await?;
+            Ok(quote)
+        })
+        .await
+    }
+
+    /// Like `checkout`, but with a card the payment service has already
+    /// tokenized, so the card number doesn't have to be sent again. The
+    /// token has to have been made for `user_id`.
+    async fn checkout_with_token(
+        &self,
+        cx: RpcContext,
+        user_id: String,
+        user_currency: String,
+        address: Address,
+        email: String,
+        payment_token: String,
+        coupon_code: Option<String>,
+    ) -> RpcResult<OrderResult> {
+        serve_rpc(LABEL, "checkout_with_token", cx, async {
+            log::info!(
+                "placing order with tokenized card (user_currency={}, coupon_code={:?})",
+                user_currency,
+                coupon_code
+            );
+
+            self.place_order(
+                &user_id,
+                &user_currency,
+                address,
+                &email,
+                Payment::Token(payment_token),
+                coupon_code,
+            )
+            .await
+        })
+        .await
+    }
+
+    async fn checkout_with_saved(
+        &self,
+        cx: RpcContext,
+        user_id: String,
+        user_currency: String,
+        address_id: String,
+        payment_token: String,
+        coupon_code: Option<String>,
+    ) -> RpcResult<OrderResult> {
+        serve_rpc(LABEL, "checkout_with_saved", cx, async {
+            log::info!(
+                "placing order with saved details (user_currency={}, coupon_code={:?})",
+                user_currency,
+                coupon_code
+            );
+
+            // Both must come from this user's own profile, so a user can't pay
+            // with somebody else's token.
+            let profile = self
+                .user
+                .call("get_profile", READ, |c| {
+                    c.get_profile(RpcContext::current(), user_id.clone())
+                })
+                .await?;
+            let address = profile
+                .addresses
+                .into_iter()
+                .find(|x| x.id == address_id)
+                .ok_or_else(|| ErrorKind::InvalidArgument.error("unknown saved address"))?
+                .address;
+            if !profile
+                .payment_methods
+                .iter()
+                .any(|x| x.token == payment_token)
+            {
+                return Err(ErrorKind::InvalidArgument.error("unknown saved payment method"));
+            }
+
+            self.place_order(
+                &user_id,
+                &user_currency,
+                address,
+                &profile.email,
+                Payment::Token(payment_token),
+                coupon_code,
+            )
+            .await
+        })
+        .await
+    }
+
+    async fn list_orders(&self, cx: RpcContext, user_id: String) -> RpcResult<Vec<OrderResult>> {
+        serve_rpc(LABEL, "list_orders", cx, async {
+            self.orders.get_or_default(&user_id).await?.into_orders()
+        })
+        .await
+    }
+
+    async fn get_order(
+        &self,
+        cx: RpcContext,
+        user_id: String,
+        order_id: String,
+    ) -> RpcResult<OrderResult> {
+        serve_rpc(LABEL, "get_order", cx, async {
+            let mut history = self.orders.get_or_default(&user_id).await?;
+            match history.orders.remove(&order_id) {
+                Some(x) => serde_json::from_str(&x.order.0)
+                    .map_err(|e| ErrorKind::Internal.error(format!("corrupt order: {}", e))),
+                None => Err(ErrorKind::NotFound.error(format!("no order {}", order_id))),
+            }
+        })
+        .await
+    }
+
+    async fn health(&self, cx: RpcContext) -> RpcResult<Health> {
+        serve_rpc(LABEL, "health", cx, async { Ok(Health::new(LABEL)) }).await
+    }
+}
+
 pub type CheckoutClient = ops::Client<CheckoutService>;
 
 pub fn component() -> ComponentConfig {
-    ops::component::<CheckoutService>("checkoutservice".to_string())
+    OrderHistory::bind("orders");
+    ops::component::<CheckoutService>(LABEL.to_owned())
 }
@@ -1,80 +1,258 @@
-use std::collections::HashMap;
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
 use amimono::{config::ComponentConfig, rpc::RpcResult};
 use amimono_haze::dashboard::tree;
+use serde::{Deserialize, Serialize};
 
-use crate::shared::Money;
+use crate::{
+    backend::rates::{self, RateProvider, Rates},
+    shared::{
+        ErrorKind, Health, Money, RpcContext,
+        cache::{self, Source},
+        now_secs, serve_rpc,
+    },
+};
 
 mod ops {
-    use crate::shared::Money;
+    use super::RatesInfo;
+    use crate::shared::{Health, Money, RpcContext};
 
-    amimono::rpc_ops! {
-        fn get_supported_currencies() -> Vec<String>;
-        fn convert(from: Money, to: String) -> Money;
+    crate::rpc_ops! {
+        fn get_supported_currencies(cx: RpcContext) -> Vec<String>;
+        fn convert(cx: RpcContext, from: Money, to: String) -> Money;
+        fn get_rates_info(cx: RpcContext) -> RatesInfo;
+        fn health(cx: RpcContext) -> Health;
     }
 }
 
+pub const LABEL: &str = "currencyservice";
+
+/// How often rates are fetched again, unless `BOUTIQUE_RATES_REFRESH_SECS`
+/// says otherwise.
+const DEFAULT_REFRESH_SECS: u64 = 3600;
+
+/// How old rates may get before conversions are refused, unless
+/// `BOUTIQUE_RATES_MAX_AGE_SECS` says otherwise. The ECB publishes on
+/// working days only, so this allows for a long weekend.
+const DEFAULT_MAX_AGE_SECS: u64 = 5 * 86_400;
+
+/// How soon a failed fetch is tried again.
+const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Where the service's rates came from and how old they are.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RatesInfo {
+    pub source: String,
+    /// When the rates were published, in seconds since the epoch.
+    pub as_of: u64,
+    /// When they were last fetched, in seconds since the epoch.
+    pub fetched_at: u64,
+    /// Whether they are too old to convert with.
+    pub stale: bool,
+    /// Whether they are the built-in rates, standing in because the source
+    /// couldn't be reached at startup.
+    pub fallback: bool,
+}
+
+struct State {
+    rates: Arc<Rates>,
+    fetched_at: u64,
+    /// Whether these are the built-in rates, standing in because the
+    /// provider couldn't be reached at startup.
+    fallback: bool,
+}
+
 pub struct CurrencyService {
-    conversion: HashMap<String, f64>,
+    provider: Arc<dyn RateProvider>,
+    max_age: u64,
+    state: Arc<Mutex<State>>,
 }
 
-const CURRENCY_CONVERSION_DATA: &'static str = include_str!("conversion.json");
+fn env_secs(name: &str, default: u64) -> u64 {
+    match std::env::var(name) {
+        Ok(x) => x
+            .parse()
+            .unwrap_or_else(|_| panic!("{} must be a number of seconds", name)),
+        Err(_) => default,
+    }
+}
+
+/// Fetches rates from `provider` every `refresh`, or every `RETRY_INTERVAL`
+/// while it is failing, so requests never wait on the provider.
+async fn refresh_rates(
+    provider: Arc<dyn RateProvider>,
+    state: Arc<Mutex<State>>,
+    refresh: Duration,
+) {
+    let mut wait = match state.lock().unwrap().fallback {
+        true => RETRY_INTERVAL,
+        false => refresh,
+    };
+    loop {
+        tokio::time::sleep(wait).await;
+        let fetched = match provider.fetch().await {
+            Ok(fetched) => fetched,
+            Err(e) => {
+                log::warn!(
+                    "failed to refresh rates from {}: {}",
+                    provider.describe(),
+                    e
+                );
+                wait = RETRY_INTERVAL;
+                continue;
+            }
+        };
+        wait = refresh;
+        let count = fetched.per_euro.len();
+        let changed = {
+            let mut state = state.lock().unwrap();
+            let changed = state.fallback || fetched != *state.rates;
+            state.rates = Arc::new(fetched);
+            state.fetched_at = now_secs();
+            state.fallback = false;
+            changed
+        };
+        if changed {
+            log::info!("refreshed {} rates from {}", count, provider.describe());
+            cache::reloaded(Source::Rates).await;
+        }
+    }
+}
 
 impl CurrencyService {
-    fn get_per_euro(&self, currency_code: &str) -> RpcResult<f64> {
-        self.conversion
-            .get(currency_code)
-            .cloned()
-            .ok_or_else(|| format!("unsupported currency: {}", currency_code).into())
+    /// The current rates, and whether they are too old to convert with.
+    fn rates(&self) -> (Arc<Rates>, bool) {
+        let state = self.state.lock().unwrap();
+        (state.rates.clone(), self.is_stale(&state))
+    }
+
+    fn is_stale(&self, state: &State) -> bool {
+        self.provider.refreshes() && now_secs().saturating_sub(state.rates.as_of) > self.max_age
+    }
+
+    fn get_per_euro(rates: &Rates, currency_code: &str) -> RpcResult<f64> {
+        rates.per_euro.get(currency_code).cloned().ok_or_else(|| {
+            ErrorKind::InvalidArgument.error(format!("unsupported currency: {}", currency_code))
+        })
     }
 }
 
 impl ops::Handler for CurrencyService {
     async fn new() -> CurrencyService {
-        let service = CurrencyService {
-            conversion: serde_json::from_str(CURRENCY_CONVERSION_DATA).unwrap(),
+        let provider: Arc<dyn RateProvider> = rates::from_env().into();
+        let (rates, fallback) = match provider.fetch().await {
+            Ok(rates) => (rates, false),
+            Err(e) => {
+                log::warn!(
+                    "cannot load rates from {}, using the built-in ones until it answers: {}",
+                    provider.describe(),
+                    e
+                );
+                let rates = rates::Embedded
+                    .fetch()
+                    .await
+                    .expect("built-in rates do not parse");
+                (rates, true)
+            }
         };
-        log::debug!("loaded conversion data: {:?}", service.conversion);
-        service
+        log::debug!("loaded conversion data: {:?}", rates.per_euro);
+        let state = Arc::new(Mutex::new(State {
+            rates: Arc::new(rates),
+            fetched_at: now_secs(),
+            fallback,
+        }));
+        if provider.refreshes() {
+            let refresh = Duration::from_secs(env_secs(
+                "BOUTIQUE_RATES_REFRESH_SECS",
+                DEFAULT_REFRESH_SECS,
+            ));
+            tokio::spawn(refresh_rates(provider.clone(), state.clone(), refresh));
+        }
+        // These may be different rates from the ones caches saw last.
+        cache::reloaded(Source::Rates).await;
+        CurrencyService {
+            provider,
+            max_age: env_secs("BOUTIQUE_RATES_MAX_AGE_SECS", DEFAULT_MAX_AGE_SECS),
+            state,
+        }
     }
 
-    async fn get_supported_currencies(&self) -> RpcResult<Vec<String>> {
-        Ok(self.conversion.keys().cloned().collect())
+    async fn get_supported_currencies(&self, cx: RpcContext) -> RpcResult<Vec<String>> {
+        serve_rpc(LABEL, "get_supported_currencies", cx, async {
+            Ok(self.rates().0.per_euro.keys().cloned().collect())
+        })
+        .await
     }
 
-    async fn convert(&self, from: Money, to: String) -> RpcResult<Money> {
-        let from_per_euro = self.get_per_euro(&from.currency_code)?;
-        let to_per_euro = self.get_per_euro(&to)?;
+    async fn convert(&self, cx: RpcContext, from: Money, to: String) -> RpcResult<Money> {
+        serve_rpc(LABEL, "convert", cx, async {
+            let (rates, stale) = self.rates();
+            let from_per_euro = Self::get_per_euro(&rates, &from.currency_code)?;
+            let to_per_euro = Self::get_per_euro(&rates, &to)?;
+            if from.currency_code != to && stale {
+                return Err(ErrorKind::Unavailable.error(format!(
+                    "exchange rates from {} are out of date",
+                    self.provider.describe()
+                )));
+            }
 
-        let from_nanos = from.units as f64 * 1_000_000_000.0 + from.nanos as f64;
+            let from_nanos = from.units as f64 * 1_000_000_000.0 + from.nanos as f64;
 
-        let to_units = 0i64;
-        let to_nanos = (from_nanos * to_per_euro / from_per_euro) as i64;
+            let to_units = 0i64;
+            let to_nanos = (from_nanos * to_per_euro / from_per_euro) as i64;
 
-        Ok(Money {
-            currency_code: to.to_owned(),
-            units: to_units + (to_nanos / 1_000_000_000),
-            nanos: (to_nanos % 1_000_000_000) as i32,
+            Ok(Money {
+                currency_code: to.to_owned(),
+                units: to_units + (to_nanos / 1_000_000_000),
+                nanos: (to_nanos % 1_000_000_000) as i32,
+            })
         })
+        .await
+    }
+
+    async fn get_rates_info(&self, cx: RpcContext) -> RpcResult<RatesInfo> {
+        serve_rpc(LABEL, "get_rates_info", cx, async {
+            let state = self.state.lock().unwrap();
+            let source = match state.fallback {
+                true => format!("embedded, until {} answers", self.provider.describe()),
+                false => self.provider.describe(),
+            };
+            Ok(RatesInfo {
+                source,
+                as_of: state.rates.as_of,
+                fetched_at: state.fetched_at,
+                stale: self.is_stale(&state),
+                fallback: state.fallback,
+            })
+        })
+        .await
+    }
+
+    async fn health(&self, cx: RpcContext) -> RpcResult<Health> {
+        serve_rpc(LABEL, "health", cx, async { Ok(Health::new(LABEL)) }).await
     }
 }
 
 pub type CurrencyClient = ops::Client<CurrencyService>;
 
 pub fn component() -> ComponentConfig {
-    ops::component::<CurrencyService>("currencyservice".to_string())
+    ops::component::<CurrencyService>(LABEL.to_owned())
 }
 
 pub struct DashboardDirectory;
 
 impl tree::Directory for DashboardDirectory {
     async fn list(&self) -> tree::TreeResult<Vec<tree::DirEntry>> {
-        let res = CurrencyClient::new()
-            .get_supported_currencies()
+        let mut res: Vec<_> = CurrencyClient::new()
+            .get_supported_currencies(RpcContext::current())
             .await?
             .into_iter()
             .map(tree::DirEntry::item)
             .collect();
+        res.push(tree::DirEntry::item("rates"));
         Ok(res)
     }
 
@@ -85,6 +263,23 @@ impl tree::Directory for DashboardDirectory {
     async fn open_item(&self, name: &str) -> tree::TreeResult<tree::Item> {
         let client = CurrencyClient::new();
 
+        if name == "rates" {
+            let info = client.get_rates_info(RpcContext::current()).await?;
+            let date = |secs: u64| {
+                time::OffsetDateTime::from_unix_timestamp(secs as i64)
+                    .map_or_else(|_| secs.to_string(), |x| x.to_string())
+            };
+            let msg = format!(
+                "source: {}\nas of: {}\nfetched: {}\nstale: {}\nfallback: {}",
+                info.source,
+                date(info.as_of),
+                date(info.fetched_at),
+                info.stale,
+                info.fallback
+            );
+            return Ok(tree::Item::new(msg));
+        }
+
         let one_in = Money {
             currency_code: name.to_owned(),
             units: 1,
@@ -96,8 +291,12 @@ impl tree::Directory for DashboardDirectory {
             nanos: 0,
         };
 
-        let as_in = client.convert(one_eur.clone(), name.to_owned()).await?;
-        let as_eur = client.convert(one_in.clone(), "EUR".to_owned()).await?;
+        let as_in = client
+            .convert(RpcContext::current(), one_eur.clone(), name.to_owned())
+            .await?;
+        let as_eur = client
+            .convert(RpcContext::current(), one_in.clone(), "EUR".to_owned())
+            .await?;
 
         let msg = format!("{one_eur:?} = {as_in:?}\n{one_in:?} = {as_eur:?}");
         Ok(tree::Item::new(msg))
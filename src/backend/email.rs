@@ -3,16 +3,20 @@ use amimono::{
     rpc::{RpcError, RpcResult},
 };
 
-use crate::shared::OrderResult;
+use crate::shared::{Health, OrderResult, RpcContext, serve_rpc};
 
-mod ops {
-    use crate::shared::OrderResult;
+/// Visible to the crate so tests can serve the service with a fake.
+pub(crate) mod ops {
+    use crate::shared::{Health, OrderResult, RpcContext};
 
-    amimono::rpc_ops! {
-        fn send_order_confirmation(email: String, order: OrderResult) -> ();
+    crate::rpc_ops! {
+        fn send_order_confirmation(cx: RpcContext, email: String, order: OrderResult) -> ();
+        fn health(cx: RpcContext) -> Health;
     }
 }
 
+pub const LABEL: &str = "emailservice";
+
 pub struct EmailService;
 
 impl ops::Handler for EmailService {
@@ -20,15 +24,27 @@ impl ops::Handler for EmailService {
         EmailService
     }
 
-    async fn send_order_confirmation(&self, _email: String, _order: OrderResult) -> RpcResult<()> {
-        Err(RpcError::Misc(
-            "send_order_confirmation is not implemented yet".to_owned(),
-        ))
+    async fn send_order_confirmation(
+        &self,
+        cx: RpcContext,
+        _email: String,
+        _order: OrderResult,
+    ) -> RpcResult<()> {
+        serve_rpc(LABEL, "send_order_confirmation", cx, async {
+            Err(RpcError::Misc(
+                "send_order_confirmation is not implemented yet".to_owned(),
+            ))
+        })
+        .await
+    }
+
+    async fn health(&self, cx: RpcContext) -> RpcResult<Health> {
+        serve_rpc(LABEL, "health", cx, async { Ok(Health::new(LABEL)) }).await
     }
 }
 
 pub type EmailClient = ops::Client<EmailService>;
 
 pub fn component() -> ComponentConfig {
-    ops::component::<EmailService>("emailservice".to_string())
+    ops::component::<EmailService>(LABEL.to_owned())
 }
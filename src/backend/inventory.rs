@@ -0,0 +1,326 @@
+use std::collections::HashMap;
+
+use amimono::{config::ComponentConfig, rpc::RpcResult};
+use amimono_haze::crdt::{Crdt, StoredCrdt, crdt::Max};
+use serde::{Deserialize, Serialize};
+
+use crate::shared::{CartItem, CrdtClient, ErrorKind, Health, RpcContext, now_secs, serve_rpc};
+
+#[derive(Serialize, Deserialize)]
+struct InventoryData {
+    stock: HashMap<String, u32>,
+}
+
+const INVENTORY_DATA: &'static str = include_str!("inventory.json");
+
+/// How long a reservation holds stock before it is considered abandoned.
+const RESERVATION_TTL_SECS: u64 = 10 * 60;
+
+/// One order's claim on a product. A reservation starts out holding stock
+/// until it expires, and is then either committed, which sells the units, or
+/// released, which returns them. Releasing a committed reservation returns
+/// its units too. Each flag only ever goes from false to true, so replicas
+/// merge by taking the maximum of every field.
+#[derive(Serialize, Deserialize)]
+struct Reservation {
+    quantity: Max<u32>,
+    expires_at: Max<u64>,
+    committed: Max<bool>,
+    released: Max<bool>,
+}
+
+impl Crdt for Reservation {
+    fn merge_from(&mut self, other: Self) {
+        self.quantity.merge_from(other.quantity);
+        self.expires_at.merge_from(other.expires_at);
+        self.committed.merge_from(other.committed);
+        self.released.merge_from(other.released);
+    }
+}
+
+/// Stock movements for a single product. The starting stock level comes from
+/// `inventory.json`, so only reservations are stored, keyed by reservation
+/// ID. Units sold and held are sums over them, so replicas merging their
+/// reservations never lose one. Reservations are never removed, since
+/// merging with a replica that still had one would bring it back.
+///
+/// Nothing makes checking what is available and reserving it atomic. A hold
+/// reads the stock back after writing and gives way if the product is now
+/// oversold, which catches two orders racing on the same replica, though
+/// both may then give way. Holds written to replicas that haven't merged yet
+/// can't see each other, so those can still oversell.
+#[derive(Default, Serialize, Deserialize)]
+struct StockData {
+    reservations: HashMap<String, Reservation>,
+}
+
+impl Crdt for StockData {
+    fn merge_from(&mut self, other: Self) {
+        self.reservations.merge_from(other.reservations);
+    }
+}
+
+impl StoredCrdt for StockData {}
+
+impl StockData {
+    fn sold(&self) -> u32 {
+        self.reservations
+            .values()
+            .filter(|r| r.committed.0 && !r.released.0)
+            .map(|r| r.quantity.0)
+            .sum()
+    }
+
+    fn held(&self, now: u64) -> u32 {
+        self.reservations
+            .values()
+            .filter(|r| !r.committed.0 && !r.released.0 && r.expires_at.0 > now)
+            .map(|r| r.quantity.0)
+            .sum()
+    }
+
+    fn available(&self, initial: u32, now: u64) -> u32 {
+        initial
+            .saturating_sub(self.sold())
+            .saturating_sub(self.held(now))
+    }
+
+    fn oversold(&self, initial: u32, now: u64) -> bool {
+        self.sold() + self.held(now) > initial
+    }
+}
+
+fn insufficient(item: &CartItem, available: u32) -> amimono::rpc::RpcError {
+    ErrorKind::OutOfStock.error(format!(
+        "insufficient stock for product {}: requested {}, available {}",
+        item.product_id, item.quantity, available
+    ))
+}
+
+mod ops {
+    use crate::shared::{CartItem, Health, RpcContext};
+
+    crate::rpc_ops! {
+        fn get_stock(cx: RpcContext, product_id: String) -> u32;
+        fn reserve(cx: RpcContext, reservation_id: String, items: Vec<CartItem>) -> ();
+        fn commit(cx: RpcContext, reservation_id: String, items: Vec<CartItem>) -> ();
+        fn release(cx: RpcContext, reservation_id: String, items: Vec<CartItem>) -> ();
+        fn health(cx: RpcContext) -> Health;
+    }
+}
+
+pub const LABEL: &str = "inventoryservice";
+
+pub struct InventoryService {
+    data: InventoryData,
+    crdt: CrdtClient<StockData>,
+}
+
+impl InventoryService {
+    fn initial_stock(&self, product_id: &str) -> u32 {
+        self.data.stock.get(product_id).cloned().unwrap_or(0)
+    }
+
+    async fn hold(&self, reservation_id: &str, item: &CartItem, now: u64) -> RpcResult<()> {
+        let initial = self.initial_stock(&item.product_id);
+        let stock = self.crdt.get_or_default(&item.product_id).await?;
+        let available = stock.available(initial, now);
+        if available < item.quantity {
+            return Err(insufficient(item, available));
+        }
+        let reservation = Reservation {
+            quantity: Max(item.quantity),
+            expires_at: Max(now + RESERVATION_TTL_SECS),
+            committed: Max(false),
+            released: Max(false),
+        };
+        let stock = StockData {
+            reservations: HashMap::from([(reservation_id.to_owned(), reservation)]),
+        };
+        self.crdt.put(&item.product_id, stock).await?;
+
+        // Someone else may have reserved the same units in the meantime.
+        let stock = self.crdt.get_or_default(&item.product_id).await?;
+        if stock.oversold(initial, now) {
+            self.settle(reservation_id, item, false).await?;
+            let available = stock.available(initial, now);
+            return Err(insufficient(item, available));
+        }
+        Ok(())
+    }
+
+    /// Commits or releases a reservation. Either can be repeated safely. A
+    /// reservation that has expired or was never made is only committed if
+    /// the stock is still there, and one that was released can't be.
+    async fn settle(&self, reservation_id: &str, item: &CartItem, sell: bool) -> RpcResult<()> {
+        if sell {
+            let now = now_secs();
+            let stock = self.crdt.get_or_default(&item.product_id).await?;
+            let reservation = stock.reservations.get(reservation_id);
+            if reservation.is_some_and(|r| r.released.0) {
+                return Err(ErrorKind::InvalidArgument.error(format!(
+                    "reservation {} for product {} was released",
+                    reservation_id, item.product_id
+                )));
+            }
+            let live = reservation.is_some_and(|r| r.committed.0 || r.expires_at.0 > now);
+            if !live {
+                let available = stock.available(self.initial_stock(&item.product_id), now);
+                if available < item.quantity {
+                    return Err(insufficient(item, available));
+                }
+                log::warn!(
+                    "committing lapsed reservation {} for product {}",
+                    reservation_id,
+                    item.product_id
+                );
+            }
+        }
+        let reservation = Reservation {
+            quantity: Max(item.quantity),
+            expires_at: Max(0),
+            committed: Max(sell),
+            released: Max(!sell),
+        };
+        let stock = StockData {
+            reservations: HashMap::from([(reservation_id.to_owned(), reservation)]),
+        };
+        self.crdt.put(&item.product_id, stock).await?;
+        Ok(())
+    }
+}
+
+impl ops::Handler for InventoryService {
+    async fn new() -> InventoryService {
+        let data: InventoryData = serde_json::from_str(INVENTORY_DATA).unwrap();
+        log::debug!("inventory loaded: {:?}", data.stock);
+        InventoryService {
+            data,
+            crdt: CrdtClient::new("inventory".to_owned()),
+        }
+    }
+
+    async fn get_stock(&self, cx: RpcContext, product_id: String) -> RpcResult<u32> {
+        serve_rpc(LABEL, "get_stock", cx, async {
+            let stock = self.crdt.get_or_default(&product_id).await?;
+            Ok(stock.available(self.initial_stock(&product_id), now_secs()))
+        })
+        .await
+    }
+
+    async fn reserve(
+        &self,
+        cx: RpcContext,
+        reservation_id: String,
+        items: Vec<CartItem>,
+    ) -> RpcResult<()> {
+        serve_rpc(LABEL, "reserve", cx, async {
+            log::info!("reserve({}, {:?})", reservation_id, items);
+            let now = now_secs();
+            for (i, item) in items.iter().enumerate() {
+                if let Err(e) = self.hold(&reservation_id, item, now).await {
+                    for held in items[..i].iter() {
+                        if let Err(e) = self.settle(&reservation_id, held, false).await {
+                            log::warn!("failed to roll back hold on {}: {:?}", held.product_id, e);
+                        }
+                    }
+                    return Err(e);
+                }
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    async fn commit(
+        &self,
+        cx: RpcContext,
+        reservation_id: String,
+        items: Vec<CartItem>,
+    ) -> RpcResult<()> {
+        serve_rpc(LABEL, "commit", cx, async {
+            log::info!("commit({})", reservation_id);
+            for item in items.iter() {
+                self.settle(&reservation_id, item, true).await?;
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    async fn release(
+        &self,
+        cx: RpcContext,
+        reservation_id: String,
+        items: Vec<CartItem>,
+    ) -> RpcResult<()> {
+        serve_rpc(LABEL, "release", cx, async {
+            log::info!("release({})", reservation_id);
+            for item in items.iter() {
+                self.settle(&reservation_id, item, false).await?;
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    async fn health(&self, cx: RpcContext) -> RpcResult<Health> {
+        serve_rpc(LABEL, "health", cx, async { Ok(Health::new(LABEL)) }).await
+    }
+}
+
+pub type InventoryClient = ops::Client<InventoryService>;
+
+pub fn component() -> ComponentConfig {
+    StockData::bind("inventory");
+    ops::component::<InventoryService>(LABEL.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settled(id: &str, quantity: u32, committed: bool, released: bool) -> StockData {
+        let reservation = Reservation {
+            quantity: Max(quantity),
+            expires_at: Max(0),
+            committed: Max(committed),
+            released: Max(released),
+        };
+        StockData {
+            reservations: HashMap::from([(id.to_owned(), reservation)]),
+        }
+    }
+
+    #[test]
+    fn replicas_selling_at_once_both_count() {
+        let mut a = settled("order-1", 2, true, false);
+        let b = settled("order-2", 2, true, false);
+        a.merge_from(b);
+        assert_eq!(a.sold(), 4);
+        assert_eq!(a.available(10, 0), 6);
+        assert!(!a.oversold(10, 0));
+
+        // Releasing a committed reservation returns its units.
+        a.merge_from(settled("order-2", 2, false, true));
+        assert_eq!(a.sold(), 2);
+    }
+
+    #[tokio::test]
+    async fn lapsed_reservations_are_only_sold_while_stock_lasts() {
+        let service = <InventoryService as ops::Handler>::new().await;
+        let item = |quantity| CartItem {
+            product_id: "1YMWWN1N4O".to_owned(),
+            quantity,
+        };
+        service.hold("order-1", &item(8), now_secs()).await.unwrap();
+
+        let err = service.settle("unknown", &item(3), true).await.unwrap_err();
+        assert_eq!(ErrorKind::of(&err), ErrorKind::OutOfStock);
+        service.settle("unknown", &item(2), true).await.unwrap();
+
+        service.settle("order-1", &item(8), false).await.unwrap();
+        let err = service.settle("order-1", &item(8), true).await.unwrap_err();
+        assert_eq!(ErrorKind::of(&err), ErrorKind::InvalidArgument);
+    }
+}
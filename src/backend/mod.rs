@@ -3,17 +3,26 @@ pub mod cart;
 pub mod checkout;
 pub mod currency;
 pub mod email;
+pub mod inventory;
 pub mod payment;
 pub mod productcatalog;
+pub mod promotion;
+mod rates;
 pub mod recommendation;
 pub mod shipping;
+pub mod tax;
+pub mod user;
 
 pub use ad::AdClient;
 pub use cart::CartClient;
 pub use checkout::CheckoutClient;
 pub use currency::CurrencyClient;
 pub use email::EmailClient;
+pub use inventory::InventoryClient;
 pub use payment::PaymentClient;
 pub use productcatalog::ProductCatalogClient;
+pub use promotion::PromotionClient;
 pub use recommendation::RecommendationClient;
 pub use shipping::ShippingClient;
+pub use tax::TaxClient;
+pub use user::UserClient;
@@ -1,31 +1,231 @@
 use amimono::{config::ComponentConfig, rpc::RpcResult};
+use amimono_haze::crdt::{Crdt, StoredCrdt, crdt::Max};
+use serde::{Deserialize, Serialize};
 
-use crate::shared::{CreditCardInfo, Money};
+use crate::shared::{
+    CrdtClient, CreditCardInfo, ErrorKind, Health, Money, PaymentMethod, RpcContext,
+    current_year_month, serve_rpc,
+};
 
-mod ops {
-    use crate::shared::{CreditCardInfo, Money};
+/// Visible to the crate so tests can serve the service with a fake.
+pub(crate) mod ops {
+    use crate::shared::{CreditCardInfo, Health, Money, PaymentMethod, RpcContext};
 
-    amimono::rpc_ops! {
-        fn charge(amount: Money, credit_card: CreditCardInfo) -> String;
+    crate::rpc_ops! {
+        fn charge(cx: RpcContext, amount: Money, credit_card: CreditCardInfo) -> String;
+        fn tokenize(cx: RpcContext, user_id: String, credit_card: CreditCardInfo) -> PaymentMethod;
+        fn charge_token(cx: RpcContext, amount: Money, user_id: String, token: String) -> String;
+        fn refund(cx: RpcContext, transaction_id: String, amount: Money) -> ();
+        fn health(cx: RpcContext) -> Health;
     }
 }
 
-pub struct PaymentService;
+pub const LABEL: &str = "paymentservice";
+
+/// Card number that is always declined, for exercising the failure path.
+const DECLINED_TEST_CARD: &'static str = "4000000000000002";
+
+/// What the vault keeps for a token. The card number itself is never
+/// stored, only what is needed to show and charge the card.
+#[derive(Serialize, Deserialize)]
+struct TokenData {
+    /// The user the card was tokenized for, the only one who can charge it.
+    owner: Max<String>,
+    card_type: Max<String>,
+    last_four: Max<String>,
+    expiration_year: Max<i32>,
+    expiration_month: Max<i32>,
+    /// Set when the card was the declined test card, so charging the token
+    /// fails the same way charging the card would.
+    declines: Max<bool>,
+}
+
+impl Crdt for TokenData {
+    fn merge_from(&mut self, other: Self) {
+        self.owner.merge_from(other.owner);
+        self.card_type.merge_from(other.card_type);
+        self.last_four.merge_from(other.last_four);
+        self.expiration_year.merge_from(other.expiration_year);
+        self.expiration_month.merge_from(other.expiration_month);
+        self.declines.merge_from(other.declines);
+    }
+}
+
+impl StoredCrdt for TokenData {}
+
+impl Default for TokenData {
+    fn default() -> Self {
+        Self {
+            owner: Max(String::new()),
+            card_type: Max(String::new()),
+            last_four: Max(String::new()),
+            expiration_year: Max(0),
+            expiration_month: Max(0),
+            declines: Max(false),
+        }
+    }
+}
+
+fn card_type(number: &str) -> &'static str {
+    let prefix = |n: usize| number.get(..n).and_then(|x| x.parse::<u32>().ok());
+    match (prefix(1), prefix(2), prefix(4)) {
+        (Some(4), _, _) => "Visa",
+        (_, Some(34 | 37), _) => "American Express",
+        (_, Some(51..=55), _) | (_, _, Some(2221..=2720)) => "Mastercard",
+        (_, Some(65), _) | (_, _, Some(6011)) => "Discover",
+        _ => "Card",
+    }
+}
+
+fn last_four(number: &str) -> String {
+    let skip = number.chars().count().saturating_sub(4);
+    number.chars().skip(skip).collect()
+}
+
+fn is_expired(year: i32, month: i32) -> bool {
+    (year, month) < current_year_month()
+}
+
+pub struct PaymentService {
+    vault: CrdtClient<TokenData>,
+}
 
 impl ops::Handler for PaymentService {
     async fn new() -> Self {
-        PaymentService
+        PaymentService {
+            vault: CrdtClient::new("payment_tokens".to_owned()),
+        }
+    }
+
+    async fn charge(
+        &self,
+        cx: RpcContext,
+        amount: Money,
+        credit_card: CreditCardInfo,
+    ) -> RpcResult<String> {
+        serve_rpc(LABEL, "charge", cx, async {
+            log::info!(
+                "charge card ending {} with {:?}",
+                last_four(&credit_card.credit_card_number),
+                amount
+            );
+            if credit_card.credit_card_number == DECLINED_TEST_CARD {
+                return Err(ErrorKind::PaymentDeclined.error("card was declined"));
+            }
+            // TODO, leave this stubbed for now
+            Ok(uuid::Uuid::new_v4().to_string())
+        })
+        .await
+    }
+
+    async fn tokenize(
+        &self,
+        cx: RpcContext,
+        user_id: String,
+        credit_card: CreditCardInfo,
+    ) -> RpcResult<PaymentMethod> {
+        serve_rpc(LABEL, "tokenize", cx, async {
+            let number = credit_card.credit_card_number.as_str();
+            log::info!("tokenize card ending {}", last_four(number));
+            if number.len() < 12 || !number.bytes().all(|b| b.is_ascii_digit()) {
+                return Err(ErrorKind::InvalidArgument.error("invalid card number"));
+            }
+            let method = PaymentMethod {
+                token: format!("tok_{}", uuid::Uuid::new_v4().simple()),
+                card_type: card_type(number).to_owned(),
+                last_four: last_four(number),
+                expiration_year: credit_card.credit_card_expiration_year,
+                expiration_month: credit_card.credit_card_expiration_month,
+            };
+            let data = TokenData {
+                owner: Max(user_id),
+                card_type: Max(method.card_type.clone()),
+                last_four: Max(method.last_four.clone()),
+                expiration_year: Max(method.expiration_year),
+                expiration_month: Max(method.expiration_month),
+                declines: Max(number == DECLINED_TEST_CARD),
+            };
+            self.vault.put(&method.token, data).await?;
+            Ok(method)
+        })
+        .await
     }
 
-    async fn charge(&self, amount: Money, credit_card: CreditCardInfo) -> RpcResult<String> {
-        log::info!("charge {:?} with {:?}", credit_card, amount);
-        // TODO, leave this stubbed for now
-        Ok(uuid::Uuid::new_v4().to_string())
+    async fn charge_token(
+        &self,
+        cx: RpcContext,
+        amount: Money,
+        user_id: String,
+        token: String,
+    ) -> RpcResult<String> {
+        serve_rpc(LABEL, "charge_token", cx, async {
+            log::info!("charge token {} with {:?}", token, amount);
+            let data = self.vault.get_or_default(&token).await?;
+            // Somebody else's token is as good as no token.
+            if data.last_four.0.is_empty() || data.owner.0 != user_id {
+                return Err(ErrorKind::InvalidArgument.error("unknown payment method"));
+            }
+            if is_expired(data.expiration_year.0, data.expiration_month.0) {
+                return Err(ErrorKind::PaymentDeclined.error("card has expired"));
+            }
+            if data.declines.0 {
+                return Err(ErrorKind::PaymentDeclined.error("card was declined"));
+            }
+            // TODO, leave this stubbed for now
+            Ok(uuid::Uuid::new_v4().to_string())
+        })
+        .await
+    }
+
+    async fn refund(&self, cx: RpcContext, transaction_id: String, amount: Money) -> RpcResult<()> {
+        serve_rpc(LABEL, "refund", cx, async {
+            log::info!("refund {:?} of transaction {}", amount, transaction_id);
+            // TODO, leave this stubbed for now
+            Ok(())
+        })
+        .await
+    }
+
+    async fn health(&self, cx: RpcContext) -> RpcResult<Health> {
+        serve_rpc(LABEL, "health", cx, async { Ok(Health::new(LABEL)) }).await
     }
 }
 
 pub type PaymentClient = ops::Client<PaymentService>;
 
 pub fn component() -> ComponentConfig {
-    ops::component::<PaymentService>("paymentservice".to_string())
+    TokenData::bind("payment_tokens");
+    ops::component::<PaymentService>(LABEL.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ops::Handler;
+
+    #[tokio::test]
+    async fn tokens_only_charge_for_their_owner() {
+        let service = PaymentService::new().await;
+        let card = CreditCardInfo {
+            credit_card_number: "4432801561520454".to_owned(),
+            credit_card_ccv: "672".to_owned(),
+            credit_card_expiration_year: current_year_month().0 + 2,
+            credit_card_expiration_month: 1,
+        };
+        let method = service
+            .tokenize(RpcContext::default(), "alice".to_owned(), card)
+            .await
+            .unwrap();
+        let charge = |user_id: &str| {
+            service.charge_token(
+                RpcContext::default(),
+                Money::from_usd(10, 0),
+                user_id.to_owned(),
+                method.token.clone(),
+            )
+        };
+        assert!(charge("alice").await.is_ok());
+        let err = charge("mallory").await.unwrap_err();
+        assert_eq!(ErrorKind::of(&err), ErrorKind::InvalidArgument);
+    }
 }
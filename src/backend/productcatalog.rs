@@ -1,11 +1,12 @@
-use amimono::{
-    config::ComponentConfig,
-    rpc::{RpcError, RpcResult},
-};
+use amimono::{config::ComponentConfig, rpc::RpcResult};
 use amimono_haze::dashboard::tree;
 use serde::{Deserialize, Serialize};
 
-use crate::shared::Product;
+use crate::shared::{
+    ErrorKind, Health, Product, RpcContext,
+    cache::{self, Source},
+    serve_rpc,
+};
 
 #[derive(Serialize, Deserialize)]
 struct ProductCatalogData {
@@ -15,15 +16,18 @@ struct ProductCatalogData {
 const PRODUCT_CATALOG_DATA: &'static str = include_str!("products.json");
 
 mod ops {
-    use crate::shared::Product;
+    use crate::shared::{Health, Product, RpcContext};
 
-    amimono::rpc_ops! {
-        fn list_products() -> Vec<Product>;
-        fn get_product(id: String) -> Product;
-        fn search_products(query: String) -> Vec<Product>;
+    crate::rpc_ops! {
+        fn list_products(cx: RpcContext) -> Vec<Product>;
+        fn get_product(cx: RpcContext, id: String) -> Product;
+        fn search_products(cx: RpcContext, query: String) -> Vec<Product>;
+        fn health(cx: RpcContext) -> Health;
     }
 }
 
+pub const LABEL: &str = "productcatalogservice";
+
 pub struct ProductCatalogService {
     data: ProductCatalogData,
 }
@@ -32,48 +36,63 @@ impl ops::Handler for ProductCatalogService {
     async fn new() -> ProductCatalogService {
         let data: ProductCatalogData = serde_json::from_str(PRODUCT_CATALOG_DATA).unwrap();
         log::debug!("catalog loaded: {:?}", data.products);
+        // This may be a different catalog from the one caches saw last.
+        cache::reloaded(Source::Catalog).await;
         ProductCatalogService { data }
     }
 
-    async fn list_products(&self) -> RpcResult<Vec<Product>> {
-        log::debug!("list_products()");
-        Ok(self.data.products.clone())
+    async fn list_products(&self, cx: RpcContext) -> RpcResult<Vec<Product>> {
+        serve_rpc(LABEL, "list_products", cx, async {
+            log::debug!("list_products()");
+            Ok(self.data.products.clone())
+        })
+        .await
     }
 
-    async fn get_product(&self, id: String) -> RpcResult<Product> {
-        log::debug!("get_product({id:?})");
-        let res = self
-            .data
-            .products
-            .iter()
-            .filter(|x| x.id == id)
-            .next()
-            .ok_or(RpcError::Misc(format!("no such product with ID: {id}")))?
-            .clone();
-        Ok(res)
+    async fn get_product(&self, cx: RpcContext, id: String) -> RpcResult<Product> {
+        serve_rpc(LABEL, "get_product", cx, async {
+            log::debug!("get_product({id:?})");
+            let res = self
+                .data
+                .products
+                .iter()
+                .filter(|x| x.id == id)
+                .next()
+                .ok_or_else(|| ErrorKind::NotFound.error(format!("no such product with ID: {id}")))?
+                .clone();
+            Ok(res)
+        })
+        .await
     }
 
-    async fn search_products(&self, query: String) -> RpcResult<Vec<Product>> {
-        log::debug!("search_products({query:?})");
-        let query = query.to_lowercase();
-        let res = self
-            .data
-            .products
-            .iter()
-            .filter(|x| {
-                x.name.to_lowercase().contains(&query[..])
-                    || x.description.to_lowercase().contains(&query[..])
-            })
-            .cloned()
-            .collect();
-        Ok(res)
+    async fn search_products(&self, cx: RpcContext, query: String) -> RpcResult<Vec<Product>> {
+        serve_rpc(LABEL, "search_products", cx, async {
+            log::debug!("search_products({query:?})");
+            let query = query.to_lowercase();
+            let res = self
+                .data
+                .products
+                .iter()
+                .filter(|x| {
+                    x.name.to_lowercase().contains(&query[..])
+                        || x.description.to_lowercase().contains(&query[..])
+                })
+                .cloned()
+                .collect();
+            Ok(res)
+        })
+        .await
+    }
+
+    async fn health(&self, cx: RpcContext) -> RpcResult<Health> {
+        serve_rpc(LABEL, "health", cx, async { Ok(Health::new(LABEL)) }).await
     }
 }
 
 pub type ProductCatalogClient = ops::Client<ProductCatalogService>;
 
 pub fn component() -> ComponentConfig {
-    ops::component::<ProductCatalogService>("productcatalogservice".to_owned())
+    ops::component::<ProductCatalogService>(LABEL.to_owned())
 }
 
 pub struct DashboardDirectory;
@@ -81,7 +100,7 @@ pub struct DashboardDirectory;
 impl tree::Directory for DashboardDirectory {
     async fn list(&self) -> tree::TreeResult<Vec<tree::DirEntry>> {
         let its = ProductCatalogClient::new()
-            .list_products()
+            .list_products(RpcContext::current())
             .await?
             .into_iter()
             .map(|it| tree::DirEntry::item(it.id))
@@ -95,7 +114,7 @@ impl tree::Directory for DashboardDirectory {
 
     async fn open_item(&self, name: &str) -> tree::TreeResult<tree::Item> {
         let it = ProductCatalogClient::new()
-            .get_product(name.to_owned())
+            .get_product(RpcContext::current(), name.to_owned())
             .await?;
         match serde_json::to_string_pretty(&it) {
             Ok(s) => Ok(tree::Item::new(s)),
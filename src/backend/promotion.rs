@@ -0,0 +1,375 @@
+use std::{collections::HashMap, time::Duration};
+
+use amimono::{config::ComponentConfig, rpc::RpcResult};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    backend::{CurrencyClient, ProductCatalogClient, productcatalog},
+    shared::{
+        Discount, ErrorKind, Health, Money, OrderItem, RpcContext,
+        cache::{Cache, Source},
+        resilience::{Policy, Resilient},
+        serve_rpc,
+    },
+};
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Rule {
+    PercentOff {
+        product_ids: Vec<String>,
+        percent: u32,
+    },
+    FixedAmountOff {
+        amount_usd: Money,
+    },
+    BuyXGetY {
+        product_id: String,
+        buy: u32,
+        get: u32,
+    },
+    CategoryPercentOff {
+        category: String,
+        percent: u32,
+    },
+}
+
+impl Rule {
+    fn validate(&self) -> Result<(), String> {
+        match self {
+            Rule::BuyXGetY { buy, get, .. } if *buy == 0 || *get == 0 => {
+                Err("buy and get must both be at least 1".to_owned())
+            }
+            Rule::BuyXGetY { buy, get, .. } if buy.checked_add(*get).is_none() => {
+                Err("buy and get are too large".to_owned())
+            }
+            Rule::PercentOff { percent, .. } | Rule::CategoryPercentOff { percent, .. }
+                if *percent > 100 =>
+            {
+                Err("percent must be at most 100".to_owned())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Promotion {
+    id: String,
+    description: String,
+    /// Promotions with a coupon code only apply when the code is entered at
+    /// checkout. All others apply automatically.
+    #[serde(default)]
+    coupon_code: Option<String>,
+    rule: Rule,
+}
+
+#[derive(Serialize, Deserialize)]
+struct PromotionData {
+    promotions: Vec<Promotion>,
+}
+
+const PROMOTION_DATA: &'static str = include_str!("promotions.json");
+
+/// The discounts on an order, and how much they take off each line.
+#[derive(Serialize, Deserialize)]
+pub struct AppliedPromotions {
+    pub discounts: Vec<Discount>,
+    /// Parallel to the order's items. Tax is charged on what is left of each
+    /// line.
+    pub line_discounts: Vec<Money>,
+}
+
+mod ops {
+    use super::AppliedPromotions;
+    use crate::shared::{Health, OrderItem, RpcContext};
+
+    crate::rpc_ops! {
+        fn apply_promotions(cx: RpcContext, items: Vec<OrderItem>, coupon_code: Option<String>) -> AppliedPromotions;
+        fn health(cx: RpcContext) -> Health;
+    }
+}
+
+pub const LABEL: &str = "promotionservice";
+
+/// Product IDs and the categories each is in.
+type Categories = HashMap<String, Vec<String>>;
+
+/// How long product categories are cached. Reloading the catalog empties the
+/// cache sooner.
+const CATALOG_TTL: Duration = Duration::from_secs(300);
+
+const READ: Policy = Policy::idempotent(Duration::from_secs(1));
+
+pub struct PromotionService {
+    data: PromotionData,
+    productcatalog: Resilient<ProductCatalogClient>,
+    currency: CurrencyClient,
+    categories: Cache<(), Categories>,
+}
+
+impl PromotionService {
+    fn is_active(&self, promotion: &Promotion, coupon_code: Option<&str>) -> bool {
+        match (&promotion.coupon_code, coupon_code) {
+            (None, _) => true,
+            (Some(x), Some(y)) => x.eq_ignore_ascii_case(y),
+            (Some(_), None) => false,
+        }
+    }
+
+    /// Returns how much `rule` takes off each line, given what is left of
+    /// each line after the promotions before it.
+    async fn evaluate(
+        &self,
+        rule: &Rule,
+        items: &[OrderItem],
+        remaining: &[Money],
+        currency: &str,
+        categories: &Categories,
+    ) -> RpcResult<Vec<Money>> {
+        let line_total = |x: &OrderItem| x.item.quantity * x.cost.clone();
+        let per_line = |f: &dyn Fn(&OrderItem) -> Option<Money>| -> Vec<Money> {
+            items
+                .iter()
+                .map(|x| f(x).unwrap_or_else(|| Money::zero(currency)))
+                .collect()
+        };
+        let amounts = match rule {
+            Rule::PercentOff {
+                product_ids,
+                percent,
+            } => per_line(&|x| {
+                product_ids
+                    .contains(&x.item.product_id)
+                    .then(|| line_total(x).percent(*percent))
+            }),
+            Rule::FixedAmountOff { amount_usd } => {
+                let amount = self
+                    .currency
+                    .convert(
+                        RpcContext::current(),
+                        amount_usd.clone(),
+                        currency.to_owned(),
+                    )
+                    .await?;
+                apportion(amount, remaining)
+            }
+            Rule::BuyXGetY {
+                product_id,
+                buy,
+                get,
+            } => per_line(&|x| {
+                // Rules are validated on load, so the sum is nonzero and
+                // doesn't overflow.
+                let free = x.item.quantity / (buy + get) * get;
+                (&x.item.product_id == product_id).then(|| free * x.cost.clone())
+            }),
+            Rule::CategoryPercentOff { category, percent } => per_line(&|x| {
+                categories
+                    .get(&x.item.product_id)
+                    .is_some_and(|cs| cs.contains(category))
+                    .then(|| line_total(x).percent(*percent))
+            }),
+        };
+        Ok(amounts)
+    }
+}
+
+/// Splits an order-wide `amount` over the lines in proportion to `weights`,
+/// taking no more than the weights add up to. What rounding leaves over goes
+/// on the last line that has any weight.
+fn apportion(amount: Money, weights: &[Money]) -> Vec<Money> {
+    let total = weights
+        .iter()
+        .fold(Money::zero(&amount.currency_code), |acc, x| acc + x.clone());
+    let amount = if amount > total {
+        total.clone()
+    } else {
+        amount
+    };
+    let mut res: Vec<Money> = weights.iter().map(|w| amount.share(w, &total)).collect();
+    let given = res
+        .iter()
+        .fold(Money::zero(&amount.currency_code), |acc, x| acc + x.clone());
+    if let Some(i) = weights.iter().rposition(|w| !w.is_zero()) {
+        res[i] = res[i].clone() + (amount - given);
+    }
+    res
+}
+
+impl ops::Handler for PromotionService {
+    async fn new() -> Self {
+        let data: PromotionData = serde_json::from_str(PROMOTION_DATA).unwrap();
+        for promotion in data.promotions.iter() {
+            if let Err(e) = promotion.rule.validate() {
+                panic!("invalid promotion {}: {}", promotion.id, e);
+            }
+        }
+        log::debug!("loaded {} promotions", data.promotions.len());
+        PromotionService {
+            data,
+            productcatalog: Resilient::new(productcatalog::LABEL, ProductCatalogClient::new()),
+            currency: CurrencyClient::new(),
+            categories: Cache::new(
+                "promotion.categories",
+                Some(Source::Catalog),
+                1,
+                CATALOG_TTL,
+            ),
+        }
+    }
+
+    async fn apply_promotions(
+        &self,
+        cx: RpcContext,
+        items: Vec<OrderItem>,
+        coupon_code: Option<String>,
+    ) -> RpcResult<AppliedPromotions> {
+        serve_rpc(LABEL, "apply_promotions", cx, async {
+            log::info!(
+                "apply_promotions({} items, coupon_code={:?})",
+                items.len(),
+                coupon_code
+            );
+
+            let coupon_code = coupon_code
+                .as_deref()
+                .map(str::trim)
+                .filter(|x| !x.is_empty());
+            if let Some(code) = coupon_code {
+                let known = self
+                    .data
+                    .promotions
+                    .iter()
+                    .any(|p| p.coupon_code.is_some() && self.is_active(p, Some(code)));
+                if !known {
+                    return Err(
+                        ErrorKind::InvalidArgument.error(format!("unknown coupon code: {}", code))
+                    );
+                }
+            }
+
+            let currency = match items.first() {
+                Some(x) => x.cost.currency_code.clone(),
+                None => {
+                    return Ok(AppliedPromotions {
+                        discounts: Vec::new(),
+                        line_discounts: Vec::new(),
+                    });
+                }
+            };
+            let categories = self
+                .categories
+                .get_or_load((), || async {
+                    let products = self
+                        .productcatalog
+                        .call("list_products", READ, |c| {
+                            c.list_products(RpcContext::current())
+                        })
+                        .await?;
+                    Ok(products.into_iter().map(|p| (p.id, p.categories)).collect())
+                })
+                .await?;
+
+            // Discounts are applied in the order they are listed, and each is
+            // capped line by line so that no line, and so the order total,
+            // can ever go below zero.
+            let mut remaining: Vec<Money> = items
+                .iter()
+                .map(|x| x.item.quantity * x.cost.clone())
+                .collect();
+            let mut res = AppliedPromotions {
+                discounts: Vec::new(),
+                line_discounts: vec![Money::zero(&currency); items.len()],
+            };
+            for promotion in self.data.promotions.iter() {
+                if !self.is_active(promotion, coupon_code) {
+                    continue;
+                }
+                let amounts = self
+                    .evaluate(&promotion.rule, &items, &remaining, &currency, &categories)
+                    .await?;
+                let mut amount = Money::zero(&currency);
+                for (i, line) in amounts.into_iter().enumerate() {
+                    let line = if line > remaining[i] {
+                        remaining[i].clone()
+                    } else {
+                        line
+                    };
+                    remaining[i] = remaining[i].clone() - line.clone();
+                    res.line_discounts[i] = res.line_discounts[i].clone() + line.clone();
+                    amount = amount + line;
+                }
+                if amount.is_zero() {
+                    continue;
+                }
+                res.discounts.push(Discount {
+                    promotion_id: promotion.id.clone(),
+                    description: promotion.description.clone(),
+                    amount,
+                });
+            }
+            Ok(res)
+        })
+        .await
+    }
+
+    async fn health(&self, cx: RpcContext) -> RpcResult<Health> {
+        serve_rpc(LABEL, "health", cx, async { Ok(Health::new(LABEL)) }).await
+    }
+}
+
+pub type PromotionClient = ops::Client<PromotionService>;
+
+pub fn component() -> ComponentConfig {
+    ops::component::<PromotionService>(LABEL.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn usd(units: i64, nanos: i32) -> Money {
+        Money {
+            currency_code: "USD".to_owned(),
+            units,
+            nanos,
+        }
+    }
+
+    #[test]
+    fn apportion_splits_by_weight_and_caps() {
+        let weights = [usd(1, 0), usd(0, 0), usd(2, 0)];
+        let res = apportion(usd(1, 0), &weights);
+        assert_eq!(
+            res,
+            vec![usd(0, 333_333_333), usd(0, 0), usd(0, 666_666_667)]
+        );
+
+        let res = apportion(usd(10, 0), &weights);
+        assert_eq!(res, weights.to_vec());
+    }
+
+    #[test]
+    fn validate_rejects_impossible_rules() {
+        let percent_off = |percent| Rule::PercentOff {
+            product_ids: Vec::new(),
+            percent,
+        };
+        assert!(percent_off(100).validate().is_ok());
+        assert!(percent_off(101).validate().is_err());
+        let category = Rule::CategoryPercentOff {
+            category: "kitchen".to_owned(),
+            percent: u32::MAX,
+        };
+        assert!(category.validate().is_err());
+        let buy_x_get_y = |buy, get| Rule::BuyXGetY {
+            product_id: String::new(),
+            buy,
+            get,
+        };
+        assert!(buy_x_get_y(2, 1).validate().is_ok());
+        assert!(buy_x_get_y(0, 1).validate().is_err());
+        assert!(buy_x_get_y(u32::MAX, 1).validate().is_err());
+    }
+}
@@ -0,0 +1,289 @@
+//! Where the currency service gets its exchange rates. A `RateProvider`
+//! fetches a full set of rates; the service decides when to ask.
+//!
+//! The provider is picked from the environment:
+//!
+//! * `BOUTIQUE_RATES_URL`: an HTTP feed, such as the ECB's daily reference
+//!   rates or a local stand-in serving a file.
+//! * `BOUTIQUE_RATES_FILE`: a file on disk.
+//! * Otherwise the snapshot in `conversion.json`, built into the binary.
+//!
+//! Feeds and files may be ECB XML (`<Cube currency='USD' rate='1.0872'/>`
+//! inside `<Cube time='2024-01-15'>`) or JSON, either a map of currency to
+//! rate like `conversion.json` or `{"base": "EUR", "date": "2024-01-15",
+//! "rates": {...}}`. Rates against a base other than the euro are converted.
+
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use futures::future::BoxFuture;
+use serde::Deserialize;
+
+use crate::shared::now_secs;
+
+const CURRENCY_CONVERSION_DATA: &'static str = include_str!("conversion.json");
+
+/// How long an HTTP feed has to answer.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A set of exchange rates.
+#[derive(Debug, PartialEq)]
+pub struct Rates {
+    /// Units of each currency one euro buys.
+    pub per_euro: HashMap<String, f64>,
+    /// When the rates were published, in seconds since the epoch. Sources
+    /// that don't say use when they were read.
+    pub as_of: u64,
+}
+
+pub trait RateProvider: Send + Sync {
+    /// Where the rates come from, for logs and the dashboard.
+    fn describe(&self) -> String;
+
+    /// Whether the rates can change, and so are worth fetching again.
+    fn refreshes(&self) -> bool {
+        true
+    }
+
+    fn fetch(&self) -> BoxFuture<'_, Result<Rates, String>>;
+}
+
+/// The snapshot built into the binary.
+pub struct Embedded;
+
+impl RateProvider for Embedded {
+    fn describe(&self) -> String {
+        "embedded".to_owned()
+    }
+
+    fn refreshes(&self) -> bool {
+        false
+    }
+
+    fn fetch(&self) -> BoxFuture<'_, Result<Rates, String>> {
+        Box::pin(async { parse(CURRENCY_CONVERSION_DATA, now_secs()) })
+    }
+}
+
+/// A file on disk. Rates without a date are dated by the file's mtime.
+pub struct RatesFile {
+    pub path: PathBuf,
+}
+
+impl RateProvider for RatesFile {
+    fn describe(&self) -> String {
+        format!("file {}", self.path.display())
+    }
+
+    fn fetch(&self) -> BoxFuture<'_, Result<Rates, String>> {
+        Box::pin(async {
+            let text = std::fs::read_to_string(&self.path).map_err(|e| e.to_string())?;
+            let modified = std::fs::metadata(&self.path)
+                .and_then(|x| x.modified())
+                .map_or_else(|_| now_secs(), secs_since_epoch);
+            parse(&text, modified)
+        })
+    }
+}
+
+/// An HTTP feed.
+pub struct HttpFeed {
+    pub client: reqwest::Client,
+    pub url: String,
+}
+
+impl RateProvider for HttpFeed {
+    fn describe(&self) -> String {
+        self.url.clone()
+    }
+
+    fn fetch(&self) -> BoxFuture<'_, Result<Rates, String>> {
+        Box::pin(async {
+            let text = self
+                .client
+                .get(self.url.as_str())
+                .timeout(FETCH_TIMEOUT)
+                .send()
+                .await
+                .and_then(|x| x.error_for_status())
+                .map_err(|e| e.to_string())?
+                .text()
+                .await
+                .map_err(|e| e.to_string())?;
+            parse(&text, now_secs())
+        })
+    }
+}
+
+/// The provider the environment asks for.
+pub fn from_env() -> Box<dyn RateProvider> {
+    if let Ok(url) = std::env::var("BOUTIQUE_RATES_URL") {
+        Box::new(HttpFeed {
+            client: reqwest::Client::new(),
+            url,
+        })
+    } else if let Ok(path) = std::env::var("BOUTIQUE_RATES_FILE") {
+        Box::new(RatesFile {
+            path: PathBuf::from(path),
+        })
+    } else {
+        Box::new(Embedded)
+    }
+}
+
+fn secs_since_epoch(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+/// Parses rates in any of the formats above. `as_of` dates rates that
+/// don't carry a date of their own.
+pub fn parse(text: &str, as_of: u64) -> Result<Rates, String> {
+    let rates = match text.trim_start().starts_with('<') {
+        true => parse_ecb(text, as_of)?,
+        false => parse_json(text, as_of)?,
+    };
+    for (code, rate) in rates.per_euro.iter() {
+        if !(rate.is_finite() && *rate > 0.0) {
+            return Err(format!("bad rate for {}: {}", code, rate));
+        }
+    }
+    match rates.per_euro.get("EUR") {
+        Some(x) if *x == 1.0 => Ok(rates),
+        _ => Err("rates must include EUR".to_owned()),
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum JsonRates {
+    Feed {
+        #[serde(default)]
+        base: Option<String>,
+        #[serde(default)]
+        date: Option<String>,
+        #[serde(default)]
+        timestamp: Option<u64>,
+        rates: HashMap<String, f64>,
+    },
+    Map(HashMap<String, f64>),
+}
+
+fn parse_json(text: &str, as_of: u64) -> Result<Rates, String> {
+    let parsed: JsonRates = serde_json::from_str(text).map_err(|e| e.to_string())?;
+    let (base, date, timestamp, mut rates) = match parsed {
+        JsonRates::Feed {
+            base,
+            date,
+            timestamp,
+            rates,
+        } => (base, date, timestamp, rates),
+        JsonRates::Map(rates) => (None, None, None, rates),
+    };
+    let as_of = match (timestamp, date) {
+        (Some(x), _) => x,
+        (None, Some(date)) => parse_date(&date)?,
+        (None, None) => as_of,
+    };
+    if let Some(base) = base {
+        rates.insert(base, 1.0);
+    }
+    // Rates against another base become rates against the euro.
+    let euro = *rates.get("EUR").ok_or("rates must include EUR")?;
+    let per_euro = rates.into_iter().map(|(k, v)| (k, v / euro)).collect();
+    Ok(Rates { per_euro, as_of })
+}
+
+/// Reads the `Cube` elements of an ECB reference rates document. The
+/// format is fixed enough that attributes can be picked out directly.
+fn parse_ecb(text: &str, as_of: u64) -> Result<Rates, String> {
+    let mut per_euro = HashMap::from([("EUR".to_owned(), 1.0)]);
+    let mut as_of = as_of;
+    for element in text.split("<Cube").skip(1) {
+        let element = element.split('>').next().unwrap_or_default();
+        if let Some(date) = attribute(element, "time") {
+            as_of = parse_date(date)?;
+        }
+        if let Some(currency) = attribute(element, "currency") {
+            let rate =
+                attribute(element, "rate").ok_or_else(|| format!("no rate for {}", currency))?;
+            let rate = rate
+                .parse()
+                .map_err(|_| format!("bad rate for {}: {}", currency, rate))?;
+            per_euro.insert(currency.to_owned(), rate);
+        }
+    }
+    if per_euro.len() == 1 {
+        return Err("no rates in document".to_owned());
+    }
+    Ok(Rates { per_euro, as_of })
+}
+
+fn attribute<'a>(element: &'a str, name: &str) -> Option<&'a str> {
+    ['\'', '"'].into_iter().find_map(|quote| {
+        let start = element.find(&format!(" {}={}", name, quote))? + name.len() + 3;
+        let len = element[start..].find(quote)?;
+        Some(&element[start..start + len])
+    })
+}
+
+/// Midnight UTC of a `YYYY-MM-DD` date, in seconds since the epoch.
+fn parse_date(date: &str) -> Result<u64, String> {
+    let bad = || format!("bad date: {:?}", date);
+    let mut parts = date.splitn(3, '-').map(|x| x.parse::<u32>().ok());
+    let (Some(Some(y)), Some(Some(m)), Some(Some(d))) = (parts.next(), parts.next(), parts.next())
+    else {
+        return Err(bad());
+    };
+    let month = time::Month::try_from(m as u8).map_err(|_| bad())?;
+    let date = time::Date::from_calendar_date(y as i32, month, d as u8).map_err(|_| bad())?;
+    Ok(date.midnight().assume_utc().unix_timestamp().max(0) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_ecb_xml() {
+        let xml = "<?xml version=\"1.0\" encoding=\"UTF-8\"?>
+<gesmes:Envelope xmlns:gesmes=\"http://www.gesmes.org/xml/2002-08-01\">
+  <Cube>
+    <Cube time='2024-01-15'>
+      <Cube currency='USD' rate='1.0950'/>
+      <Cube currency='JPY' rate='159.72'/>
+    </Cube>
+  </Cube>
+</gesmes:Envelope>";
+        let rates = parse(xml, 0).unwrap();
+        assert_eq!(rates.as_of, 1_705_276_800);
+        assert_eq!(rates.per_euro["EUR"], 1.0);
+        assert_eq!(rates.per_euro["USD"], 1.095);
+        assert_eq!(rates.per_euro["JPY"], 159.72);
+    }
+
+    #[test]
+    fn parses_json_against_any_base() {
+        let json = r#"{"base": "USD", "timestamp": 1700000000,
+            "rates": {"EUR": 0.5, "GBP": 0.4}}"#;
+        let rates = parse(json, 0).unwrap();
+        assert_eq!(rates.as_of, 1_700_000_000);
+        assert_eq!(rates.per_euro["EUR"], 1.0);
+        assert_eq!(rates.per_euro["USD"], 2.0);
+        assert_eq!(rates.per_euro["GBP"], 0.8);
+
+        let rates = parse(CURRENCY_CONVERSION_DATA, 42).unwrap();
+        assert_eq!(rates.as_of, 42);
+        assert_eq!(rates.per_euro["USD"], 1.1305);
+    }
+
+    #[test]
+    fn rejects_unusable_rates() {
+        assert!(parse(r#"{"USD": 1.1}"#, 0).is_err());
+        assert!(parse(r#"{"EUR": 1.0, "USD": 0}"#, 0).is_err());
+        assert!(parse("<Cube time='2024-13-01'><Cube currency='USD' rate='1'/>", 0).is_err());
+        assert!(parse("<html>not rates</html>", 0).is_err());
+    }
+}
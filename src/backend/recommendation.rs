@@ -1,44 +1,81 @@
-use crate::backend::ProductCatalogClient;
+use std::time::Duration;
+
+use crate::{
+    backend::ProductCatalogClient,
+    shared::{
+        Health, Product, RpcContext,
+        cache::{Cache, Source},
+        serve_rpc,
+    },
+};
 use amimono::{config::ComponentConfig, rpc::RpcResult};
 use rand::seq::SliceRandom;
 
 mod ops {
-    amimono::rpc_ops! {
-        fn list_recommendations(user_id: String, product_ids: Vec<String>) -> Vec<String>;
+    use crate::shared::{Health, RpcContext};
+
+    crate::rpc_ops! {
+        fn list_recommendations(cx: RpcContext, user_id: String, product_ids: Vec<String>) -> Vec<String>;
+        fn health(cx: RpcContext) -> Health;
     }
 }
 
+pub const LABEL: &str = "recommendationservice";
+
 pub struct RecommendationService {
     productcatalog: ProductCatalogClient,
+    products: Cache<(), Vec<Product>>,
 }
 
 const NUM_RECOMMENDATIONS: usize = 3;
 
+/// How long the catalog is cached. Reloading it empties the cache sooner.
+const CATALOG_TTL: Duration = Duration::from_secs(300);
+
 impl ops::Handler for RecommendationService {
     async fn new() -> Self {
         RecommendationService {
             productcatalog: ProductCatalogClient::new(),
+            products: Cache::new(
+                "recommendation.products",
+                Some(Source::Catalog),
+                1,
+                CATALOG_TTL,
+            ),
         }
     }
 
     async fn list_recommendations(
         &self,
+        cx: RpcContext,
         _user_id: String,
         _product_ids: Vec<String>,
     ) -> RpcResult<Vec<String>> {
-        let mut products = self.productcatalog.list_products().await?;
-        products.shuffle(&mut rand::rng());
-        let ids = products
-            .into_iter()
-            .take(NUM_RECOMMENDATIONS)
-            .map(|p| p.id)
-            .collect::<Vec<_>>();
-        Ok(ids)
+        serve_rpc(LABEL, "list_recommendations", cx, async {
+            let mut products = self
+                .products
+                .get_or_load((), || {
+                    self.productcatalog.list_products(RpcContext::current())
+                })
+                .await?;
+            products.shuffle(&mut rand::rng());
+            let ids = products
+                .into_iter()
+                .take(NUM_RECOMMENDATIONS)
+                .map(|p| p.id)
+                .collect::<Vec<_>>();
+            Ok(ids)
+        })
+        .await
+    }
+
+    async fn health(&self, cx: RpcContext) -> RpcResult<Health> {
+        serve_rpc(LABEL, "health", cx, async { Ok(Health::new(LABEL)) }).await
     }
 }
 
 pub type RecommendationClient = ops::Client<RecommendationService>;
 
 pub fn component() -> ComponentConfig {
-    ops::component::<RecommendationService>("recommendationservice".to_string())
+    ops::component::<RecommendationService>(LABEL.to_owned())
 }
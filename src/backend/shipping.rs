@@ -1,16 +1,19 @@
 use amimono::{config::ComponentConfig, rpc::RpcResult};
 
-use crate::shared::{Address, CartItem, Money};
+use crate::shared::{Address, CartItem, Health, Money, RpcContext, serve_rpc};
 
 mod ops {
-    use crate::shared::{Address, CartItem, Money};
+    use crate::shared::{Address, CartItem, Health, Money, RpcContext};
 
-    amimono::rpc_ops! {
-        fn get_quote(address: Address, items: Vec<CartItem>) -> Money;
-        fn ship_order(address: Address, items: Vec<CartItem>) -> String;
+    crate::rpc_ops! {
+        fn get_quote(cx: RpcContext, address: Address, items: Vec<CartItem>) -> Money;
+        fn ship_order(cx: RpcContext, address: Address, items: Vec<CartItem>) -> String;
+        fn health(cx: RpcContext) -> Health;
     }
 }
 
+pub const LABEL: &str = "shippingservice";
+
 pub struct ShippingService;
 
 impl ops::Handler for ShippingService {
@@ -18,17 +21,34 @@ impl ops::Handler for ShippingService {
         ShippingService
     }
 
-    async fn get_quote(&self, _address: Address, _items: Vec<CartItem>) -> RpcResult<Money> {
-        Ok(Money::from_usd(3, 50))
+    async fn get_quote(
+        &self,
+        cx: RpcContext,
+        _address: Address,
+        _items: Vec<CartItem>,
+    ) -> RpcResult<Money> {
+        serve_rpc(LABEL, "get_quote", cx, async { Ok(Money::from_usd(3, 50)) }).await
+    }
+
+    async fn ship_order(
+        &self,
+        cx: RpcContext,
+        _address: Address,
+        _items: Vec<CartItem>,
+    ) -> RpcResult<String> {
+        serve_rpc(LABEL, "ship_order", cx, async {
+            Ok(uuid::Uuid::new_v4().to_string())
+        })
+        .await
     }
 
-    async fn ship_order(&self, _address: Address, _items: Vec<CartItem>) -> RpcResult<String> {
-        Ok(uuid::Uuid::new_v4().to_string())
+    async fn health(&self, cx: RpcContext) -> RpcResult<Health> {
+        serve_rpc(LABEL, "health", cx, async { Ok(Health::new(LABEL)) }).await
     }
 }
 
 pub type ShippingClient = ops::Client<ShippingService>;
 
 pub fn component() -> ComponentConfig {
-    ops::component::<ShippingService>("shippingservice".to_string())
+    ops::component::<ShippingService>(LABEL.to_owned())
 }
@@ -0,0 +1,193 @@
+use std::{collections::HashMap, time::Duration};
+
+use amimono::{config::ComponentConfig, rpc::RpcResult};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    backend::{ProductCatalogClient, productcatalog},
+    shared::{
+        Address, Health, Money, OrderItem, RpcContext, TaxLine,
+        cache::{Cache, Source},
+        resilience::{Policy, Resilient},
+        serve_rpc,
+    },
+};
+
+#[derive(Serialize, Deserialize)]
+struct Jurisdiction {
+    country: String,
+    /// Jurisdictions without a state apply to every address in the country.
+    #[serde(default)]
+    state: Option<String>,
+    description: String,
+    rate_bps: u32,
+    /// Overrides `rate_bps` for products in the given categories. When a
+    /// product is in several overridden categories the lowest rate wins.
+    #[serde(default)]
+    category_rates_bps: HashMap<String, u32>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct TaxData {
+    country_aliases: HashMap<String, String>,
+    jurisdictions: Vec<Jurisdiction>,
+}
+
+const TAX_DATA: &'static str = include_str!("tax_rates.json");
+
+impl TaxData {
+    fn country_code(&self, country: &str) -> String {
+        let country = country.trim();
+        match self.country_aliases.get(&country.to_lowercase()) {
+            Some(x) => x.clone(),
+            None => country.to_uppercase(),
+        }
+    }
+
+    fn jurisdictions_for<'a>(&'a self, address: &Address) -> Vec<&'a Jurisdiction> {
+        let country = self.country_code(&address.country);
+        let state = address.state.trim();
+        self.jurisdictions
+            .iter()
+            .filter(|j| j.country == country)
+            .filter(|j| match &j.state {
+                Some(x) => x.eq_ignore_ascii_case(state),
+                None => true,
+            })
+            .collect()
+    }
+}
+
+impl Jurisdiction {
+    fn rate_for(&self, categories: &[String]) -> u32 {
+        categories
+            .iter()
+            .filter_map(|c| self.category_rates_bps.get(c).cloned())
+            .min()
+            .unwrap_or(self.rate_bps)
+    }
+}
+
+mod ops {
+    use crate::shared::{Address, Health, Money, OrderItem, RpcContext, TaxLine};
+
+    crate::rpc_ops! {
+        fn calculate_tax(
+            cx: RpcContext,
+            address: Address,
+            items: Vec<OrderItem>,
+            line_discounts: Vec<Money>
+        ) -> Vec<TaxLine>;
+        fn health(cx: RpcContext) -> Health;
+    }
+}
+
+pub const LABEL: &str = "taxservice";
+
+/// How long product categories are cached. Reloading the catalog empties the
+/// cache sooner.
+const CATALOG_TTL: Duration = Duration::from_secs(300);
+
+const READ: Policy = Policy::idempotent(Duration::from_secs(1));
+
+pub struct TaxService {
+    data: TaxData,
+    productcatalog: Resilient<ProductCatalogClient>,
+    /// Product IDs and the categories each is in.
+    categories: Cache<(), HashMap<String, Vec<String>>>,
+}
+
+impl ops::Handler for TaxService {
+    async fn new() -> Self {
+        let data: TaxData = serde_json::from_str(TAX_DATA).unwrap();
+        log::debug!("loaded {} tax jurisdictions", data.jurisdictions.len());
+        TaxService {
+            data,
+            productcatalog: Resilient::new(productcatalog::LABEL, ProductCatalogClient::new()),
+            categories: Cache::new("tax.categories", Some(Source::Catalog), 1, CATALOG_TTL),
+        }
+    }
+
+    /// Tax is assessed per item on what is paid for it, in the order
+    /// currency: its list price less `line_discounts`, which is parallel to
+    /// `items`. Shipping is not taxed.
+    async fn calculate_tax(
+        &self,
+        cx: RpcContext,
+        address: Address,
+        items: Vec<OrderItem>,
+        line_discounts: Vec<Money>,
+    ) -> RpcResult<Vec<TaxLine>> {
+        serve_rpc(LABEL, "calculate_tax", cx, async {
+            log::info!(
+                "calculate_tax(country={:?}, state={:?}, {} items)",
+                address.country,
+                address.state,
+                items.len()
+            );
+
+            let jurisdictions = self.data.jurisdictions_for(&address);
+            let currency = match items.first() {
+                Some(x) if !jurisdictions.is_empty() => x.cost.currency_code.clone(),
+                _ => return Ok(Vec::new()),
+            };
+            let categories = self
+                .categories
+                .get_or_load((), || async {
+                    let products = self
+                        .productcatalog
+                        .call("list_products", READ, |c| {
+                            c.list_products(RpcContext::current())
+                        })
+                        .await?;
+                    Ok(products.into_iter().map(|p| (p.id, p.categories)).collect())
+                })
+                .await?;
+
+            let paid: Vec<Money> = items
+                .iter()
+                .enumerate()
+                .map(|(i, x)| {
+                    let line = x.item.quantity * x.cost.clone();
+                    match line_discounts.get(i) {
+                        Some(d) if *d > line => Money::zero(&currency),
+                        Some(d) => line - d.clone(),
+                        None => line,
+                    }
+                })
+                .collect();
+            let res = jurisdictions
+                .into_iter()
+                .map(|j| {
+                    let amount = items.iter().zip(paid.iter()).fold(
+                        Money::zero(&currency),
+                        |acc, (x, paid)| {
+                            let cats = categories
+                                .get(&x.item.product_id)
+                                .map(|x| x.as_slice())
+                                .unwrap_or(&[]);
+                            acc + paid.basis_points(j.rate_for(cats))
+                        },
+                    );
+                    TaxLine {
+                        description: j.description.clone(),
+                        amount,
+                    }
+                })
+                .filter(|x| !x.amount.is_zero())
+                .collect();
+            Ok(res)
+        })
+        .await
+    }
+
+    async fn health(&self, cx: RpcContext) -> RpcResult<Health> {
+        serve_rpc(LABEL, "health", cx, async { Ok(Health::new(LABEL)) }).await
+    }
+}
+
+pub type TaxClient = ops::Client<TaxService>;
+
+pub fn component() -> ComponentConfig {
+    ops::component::<TaxService>(LABEL.to_owned())
+}
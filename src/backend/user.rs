@@ -0,0 +1,414 @@
+use std::collections::HashMap;
+
+use amimono::{config::ComponentConfig, rpc::RpcResult};
+use amimono_haze::crdt::{
+    Crdt, StoredCrdt,
+    crdt::{Max, Version},
+};
+use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier, password_hash::SaltString};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::shared::{
+    Address, CrdtClient, ErrorKind, Health, PaymentMethod, RpcContext, is_valid_email, now_secs,
+    serve_rpc,
+};
+
+const MIN_PASSWORD_LEN: usize = 8;
+
+/// Hashing cost grows with the password, so very long ones are refused.
+const MAX_PASSWORD_LEN: usize = 256;
+
+#[derive(Serialize, Deserialize)]
+pub struct Profile {
+    pub user_id: String,
+    pub email: String,
+    pub addresses: Vec<SavedAddress>,
+    pub payment_methods: Vec<PaymentMethod>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SavedAddress {
+    pub id: String,
+    pub address: Address,
+}
+
+/// Login credentials, keyed by normalized email. An empty `user_id` means
+/// the email is not registered.
+#[derive(Serialize, Deserialize)]
+struct AccountData {
+    user_id: Max<String>,
+    password_hash: Version<u32, Max<String>>,
+}
+
+impl Crdt for AccountData {
+    fn merge_from(&mut self, other: Self) {
+        self.user_id.merge_from(other.user_id);
+        self.password_hash.merge_from(other.password_hash);
+    }
+}
+
+impl StoredCrdt for AccountData {}
+
+impl Default for AccountData {
+    fn default() -> Self {
+        Self {
+            user_id: Max(String::new()),
+            password_hash: Version(0, Max(String::new())),
+        }
+    }
+}
+
+/// An address as stored in a profile. Address IDs are derived from the
+/// address itself, so every field of a given entry is the same on every
+/// replica and only `last_used` ever changes.
+#[derive(Serialize, Deserialize)]
+struct StoredAddress {
+    street_address: Max<String>,
+    city: Max<String>,
+    state: Max<String>,
+    country: Max<String>,
+    zip_code: Max<String>,
+    last_used: Max<u64>,
+}
+
+impl Crdt for StoredAddress {
+    fn merge_from(&mut self, other: Self) {
+        self.street_address.merge_from(other.street_address);
+        self.city.merge_from(other.city);
+        self.state.merge_from(other.state);
+        self.country.merge_from(other.country);
+        self.zip_code.merge_from(other.zip_code);
+        self.last_used.merge_from(other.last_used);
+    }
+}
+
+impl StoredAddress {
+    fn to_address(&self) -> Address {
+        Address {
+            street_address: self.street_address.0.clone(),
+            city: self.city.0.clone(),
+            state: self.state.0.clone(),
+            country: self.country.0.clone(),
+            zip_code: self.zip_code.0.clone(),
+        }
+    }
+}
+
+/// A payment token as stored in a profile, keyed by the token. Like
+/// addresses, only `last_used` changes after the entry is created.
+#[derive(Serialize, Deserialize)]
+struct StoredPaymentMethod {
+    card_type: Max<String>,
+    last_four: Max<String>,
+    expiration_year: Max<i32>,
+    expiration_month: Max<i32>,
+    last_used: Max<u64>,
+}
+
+impl Crdt for StoredPaymentMethod {
+    fn merge_from(&mut self, other: Self) {
+        self.card_type.merge_from(other.card_type);
+        self.last_four.merge_from(other.last_four);
+        self.expiration_year.merge_from(other.expiration_year);
+        self.expiration_month.merge_from(other.expiration_month);
+        self.last_used.merge_from(other.last_used);
+    }
+}
+
+impl StoredPaymentMethod {
+    fn to_payment_method(&self, token: &str) -> PaymentMethod {
+        PaymentMethod {
+            token: token.to_owned(),
+            card_type: self.card_type.0.clone(),
+            last_four: self.last_four.0.clone(),
+            expiration_year: self.expiration_year.0,
+            expiration_month: self.expiration_month.0,
+        }
+    }
+
+    fn is_same_card(&self, method: &PaymentMethod) -> bool {
+        self.card_type.0 == method.card_type
+            && self.last_four.0 == method.last_four
+            && self.expiration_year.0 == method.expiration_year
+            && self.expiration_month.0 == method.expiration_month
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct ProfileData {
+    email: Max<String>,
+    addresses: Version<u32, HashMap<String, StoredAddress>>,
+    #[serde(default = "empty_payment_methods")]
+    payment_methods: Version<u32, HashMap<String, StoredPaymentMethod>>,
+}
+
+fn empty_payment_methods() -> Version<u32, HashMap<String, StoredPaymentMethod>> {
+    Version(0, HashMap::new())
+}
+
+impl Crdt for ProfileData {
+    fn merge_from(&mut self, other: Self) {
+        self.email.merge_from(other.email);
+        self.addresses.merge_from(other.addresses);
+        self.payment_methods.merge_from(other.payment_methods);
+    }
+}
+
+impl StoredCrdt for ProfileData {}
+
+impl Default for ProfileData {
+    fn default() -> Self {
+        Self {
+            email: Max(String::new()),
+            addresses: Version(0, HashMap::new()),
+            payment_methods: empty_payment_methods(),
+        }
+    }
+}
+
+fn normalize_email(email: &str) -> String {
+    email.trim().to_lowercase()
+}
+
+fn address_id(address: &Address) -> String {
+    let mut hasher = Sha256::new();
+    for field in [
+        &address.street_address,
+        &address.city,
+        &address.state,
+        &address.country,
+        &address.zip_code,
+    ] {
+        hasher.update(field.trim().to_lowercase().as_bytes());
+        hasher.update([0]);
+    }
+    hasher.finalize()[..8]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Argon2id with the crate defaults, which follow the OWASP recommendation.
+/// Hashing is deliberately slow, so it runs off the async workers.
+async fn hash_password(password: String) -> RpcResult<String> {
+    let salt = SaltString::encode_b64(&rand::random::<[u8; 16]>())
+        .map_err(|e| ErrorKind::Internal.error(e))?;
+    tokio::task::spawn_blocking(move || {
+        Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map(|h| h.to_string())
+            .map_err(|e| ErrorKind::Internal.error(e))
+    })
+    .await
+    .map_err(|e| ErrorKind::Internal.error(e))?
+}
+
+async fn verify_password(password: String, hash: String) -> RpcResult<bool> {
+    tokio::task::spawn_blocking(move || {
+        let hash = PasswordHash::new(&hash).map_err(|e| ErrorKind::Internal.error(e))?;
+        Ok(Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok())
+    })
+    .await
+    .map_err(|e| ErrorKind::Internal.error(e))?
+}
+
+mod ops {
+    use super::Profile;
+    use crate::shared::{Address, Health, PaymentMethod, RpcContext};
+
+    crate::rpc_ops! {
+        fn register(cx: RpcContext, email: String, password: String) -> String;
+        fn login(cx: RpcContext, email: String, password: String) -> String;
+        fn get_profile(cx: RpcContext, user_id: String) -> Profile;
+        fn save_address(cx: RpcContext, user_id: String, address: Address) -> String;
+        fn save_payment_method(cx: RpcContext, user_id: String, method: PaymentMethod) -> ();
+        fn health(cx: RpcContext) -> Health;
+    }
+}
+
+pub const LABEL: &str = "userservice";
+
+pub struct UserService {
+    accounts: CrdtClient<AccountData>,
+    profiles: CrdtClient<ProfileData>,
+    /// Checked against when an email is not registered, so a failed login
+    /// takes as long whether or not the account exists.
+    dummy_hash: String,
+}
+
+impl ops::Handler for UserService {
+    async fn new() -> UserService {
+        UserService {
+            accounts: CrdtClient::new("users".to_owned()),
+            profiles: CrdtClient::new("profiles".to_owned()),
+            dummy_hash: hash_password(uuid::Uuid::new_v4().to_string())
+                .await
+                .unwrap(),
+        }
+    }
+
+    async fn register(&self, cx: RpcContext, email: String, password: String) -> RpcResult<String> {
+        serve_rpc(LABEL, "register", cx, async {
+            let email = normalize_email(&email);
+            log::info!("register({})", email);
+            if !is_valid_email(&email) {
+                return Err(ErrorKind::InvalidArgument.error("Enter a valid email address."));
+            }
+            if password.chars().count() < MIN_PASSWORD_LEN {
+                return Err(ErrorKind::InvalidArgument.error(format!(
+                    "Passwords must be at least {} characters.",
+                    MIN_PASSWORD_LEN
+                )));
+            }
+            if password.len() > MAX_PASSWORD_LEN {
+                return Err(ErrorKind::InvalidArgument.error("That password is too long."));
+            }
+
+            let mut account = self.accounts.get_or_default(&email).await?;
+            if !account.user_id.0.is_empty() {
+                return Err(
+                    ErrorKind::InvalidArgument.error("An account with that email already exists.")
+                );
+            }
+            let user_id = uuid::Uuid::new_v4().to_string();
+            account.user_id = Max(user_id.clone());
+            account.password_hash = Version(1, Max(hash_password(password).await?));
+            self.accounts.put(&email, account).await?;
+
+            let mut profile = self.profiles.get_or_default(&user_id).await?;
+            profile.email = Max(email);
+            self.profiles.put(&user_id, profile).await?;
+
+            Ok(user_id)
+        })
+        .await
+    }
+
+    async fn login(&self, cx: RpcContext, email: String, password: String) -> RpcResult<String> {
+        serve_rpc(LABEL, "login", cx, async {
+            let email = normalize_email(&email);
+            log::info!("login({})", email);
+            let account = self.accounts.get_or_default(&email).await?;
+            let registered = !account.user_id.0.is_empty();
+            let hash = match registered {
+                true => account.password_hash.1.0,
+                false => self.dummy_hash.clone(),
+            };
+            let password = password.chars().take(MAX_PASSWORD_LEN).collect();
+            if !verify_password(password, hash).await? || !registered {
+                return Err(ErrorKind::InvalidArgument.error("Incorrect email or password."));
+            }
+            Ok(account.user_id.0)
+        })
+        .await
+    }
+
+    async fn get_profile(&self, cx: RpcContext, user_id: String) -> RpcResult<Profile> {
+        serve_rpc(LABEL, "get_profile", cx, async {
+            let profile = self.profiles.get_or_default(&user_id).await?;
+            if profile.email.0.is_empty() {
+                return Err(ErrorKind::NotFound.error(format!("no such user: {}", user_id)));
+            }
+            let mut addresses: Vec<_> = profile.addresses.1.iter().collect();
+            addresses.sort_by_key(|(_, x)| std::cmp::Reverse(x.last_used.0));
+            let mut payment_methods: Vec<_> = profile.payment_methods.1.iter().collect();
+            payment_methods.sort_by_key(|(_, x)| std::cmp::Reverse(x.last_used.0));
+            Ok(Profile {
+                addresses: addresses
+                    .into_iter()
+                    .map(|(id, x)| SavedAddress {
+                        id: id.clone(),
+                        address: x.to_address(),
+                    })
+                    .collect(),
+                payment_methods: payment_methods
+                    .into_iter()
+                    .map(|(token, x)| x.to_payment_method(token))
+                    .collect(),
+                user_id,
+                email: profile.email.0,
+            })
+        })
+        .await
+    }
+
+    async fn save_address(
+        &self,
+        cx: RpcContext,
+        user_id: String,
+        address: Address,
+    ) -> RpcResult<String> {
+        serve_rpc(LABEL, "save_address", cx, async {
+            log::info!("save_address({})", user_id);
+            let mut profile = self.profiles.get_or_default(&user_id).await?;
+            if profile.email.0.is_empty() {
+                return Err(ErrorKind::NotFound.error(format!("no such user: {}", user_id)));
+            }
+            let id = address_id(&address);
+            profile.addresses.1.insert(
+                id.clone(),
+                StoredAddress {
+                    street_address: Max(address.street_address),
+                    city: Max(address.city),
+                    state: Max(address.state),
+                    country: Max(address.country),
+                    zip_code: Max(address.zip_code),
+                    last_used: Max(now_secs()),
+                },
+            );
+            self.profiles.put(&user_id, profile).await?;
+            Ok(id)
+        })
+        .await
+    }
+
+    async fn save_payment_method(
+        &self,
+        cx: RpcContext,
+        user_id: String,
+        method: PaymentMethod,
+    ) -> RpcResult<()> {
+        serve_rpc(LABEL, "save_payment_method", cx, async {
+            log::info!("save_payment_method({}, {})", user_id, method.token);
+            let mut profile = self.profiles.get_or_default(&user_id).await?;
+            if profile.email.0.is_empty() {
+                return Err(ErrorKind::NotFound.error(format!("no such user: {}", user_id)));
+            }
+            // Every checkout tokenizes the card again, so an older token for the
+            // same card is replaced rather than listed twice.
+            let methods = &mut profile.payment_methods;
+            if methods.1.values().any(|x| x.is_same_card(&method)) {
+                methods.0 += 1;
+                methods.1.retain(|_, x| !x.is_same_card(&method));
+            }
+            methods.1.insert(
+                method.token,
+                StoredPaymentMethod {
+                    card_type: Max(method.card_type),
+                    last_four: Max(method.last_four),
+                    expiration_year: Max(method.expiration_year),
+                    expiration_month: Max(method.expiration_month),
+                    last_used: Max(now_secs()),
+                },
+            );
+            self.profiles.put(&user_id, profile).await?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn health(&self, cx: RpcContext) -> RpcResult<Health> {
+        serve_rpc(LABEL, "health", cx, async { Ok(Health::new(LABEL)) }).await
+    }
+}
+
+pub type UserClient = ops::Client<UserService>;
+
+pub fn component() -> ComponentConfig {
+    AccountData::bind("users");
+    ProfileData::bind("profiles");
+    ops::component::<UserService>(LABEL.to_owned())
+}
@@ -0,0 +1,551 @@
+//! Simulates shoppers against a running frontend, in the spirit of the Locust
+//! load generator that ships with upstream Online Boutique. Each simulated
+//! shopper keeps its own cookies and repeatedly picks a weighted journey
+//! (browsing the home page, viewing a product, adding to the cart, changing
+//! currency, checking out), pausing between journeys like a person would.
+//! At the end it prints latency percentiles and error rates per endpoint.
+//!
+//! Start the app with `cargo run -- --local`, then in another terminal:
+//!
+//! ```text
+//! cargo run --bin loadgen -- --users 20 --ramp-up 10s --duration 2m
+//! ```
+
+use std::{
+    collections::BTreeMap,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use rand::{Rng, seq::IndexedRandom};
+use reqwest::{Client, RequestBuilder, StatusCode, header, redirect};
+use serde_json::Value;
+
+const USAGE: &str =
+    "usage: loadgen [--url URL] [--users N] [--ramp-up DURATION] [--duration DURATION]
+
+  --url        frontend to drive (default http://localhost:8123)
+  --users      number of concurrent shoppers (default 10)
+  --ramp-up    time over which shoppers are started (default 10s)
+  --duration   how long to run, including ramp-up (default 60s)
+
+Durations are seconds, optionally suffixed with s, m or h.";
+
+/// How often progress is printed while running.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(10);
+
+const CSRF_COOKIE: &str = "BOUTIQUE_CSRF";
+
+/// Most of any one product a shopper adds at a time. Stock is never
+/// replenished, so big orders would sell the store out within minutes.
+const MAX_QUANTITY: u32 = 3;
+
+/// What the frontend answers when the cart or an order asks for more than is
+/// in stock. With no restocking, a long enough run sells out, so this is an
+/// expected outcome rather than an error.
+const SOLD_OUT: StatusCode = StatusCode::CONFLICT;
+
+/// The card and address upstream's load generator checks out with, less the
+/// card's expiry year, which has to stay in the future; see `checkout_form`.
+const CHECKOUT_FORM: [(&str, &str); 9] = [
+    ("email", "someone@example.com"),
+    ("street_address", "1600 Amphitheatre Parkway"),
+    ("zip_code", "94043"),
+    ("city", "Mountain View"),
+    ("state", "CA"),
+    ("country", "United States"),
+    ("credit_card_number", "4432-8015-6152-0454"),
+    ("credit_card_expiration_month", "1"),
+    ("credit_card_ccv", "672"),
+];
+
+/// `CHECKOUT_FORM` with a card that expires two years from now.
+fn checkout_form() -> Vec<(&'static str, String)> {
+    let year = time::OffsetDateTime::now_utc().year() + 2;
+    CHECKOUT_FORM
+        .iter()
+        .map(|(k, v)| (*k, v.to_string()))
+        .chain([("credit_card_expiration_year", year.to_string())])
+        .collect()
+}
+
+/// A response, read in full.
+struct Page {
+    status: StatusCode,
+    body: String,
+}
+
+struct Options {
+    url: String,
+    users: usize,
+    ramp_up: Duration,
+    duration: Duration,
+}
+
+impl Options {
+    fn from_args() -> Result<Options, String> {
+        let mut options = Options {
+            url: "http://localhost:8123".to_owned(),
+            users: 10,
+            ramp_up: Duration::from_secs(10),
+            duration: Duration::from_secs(60),
+        };
+        let mut args = std::env::args().skip(1);
+        while let Some(arg) = args.next() {
+            let (flag, value) = match arg.split_once('=') {
+                Some((flag, value)) => (flag.to_owned(), Some(value.to_owned())),
+                None => (arg, None),
+            };
+            if flag == "-h" || flag == "--help" {
+                return Err(String::new());
+            }
+            let value = value
+                .or_else(|| args.next())
+                .ok_or_else(|| format!("{} needs a value", flag))?;
+            match flag.as_str() {
+                "--url" => options.url = value.trim_end_matches('/').to_owned(),
+                "--users" => {
+                    options.users = value
+                        .parse()
+                        .ok()
+                        .filter(|x| *x > 0)
+                        .ok_or_else(|| format!("invalid --users: {}", value))?
+                }
+                "--ramp-up" => options.ramp_up = parse_duration(&value)?,
+                "--duration" => options.duration = parse_duration(&value)?,
+                _ => return Err(format!("unknown option: {}", flag)),
+            }
+        }
+        Ok(options)
+    }
+}
+
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let (number, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (s, "s"),
+    };
+    let scale = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        _ => return Err(format!("invalid duration: {}", s)),
+    };
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("invalid duration: {}", s))?;
+    Ok(Duration::from_secs(number * scale))
+}
+
+#[derive(Clone, Copy)]
+enum Journey {
+    BrowseHome,
+    ViewProduct,
+    AddToCart,
+    ChangeCurrency,
+    Checkout,
+}
+
+/// Relative weights, roughly those of upstream's load generator.
+const JOURNEYS: [(Journey, u32); 5] = [
+    (Journey::BrowseHome, 1),
+    (Journey::ViewProduct, 10),
+    (Journey::AddToCart, 2),
+    (Journey::ChangeCurrency, 2),
+    (Journey::Checkout, 1),
+];
+
+/// What shoppers pick from, fetched from the JSON API before starting.
+struct Catalog {
+    products: Vec<String>,
+    currencies: Vec<String>,
+}
+
+impl Catalog {
+    async fn fetch(client: &Client, url: &str) -> Result<Catalog, String> {
+        let products = get_json(client, &format!("{}/api/v1/products", url)).await?;
+        let currencies = get_json(client, &format!("{}/api/v1/currencies", url)).await?;
+        let products: Vec<String> = products
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|x| x["id"].as_str().map(str::to_owned))
+            .collect();
+        let currencies: Vec<String> = currencies
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|x| x.as_str().map(str::to_owned))
+            .collect();
+        if products.is_empty() || currencies.is_empty() {
+            return Err("the catalog or currency list is empty".to_owned());
+        }
+        Ok(Catalog {
+            products,
+            currencies,
+        })
+    }
+
+    fn product(&self) -> &str {
+        self.products.choose(&mut rand::rng()).unwrap()
+    }
+
+    fn currency(&self) -> &str {
+        self.currencies.choose(&mut rand::rng()).unwrap()
+    }
+}
+
+async fn get_json(client: &Client, url: &str) -> Result<Value, String> {
+    let res = client
+        .get(url)
+        .send()
+        .await
+        .map_err(|e| e.to_string())?
+        .error_for_status()
+        .map_err(|e| e.to_string())?;
+    let bytes = res.bytes().await.map_err(|e| e.to_string())?;
+    serde_json::from_slice(&bytes).map_err(|e| format!("{}: {}", url, e))
+}
+
+#[derive(Default)]
+struct Endpoint {
+    latencies: Vec<Duration>,
+    failures: u64,
+    errors: BTreeMap<String, u64>,
+}
+
+/// Results so far, by endpoint.
+#[derive(Default)]
+struct Stats {
+    endpoints: Mutex<BTreeMap<&'static str, Endpoint>>,
+}
+
+impl Stats {
+    fn record(&self, name: &'static str, elapsed: Duration, error: Option<&String>) {
+        let mut endpoints = self.endpoints.lock().unwrap();
+        let endpoint = endpoints.entry(name).or_default();
+        endpoint.latencies.push(elapsed);
+        if let Some(error) = error {
+            endpoint.failures += 1;
+            *endpoint.errors.entry(error.clone()).or_default() += 1;
+        }
+    }
+
+    fn totals(&self) -> (usize, u64) {
+        let endpoints = self.endpoints.lock().unwrap();
+        endpoints.values().fold((0, 0), |(reqs, fails), x| {
+            (reqs + x.latencies.len(), fails + x.failures)
+        })
+    }
+
+    fn report(&self, elapsed: Duration) {
+        let mut endpoints = self.endpoints.lock().unwrap();
+        let secs = elapsed.as_secs_f64().max(1.0);
+        println!(
+            "\n{:<22} {:>8} {:>8} {:>7} {:>7} {:>7} {:>7} {:>7} {:>7}",
+            "endpoint", "requests", "errors", "req/s", "p50", "p90", "p95", "p99", "max"
+        );
+        let mut all = Vec::new();
+        let mut failures = 0;
+        for (name, endpoint) in endpoints.iter_mut() {
+            endpoint.latencies.sort();
+            print_row(name, &endpoint.latencies, endpoint.failures, secs);
+            all.extend_from_slice(&endpoint.latencies);
+            failures += endpoint.failures;
+        }
+        all.sort();
+        print_row("total", &all, failures, secs);
+        println!("(latencies in ms)");
+
+        let errors: Vec<_> = endpoints
+            .iter()
+            .flat_map(|(name, x)| x.errors.iter().map(move |(e, n)| (n, name, e)))
+            .collect();
+        if !errors.is_empty() {
+            println!("\nerrors:");
+            for (n, name, e) in errors {
+                println!("  {:>6}  {}  {}", n, name, e);
+            }
+        }
+    }
+}
+
+fn print_row(name: &str, sorted: &[Duration], failures: u64, secs: f64) {
+    let percentile = |p: f64| -> String {
+        match sorted.len() {
+            0 => "-".to_owned(),
+            n => {
+                let i = ((n as f64 * p).ceil() as usize).clamp(1, n) - 1;
+                sorted[i].as_millis().to_string()
+            }
+        }
+    };
+    let error_rate = match sorted.len() {
+        0 => 0.0,
+        n => failures as f64 * 100.0 / n as f64,
+    };
+    println!(
+        "{:<22} {:>8} {:>7.1}% {:>7.1} {:>7} {:>7} {:>7} {:>7} {:>7}",
+        name,
+        sorted.len(),
+        error_rate,
+        sorted.len() as f64 / secs,
+        percentile(0.50),
+        percentile(0.90),
+        percentile(0.95),
+        percentile(0.99),
+        percentile(1.0),
+    );
+}
+
+/// One simulated shopper, with its own session and CSRF cookies.
+struct Shopper {
+    client: Client,
+    url: String,
+    catalog: Arc<Catalog>,
+    stats: Arc<Stats>,
+    cookies: BTreeMap<String, String>,
+}
+
+impl Shopper {
+    fn cookie_header(&self) -> String {
+        self.cookies
+            .iter()
+            .map(|(k, v)| format!("{}={}", k, v))
+            .collect::<Vec<_>>()
+            .join("; ")
+    }
+
+    fn store_cookies(&mut self, headers: &header::HeaderMap) {
+        for value in headers.get_all(header::SET_COOKIE) {
+            let Some(pair) = value.to_str().ok().and_then(|x| x.split(';').next()) else {
+                continue;
+            };
+            if let Some((name, value)) = pair.split_once('=') {
+                match value.trim() {
+                    "" => self.cookies.remove(name.trim()),
+                    value => self
+                        .cookies
+                        .insert(name.trim().to_owned(), value.to_owned()),
+                };
+            }
+        }
+    }
+
+    /// Sends a request and records how it went under `name`, returning the
+    /// response. Redirects count as success and aren't followed. If `expect`
+    /// is given, the response body has to contain it, since some failures
+    /// still render a page. Being refused for lack of stock counts as success
+    /// too.
+    async fn send(
+        &mut self,
+        name: &'static str,
+        req: RequestBuilder,
+        expect: Option<&str>,
+    ) -> Result<Page, String> {
+        let req = req.header(header::COOKIE, self.cookie_header());
+        let start = Instant::now();
+        let res = match req.send().await {
+            Ok(res) => {
+                self.store_cookies(res.headers());
+                let status = res.status();
+                match res.text().await {
+                    Ok(body) if status == SOLD_OUT => Ok(Page { status, body }),
+                    Ok(_) if !(status.is_success() || status.is_redirection()) => {
+                        Err(status.to_string())
+                    }
+                    Ok(body) => match expect {
+                        Some(x) if !body.contains(x) => Err(format!("response lacks {:?}", x)),
+                        _ => Ok(Page { status, body }),
+                    },
+                    Err(e) => Err(e.without_url().to_string()),
+                }
+            }
+            Err(e) => Err(e.without_url().to_string()),
+        };
+        self.stats.record(name, start.elapsed(), res.as_ref().err());
+        res
+    }
+
+    fn csrf_token(&self) -> String {
+        self.cookies.get(CSRF_COOKIE).cloned().unwrap_or_default()
+    }
+
+    async fn browse_home(&mut self) -> Result<(), String> {
+        let req = self.client.get(format!("{}/", self.url));
+        self.send("GET /", req, None).await?;
+        Ok(())
+    }
+
+    async fn view_product(&mut self) -> Result<(), String> {
+        let url = format!("{}/product/{}", self.url, self.catalog.product());
+        let req = self.client.get(url);
+        self.send("GET /product/{id}", req, None).await?;
+        Ok(())
+    }
+
+    /// Adds a few of a random product, unless its page says it is out of
+    /// stock, in which case the shopper moves on like a person would.
+    async fn add_to_cart(&mut self) -> Result<(), String> {
+        let product = self.catalog.product().to_owned();
+        let req = self.client.get(format!("{}/product/{}", self.url, product));
+        let page = self.send("GET /product/{id}", req, None).await?;
+        if page.body.contains("Out of stock") {
+            return Ok(());
+        }
+        let quantity = rand::rng().random_range(1..=MAX_QUANTITY).to_string();
+        let form = [
+            ("csrf_token", self.csrf_token()),
+            ("product_id", product),
+            ("quantity", quantity),
+        ];
+        let req = self.client.post(format!("{}/cart", self.url)).form(&form);
+        self.send("POST /cart", req, None).await?;
+        let req = self.client.get(format!("{}/cart", self.url));
+        self.send("GET /cart", req, None).await?;
+        Ok(())
+    }
+
+    async fn change_currency(&mut self) -> Result<(), String> {
+        self.browse_home().await?;
+        let form = [
+            ("csrf_token", self.csrf_token()),
+            ("currency_code", self.catalog.currency().to_owned()),
+        ];
+        let req = self
+            .client
+            .post(format!("{}/set_currency", self.url))
+            .form(&form);
+        self.send("POST /set_currency", req, None).await?;
+        Ok(())
+    }
+
+    async fn checkout(&mut self) -> Result<(), String> {
+        self.add_to_cart().await?;
+        let mut form = checkout_form();
+        form.push(("csrf_token", self.csrf_token()));
+        let req = self
+            .client
+            .post(format!("{}/cart/checkout", self.url))
+            .form(&form);
+        let page = self
+            .send("POST /cart/checkout", req, Some("Order Confirmation"))
+            .await?;
+        if page.status == SOLD_OUT {
+            // Start afresh, rather than trying the same cart forever.
+            let form = [("csrf_token", self.csrf_token())];
+            let req = self
+                .client
+                .post(format!("{}/cart/empty", self.url))
+                .form(&form);
+            self.send("POST /cart/empty", req, None).await?;
+        }
+        Ok(())
+    }
+
+    async fn run(mut self, deadline: Instant) {
+        while Instant::now() < deadline {
+            let (journey, _) = JOURNEYS.choose_weighted(&mut rand::rng(), |x| x.1).unwrap();
+            // A failed step has already been recorded, and the journey is
+            // abandoned like a shopper giving up on a broken page.
+            let _ = match journey {
+                Journey::BrowseHome => self.browse_home().await,
+                Journey::ViewProduct => self.view_product().await,
+                Journey::AddToCart => self.add_to_cart().await,
+                Journey::ChangeCurrency => self.change_currency().await,
+                Journey::Checkout => self.checkout().await,
+            };
+            let think = Duration::from_millis(rand::rng().random_range(1000..3000));
+            let left = deadline.saturating_duration_since(Instant::now());
+            tokio::time::sleep(think.min(left)).await;
+        }
+    }
+}
+
+async fn run(options: Options) -> Result<(), String> {
+    let client = Client::builder()
+        .redirect(redirect::Policy::none())
+        .timeout(Duration::from_secs(30))
+        .build()
+        .map_err(|e| e.to_string())?;
+    let catalog = Catalog::fetch(&client, &options.url)
+        .await
+        .map_err(|e| format!("cannot load the catalog from {}: {}", options.url, e))?;
+    let catalog = Arc::new(catalog);
+    let stats = Arc::new(Stats::default());
+
+    println!(
+        "driving {} with {} shoppers (ramp-up {}s, duration {}s)",
+        options.url,
+        options.users,
+        options.ramp_up.as_secs(),
+        options.duration.as_secs()
+    );
+    let start = Instant::now();
+    let deadline = start + options.duration;
+    let active = Arc::new(AtomicUsize::new(0));
+    let mut shoppers = tokio::task::JoinSet::new();
+    for i in 0..options.users {
+        let delay = options.ramp_up.mul_f64(i as f64 / options.users as f64);
+        let shopper = Shopper {
+            client: client.clone(),
+            url: options.url.clone(),
+            catalog: catalog.clone(),
+            stats: stats.clone(),
+            cookies: BTreeMap::new(),
+        };
+        let active = active.clone();
+        shoppers.spawn(async move {
+            tokio::time::sleep(delay).await;
+            active.fetch_add(1, Ordering::Relaxed);
+            shopper.run(deadline).await;
+        });
+    }
+
+    let progress = {
+        let stats = stats.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(PROGRESS_INTERVAL).await;
+                let (reqs, fails) = stats.totals();
+                println!(
+                    "[{:>4}s] {} shoppers, {} requests, {} errors",
+                    start.elapsed().as_secs(),
+                    active.load(Ordering::Relaxed),
+                    reqs,
+                    fails
+                );
+            }
+        })
+    };
+    shoppers.join_all().await;
+    progress.abort();
+
+    stats.report(start.elapsed());
+    Ok(())
+}
+
+fn main() {
+    let options = match Options::from_args() {
+        Ok(x) => x,
+        Err(e) if e.is_empty() => {
+            println!("{}", USAGE);
+            return;
+        }
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            std::process::exit(2);
+        }
+    };
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    if let Err(e) = runtime.block_on(run(options)) {
+        eprintln!("loadgen: {}", e);
+        std::process::exit(1);
+    }
+}
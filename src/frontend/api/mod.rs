@@ -0,0 +1,473 @@
+//! The JSON API under `/api/v1`. It uses the same session as the pages, so
+//! a browser client shares the cart of the signed-in user, and it speaks the
+//! `shared` types directly. Every route is declared together with its
+//! documentation; the OpenAPI document is served at `/api/v1/openapi.json`.
+
+use std::collections::BTreeMap;
+
+use amimono::rpc::RpcError;
+use axum::{
+    Json, Router,
+    extract::{FromRequest, Path, Query, Request},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+
+use crate::{
+    backend::cart::Cart,
+    shared::{
+        Address, CartItem, CreditCardInfo, ErrorKind, Money, OrderQuote, OrderResult, Product,
+        RpcContext,
+    },
+};
+
+use super::{
+    CHECKOUT, FrontendServerData, QUOTE, READ, UsedCard, WRITE, checkout_form, request, session,
+};
+
+mod openapi;
+
+use openapi::{ApiRouter, Operation};
+
+/// Currency used when a request doesn't name one.
+const DEFAULT_CURRENCY: &str = "USD";
+
+/// The body of every error response.
+#[derive(Serialize, JsonSchema)]
+pub struct ErrorBody {
+    error: ErrorDetail,
+}
+
+#[derive(Serialize, JsonSchema)]
+struct ErrorDetail {
+    /// One of `not_found`, `invalid_argument`, `out_of_stock`,
+    /// `payment_declined`, `unavailable` or `internal`.
+    code: &'static str,
+    message: String,
+    request_id: String,
+    /// For invalid checkout requests, what is wrong with each field.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fields: Option<BTreeMap<String, String>>,
+}
+
+pub struct ApiError {
+    kind: ErrorKind,
+    /// Shown to the client, so only backend messages written for users end
+    /// up here.
+    message: String,
+    fields: Option<BTreeMap<String, String>>,
+    /// Logged, never shown.
+    cause: Option<RpcError>,
+}
+
+type ApiResult<T> = Result<Json<T>, ApiError>;
+
+impl ApiError {
+    fn new<S: ToString>(kind: ErrorKind, message: S) -> ApiError {
+        ApiError {
+            kind,
+            message: message.to_string(),
+            fields: None,
+            cause: None,
+        }
+    }
+
+    fn invalid_fields(errors: checkout_form::CheckoutFormErrors) -> ApiError {
+        let fields = match serde_json::to_value(&errors) {
+            Ok(serde_json::Value::Object(x)) => x
+                .into_iter()
+                .filter_map(|(k, v)| Some((k, v.as_str()?.to_owned())))
+                .collect(),
+            _ => BTreeMap::new(),
+        };
+        ApiError {
+            fields: Some(fields),
+            ..ApiError::new(ErrorKind::InvalidArgument, "Some fields are invalid.")
+        }
+    }
+
+    fn status(&self) -> StatusCode {
+        match self.kind {
+            ErrorKind::NotFound => StatusCode::NOT_FOUND,
+            ErrorKind::InvalidArgument => StatusCode::BAD_REQUEST,
+            ErrorKind::OutOfStock => StatusCode::CONFLICT,
+            ErrorKind::PaymentDeclined => StatusCode::PAYMENT_REQUIRED,
+            ErrorKind::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+            ErrorKind::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl From<RpcError> for ApiError {
+    fn from(err: RpcError) -> Self {
+        let kind = ErrorKind::of(&err);
+        let message = match kind {
+            ErrorKind::NotFound
+            | ErrorKind::InvalidArgument
+            | ErrorKind::OutOfStock
+            | ErrorKind::PaymentDeclined => ErrorKind::message(&err),
+            ErrorKind::Unavailable => {
+                "Part of the store is temporarily unavailable. Please try again shortly.".to_owned()
+            }
+            ErrorKind::Internal => "Something went wrong on our end.".to_owned(),
+        };
+        ApiError {
+            cause: Some(err),
+            ..ApiError::new(kind, message)
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let status = self.status();
+        let request_id = request::request_id().unwrap_or_default();
+        if status.is_server_error() {
+            log::error!("API error: {:?}", self.cause);
+        } else {
+            log::info!("API error: {}", self.message);
+        }
+        let body = ErrorBody {
+            error: ErrorDetail {
+                code: self.kind.code(),
+                message: self.message,
+                request_id,
+                fields: self.fields,
+            },
+        };
+        (status, Json(body)).into_response()
+    }
+}
+
+/// `Json`, but rejecting bad bodies with the API's error format.
+struct ApiJson<T>(T);
+
+impl<S: Send + Sync, T: DeserializeOwned> FromRequest<S> for ApiJson<T> {
+    type Rejection = ApiError;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        match Json::<T>::from_request(req, state).await {
+            Ok(Json(x)) => Ok(ApiJson(x)),
+            Err(e) => Err(ApiError::new(ErrorKind::InvalidArgument, e.body_text())),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct SearchQuery {
+    #[serde(default)]
+    q: String,
+}
+
+#[derive(Deserialize, JsonSchema)]
+struct AddItemRequest {
+    product_id: String,
+    quantity: u32,
+}
+
+#[derive(Deserialize, JsonSchema)]
+struct UpdateItemRequest {
+    /// The new quantity. Zero removes the item.
+    quantity: u32,
+}
+
+#[derive(Deserialize, JsonSchema)]
+struct ConvertRequest {
+    from: Money,
+    /// Currency code to convert to.
+    to: String,
+}
+
+#[derive(Deserialize, JsonSchema)]
+struct QuoteRequest {
+    address: Address,
+    /// Currency to price the order in. Defaults to USD.
+    currency: Option<String>,
+    coupon_code: Option<String>,
+}
+
+#[derive(Deserialize, JsonSchema)]
+struct CheckoutRequest {
+    address: Address,
+    email: String,
+    credit_card: CreditCardInfo,
+    /// Currency to charge in. Defaults to USD.
+    currency: Option<String>,
+    coupon_code: Option<String>,
+}
+
+impl CheckoutRequest {
+    /// Checks the request the same way the checkout form is checked, so both
+    /// accept exactly the same orders.
+    fn validate(self) -> Result<checkout_form::CheckoutDetails, ApiError> {
+        let form = checkout_form::CheckoutForm {
+            street_address: self.address.street_address,
+            city: self.address.city,
+            state: self.address.state,
+            country: self.address.country,
+            zip_code: self.address.zip_code,
+            email: self.email,
+            credit_card_number: self.credit_card.credit_card_number,
+            credit_card_ccv: self.credit_card.credit_card_ccv,
+            credit_card_expiration_year: self.credit_card.credit_card_expiration_year.to_string(),
+            credit_card_expiration_month: self.credit_card.credit_card_expiration_month.to_string(),
+            coupon_code: self.coupon_code.unwrap_or_default(),
+        };
+        form.validate()
+            .map_err(|errors| ApiError::invalid_fields(*errors))
+    }
+}
+
+fn currency_or_default(currency: Option<String>) -> String {
+    currency.unwrap_or_else(|| DEFAULT_CURRENCY.to_owned())
+}
+
+pub(super) fn router(data: &FrontendServerData) -> Router {
+    ApiRouter::new()
+        .route(
+            Operation::get("/products", "List all products").returns::<Vec<Product>>(),
+            {
+                let data = data.clone();
+                async move || -> ApiResult<Vec<Product>> { Ok(Json(data.list_products().await?)) }
+            },
+        )
+        .route(
+            Operation::get("/products/{id}", "Get a product").returns::<Product>(),
+            {
+                let data = data.clone();
+                async move |Path(id): Path<String>| -> ApiResult<Product> {
+                    Ok(Json(data.get_product(id).await?))
+                }
+            },
+        )
+        .route(
+            Operation::get("/search", "Search products by name and description")
+                .query("q", "Text to search for")
+                .returns::<Vec<Product>>(),
+            {
+                let data = data.clone();
+                async move |Query(query): Query<SearchQuery>| -> ApiResult<Vec<Product>> {
+                    Ok(Json(
+                        data.productcatalog
+                            .call("search_products", READ, |c| {
+                                c.search_products(RpcContext::current(), query.q.clone())
+                            })
+                            .await?,
+                    ))
+                }
+            },
+        )
+        .route(
+            Operation::get("/cart", "Get the cart of the current session").returns::<Cart>(),
+            {
+                let data = data.clone();
+                async move || -> ApiResult<Cart> {
+                    Ok(Json(data.user_cart(session::user_id()).await?))
+                }
+            },
+        )
+        .route(
+            Operation::delete("/cart", "Empty the cart").returns::<Cart>(),
+            {
+                let data = data.clone();
+                async move || -> ApiResult<Cart> {
+                    let user_id = session::user_id();
+                    data.cart
+                        .call("empty_cart", WRITE, |c| {
+                            c.empty_cart(RpcContext::current(), user_id.clone())
+                        })
+                        .await?;
+                    Ok(Json(data.user_cart(user_id).await?))
+                }
+            },
+        )
+        .route(
+            Operation::post("/cart/items", "Add a quantity of a product to the cart")
+                .body::<AddItemRequest>()
+                .returns::<Cart>(),
+            {
+                let data = data.clone();
+                async move |ApiJson(req): ApiJson<AddItemRequest>| -> ApiResult<Cart> {
+                    if req.quantity == 0 {
+                        return Err(ApiError::new(
+                            ErrorKind::InvalidArgument,
+                            "quantity must be at least 1",
+                        ));
+                    }
+                    let user_id = session::user_id();
+                    let item = CartItem {
+                        product_id: req.product_id,
+                        quantity: req.quantity,
+                    };
+                    data.cart
+                        .call("add_item", WRITE, |c| {
+                            c.add_item(RpcContext::current(), user_id.clone(), item.clone())
+                        })
+                        .await?;
+                    Ok(Json(data.user_cart(user_id).await?))
+                }
+            },
+        )
+        .route(
+            Operation::put("/cart/items/{product_id}", "Set the quantity of a product")
+                .body::<UpdateItemRequest>()
+                .returns::<Cart>(),
+            {
+                let data = data.clone();
+                async move |Path(product_id): Path<String>,
+                            ApiJson(req): ApiJson<UpdateItemRequest>|
+                            -> ApiResult<Cart> {
+                    let user_id = session::user_id();
+                    let item = CartItem {
+                        product_id,
+                        quantity: req.quantity,
+                    };
+                    data.cart
+                        .call("update_item", WRITE, |c| {
+                            c.update_item(RpcContext::current(), user_id.clone(), item.clone())
+                        })
+                        .await?;
+                    Ok(Json(data.user_cart(user_id).await?))
+                }
+            },
+        )
+        .route(
+            Operation::delete("/cart/items/{product_id}", "Remove a product from the cart")
+                .returns::<Cart>(),
+            {
+                let data = data.clone();
+                async move |Path(product_id): Path<String>| -> ApiResult<Cart> {
+                    let user_id = session::user_id();
+                    let item = CartItem {
+                        product_id,
+                        quantity: 0,
+                    };
+                    data.cart
+                        .call("update_item", WRITE, |c| {
+                            c.update_item(RpcContext::current(), user_id.clone(), item.clone())
+                        })
+                        .await?;
+                    Ok(Json(data.user_cart(user_id).await?))
+                }
+            },
+        )
+        .route(
+            Operation::get("/currencies", "List supported currency codes").returns::<Vec<String>>(),
+            {
+                let data = data.clone();
+                async move || -> ApiResult<Vec<String>> {
+                    Ok(Json(data.supported_currencies().await?))
+                }
+            },
+        )
+        .route(
+            Operation::post(
+                "/currencies/convert",
+                "Convert an amount to another currency",
+            )
+            .body::<ConvertRequest>()
+            .returns::<Money>(),
+            {
+                let data = data.clone();
+                async move |ApiJson(req): ApiJson<ConvertRequest>| -> ApiResult<Money> {
+                    Ok(Json(data.convert(req.from, req.to).await?))
+                }
+            },
+        )
+        .route(
+            Operation::post("/checkout/quote", "Price the cart without placing an order")
+                .body::<QuoteRequest>()
+                .returns::<OrderQuote>(),
+            {
+                let data = data.clone();
+                async move |ApiJson(req): ApiJson<QuoteRequest>| -> ApiResult<OrderQuote> {
+                    let quote = data
+                        .checkout
+                        .call("quote_order", QUOTE, |c| {
+                            c.quote_order(
+                                RpcContext::current(),
+                                session::user_id(),
+                                currency_or_default(req.currency.clone()),
+                                req.address.clone(),
+                                req.coupon_code.clone(),
+                            )
+                        })
+                        .await?;
+                    Ok(Json(quote))
+                }
+            },
+        )
+        .route(
+            Operation::post("/checkout", "Place an order for the contents of the cart")
+                .body::<CheckoutRequest>()
+                .returns::<OrderResult>()
+                .status(StatusCode::CREATED),
+            {
+                let data = data.clone();
+                async move |ApiJson(req): ApiJson<CheckoutRequest>| -> Result<Response, ApiError> {
+                    let user_id = session::user_id();
+                    let user_currency = currency_or_default(req.currency.clone());
+                    let details = req.validate()?;
+                    let order = data
+                        .checkout
+                        .call("checkout", CHECKOUT, |c| {
+                            c.checkout(
+                                RpcContext::current(),
+                                user_id.clone(),
+                                user_currency.clone(),
+                                details.address.clone(),
+                                details.email.clone(),
+                                details.credit_card.clone(),
+                                details.coupon_code.clone(),
+                            )
+                        })
+                        .await?;
+                    data.remember_checkout(
+                        user_id,
+                        &order,
+                        Some(UsedCard::Entered(details.credit_card)),
+                    )
+                    .await;
+                    Ok((StatusCode::CREATED, Json(order)).into_response())
+                }
+            },
+        )
+        .route(
+            Operation::get(
+                "/orders",
+                "List orders placed in this session, newest first",
+            )
+            .returns::<Vec<OrderResult>>(),
+            {
+                let data = data.clone();
+                async move || -> ApiResult<Vec<OrderResult>> {
+                    Ok(Json(
+                        data.checkout
+                            .call("list_orders", READ, |c| {
+                                c.list_orders(RpcContext::current(), session::user_id())
+                            })
+                            .await?,
+                    ))
+                }
+            },
+        )
+        .route(
+            Operation::get("/orders/{order_id}", "Get an order").returns::<OrderResult>(),
+            {
+                let data = data.clone();
+                async move |Path(order_id): Path<String>| -> ApiResult<OrderResult> {
+                    let order = data
+                        .checkout
+                        .call("get_order", READ, |c| {
+                            c.get_order(RpcContext::current(), session::user_id(), order_id.clone())
+                        })
+                        .await?;
+                    Ok(Json(order))
+                }
+            },
+        )
+        .finish("Online Boutique", format!("{}/api/v1", data.base_url))
+        .fallback(async || ApiError::new(ErrorKind::NotFound, "no such endpoint"))
+}
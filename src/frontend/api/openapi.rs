@@ -0,0 +1,203 @@
+//! Builds the OpenAPI document from the same definitions that build the
+//! router, so the document can't drift from the routes it describes.
+
+use std::sync::Arc;
+
+use axum::{
+    Json, Router,
+    handler::Handler,
+    http::StatusCode,
+    routing::{MethodFilter, get, on},
+};
+use schemars::{JsonSchema, Schema, SchemaGenerator, generate::SchemaSettings};
+use serde_json::{Map, Value, json};
+
+use super::ErrorBody;
+
+type SchemaFn = fn(&mut SchemaGenerator) -> Schema;
+
+/// The documentation half of a route: everything but the handler.
+pub struct Operation {
+    method: MethodFilter,
+    method_name: &'static str,
+    path: &'static str,
+    summary: &'static str,
+    query: Vec<(&'static str, &'static str)>,
+    body: Option<SchemaFn>,
+    response: Option<SchemaFn>,
+    status: StatusCode,
+}
+
+impl Operation {
+    fn new(
+        method: MethodFilter,
+        method_name: &'static str,
+        path: &'static str,
+        summary: &'static str,
+    ) -> Operation {
+        Operation {
+            method,
+            method_name,
+            path,
+            summary,
+            query: Vec::new(),
+            body: None,
+            response: None,
+            status: StatusCode::OK,
+        }
+    }
+
+    pub fn get(path: &'static str, summary: &'static str) -> Operation {
+        Operation::new(MethodFilter::GET, "get", path, summary)
+    }
+
+    pub fn post(path: &'static str, summary: &'static str) -> Operation {
+        Operation::new(MethodFilter::POST, "post", path, summary)
+    }
+
+    pub fn put(path: &'static str, summary: &'static str) -> Operation {
+        Operation::new(MethodFilter::PUT, "put", path, summary)
+    }
+
+    pub fn delete(path: &'static str, summary: &'static str) -> Operation {
+        Operation::new(MethodFilter::DELETE, "delete", path, summary)
+    }
+
+    /// Documents an optional query parameter. Path parameters are taken from
+    /// the path itself.
+    pub fn query(mut self, name: &'static str, description: &'static str) -> Operation {
+        self.query.push((name, description));
+        self
+    }
+
+    /// Documents a JSON request body.
+    pub fn body<T: JsonSchema>(mut self) -> Operation {
+        self.body = Some(SchemaGenerator::subschema_for::<T>);
+        self
+    }
+
+    /// Documents the JSON response on success.
+    pub fn returns<T: JsonSchema>(mut self) -> Operation {
+        self.response = Some(SchemaGenerator::subschema_for::<T>);
+        self
+    }
+
+    /// Sets the status returned on success, if not 200.
+    pub fn status(mut self, status: StatusCode) -> Operation {
+        self.status = status;
+        self
+    }
+
+    fn path_params(&self) -> impl Iterator<Item = &'static str> {
+        self.path
+            .split('/')
+            .filter_map(|x| x.strip_prefix('{')?.strip_suffix('}'))
+    }
+
+    fn to_json(&self, generator: &mut SchemaGenerator) -> Value {
+        let mut parameters: Vec<Value> = self
+            .path_params()
+            .map(|name| {
+                json!({
+                    "name": name,
+                    "in": "path",
+                    "required": true,
+                    "schema": { "type": "string" },
+                })
+            })
+            .collect();
+        parameters.extend(self.query.iter().map(|(name, description)| {
+            json!({
+                "name": name,
+                "in": "query",
+                "required": false,
+                "description": description,
+                "schema": { "type": "string" },
+            })
+        }));
+
+        let mut success = json!({
+            "description": self.status.canonical_reason().unwrap_or("Success"),
+        });
+        if let Some(response) = self.response {
+            success["content"] = json!({ "application/json": { "schema": response(generator) } });
+        }
+        let error = json!({
+            "description": "Error",
+            "content": {
+                "application/json": { "schema": generator.subschema_for::<ErrorBody>() },
+            },
+        });
+
+        let mut op = json!({
+            "summary": self.summary,
+            "parameters": parameters,
+            "responses": {
+                self.status.as_str(): success,
+                "default": error,
+            },
+        });
+        if let Some(body) = self.body {
+            op["requestBody"] = json!({
+                "required": true,
+                "content": { "application/json": { "schema": body(generator) } },
+            });
+        }
+        op
+    }
+}
+
+/// A router that documents each route as it is added.
+pub struct ApiRouter {
+    router: Router,
+    generator: SchemaGenerator,
+    paths: Map<String, Value>,
+}
+
+impl ApiRouter {
+    pub fn new() -> ApiRouter {
+        let generator = SchemaSettings::draft2020_12()
+            .with(|s| s.definitions_path = "#/components/schemas/".into())
+            .into_generator();
+        ApiRouter {
+            router: Router::new(),
+            generator,
+            paths: Map::new(),
+        }
+    }
+
+    pub fn route<H, T>(mut self, op: Operation, handler: H) -> ApiRouter
+    where
+        H: Handler<T, ()>,
+        T: 'static,
+    {
+        let spec = op.to_json(&mut self.generator);
+        self.paths
+            .entry(op.path)
+            .or_insert_with(|| json!({}))
+            .as_object_mut()
+            .unwrap()
+            .insert(op.method_name.to_owned(), spec);
+        self.router = self.router.route(op.path, on(op.method, handler));
+        self
+    }
+
+    /// Returns the finished router, with the document served at
+    /// `/openapi.json`. `server_url` is where the API is mounted.
+    pub fn finish(mut self, title: &str, server_url: String) -> Router {
+        let doc = Arc::new(json!({
+            "openapi": "3.1.0",
+            "info": {
+                "title": title,
+                "version": env!("APP_REVISION"),
+            },
+            "servers": [{ "url": server_url }],
+            "paths": self.paths,
+            "components": {
+                "schemas": self.generator.take_definitions(true),
+            },
+        }));
+        self.router
+            .route("/openapi.json", get(async move || Json(Value::clone(&doc))))
+    }
+}
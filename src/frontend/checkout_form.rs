@@ -0,0 +1,186 @@
+//! The checkout form, and turning what was typed into it into an order.
+
+use serde::{Deserialize, Serialize};
+
+use crate::shared::{
+    Address, CreditCardInfo, current_year_month, is_valid_card_number, is_valid_country,
+    is_valid_email, is_valid_postal_code, parse_expiration,
+};
+
+/// The checkout form as submitted. Every field is kept as the raw string the
+/// user entered so that a rejected form can be re-rendered as-is.
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CheckoutForm {
+    pub street_address: String,
+    pub city: String,
+    pub state: String,
+    pub country: String,
+    pub zip_code: String,
+    pub email: String,
+    pub credit_card_number: String,
+    pub credit_card_ccv: String,
+    pub credit_card_expiration_year: String,
+    pub credit_card_expiration_month: String,
+    pub coupon_code: String,
+}
+
+/// Checkout with an address and card saved in the user's profile.
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SavedCheckoutForm {
+    pub address_id: String,
+    pub payment_token: String,
+    pub coupon_code: String,
+}
+
+#[derive(Default, Serialize)]
+pub struct CheckoutFormErrors {
+    pub street_address: Option<&'static str>,
+    pub city: Option<&'static str>,
+    pub state: Option<&'static str>,
+    pub country: Option<&'static str>,
+    pub zip_code: Option<&'static str>,
+    pub email: Option<&'static str>,
+    pub credit_card_number: Option<&'static str>,
+    pub credit_card_ccv: Option<&'static str>,
+    pub credit_card_expiration: Option<&'static str>,
+}
+
+pub struct CheckoutDetails {
+    pub address: Address,
+    pub email: String,
+    pub credit_card: CreditCardInfo,
+    pub coupon_code: Option<String>,
+}
+
+/// Everything checkout needs apart from the card.
+pub struct ShippingDetails {
+    pub address: Address,
+    pub email: String,
+    pub coupon_code: Option<String>,
+}
+
+impl CheckoutForm {
+    pub fn validate(&self) -> Result<CheckoutDetails, Box<CheckoutFormErrors>> {
+        let mut errors = CheckoutFormErrors::default();
+        let shipping = self.check_shipping(&mut errors);
+        let credit_card = self.check_card(&mut errors);
+        match credit_card {
+            Some(credit_card) if errors.is_empty() => Ok(CheckoutDetails {
+                address: shipping.address,
+                email: shipping.email,
+                credit_card,
+                coupon_code: shipping.coupon_code,
+            }),
+            _ => Err(Box::new(errors)),
+        }
+    }
+
+    /// Validates all but the card fields, for when the card has already
+    /// been entered and tokenized.
+    pub fn validate_shipping(&self) -> Result<ShippingDetails, Box<CheckoutFormErrors>> {
+        let mut errors = CheckoutFormErrors::default();
+        let shipping = self.check_shipping(&mut errors);
+        match errors.is_empty() {
+            true => Ok(shipping),
+            false => Err(Box::new(errors)),
+        }
+    }
+
+    /// Drops what was typed into the card fields, so a re-rendered form
+    /// never echoes the card back.
+    pub fn without_card(self) -> CheckoutForm {
+        CheckoutForm {
+            credit_card_number: String::new(),
+            credit_card_ccv: String::new(),
+            ..self
+        }
+    }
+
+    fn check_shipping(&self, errors: &mut CheckoutFormErrors) -> ShippingDetails {
+        let street_address = self.street_address.trim();
+        if street_address.is_empty() {
+            errors.street_address = Some("Enter a street address.");
+        }
+        let city = self.city.trim();
+        if city.is_empty() {
+            errors.city = Some("Enter a city.");
+        }
+        let state = self.state.trim();
+        if state.len() > 64 {
+            errors.state = Some("State is too long.");
+        }
+        let country = self.country.trim();
+        if !is_valid_country(country) {
+            errors.country = Some("Enter a country name or two-letter country code.");
+        }
+        let zip_code = self.zip_code.trim().to_uppercase();
+        if !is_valid_postal_code(&zip_code) {
+            errors.zip_code = Some("Enter a valid postal code.");
+        }
+        let email = self.email.trim();
+        if !is_valid_email(email) {
+            errors.email = Some("Enter a valid email address.");
+        }
+        ShippingDetails {
+            address: Address {
+                street_address: street_address.to_owned(),
+                city: city.to_owned(),
+                state: state.to_owned(),
+                country: country.to_owned(),
+                zip_code,
+            },
+            email: email.to_owned(),
+            coupon_code: Some(self.coupon_code.trim().to_owned()).filter(|x| !x.is_empty()),
+        }
+    }
+
+    fn check_card(&self, errors: &mut CheckoutFormErrors) -> Option<CreditCardInfo> {
+        let card_number: String = self
+            .credit_card_number
+            .chars()
+            .filter(|c| *c != ' ' && *c != '-')
+            .collect();
+        if !is_valid_card_number(&card_number) {
+            errors.credit_card_number = Some("Enter a valid card number.");
+        }
+        let ccv = self.credit_card_ccv.trim();
+        if !(3..=4).contains(&ccv.len()) || !ccv.chars().all(|c| c.is_ascii_digit()) {
+            errors.credit_card_ccv = Some("Enter the 3 or 4 digit security code.");
+        }
+        let expiration = parse_expiration(
+            self.credit_card_expiration_year.trim(),
+            self.credit_card_expiration_month.trim(),
+            current_year_month(),
+        );
+        if expiration.is_none() {
+            errors.credit_card_expiration = Some("Enter a valid expiration date in the future.");
+        }
+        let (year, month) = expiration?;
+        Some(CreditCardInfo {
+            credit_card_number: card_number,
+            credit_card_ccv: ccv.to_owned(),
+            credit_card_expiration_year: year,
+            credit_card_expiration_month: month,
+        })
+    }
+}
+
+impl CheckoutFormErrors {
+    fn is_empty(&self) -> bool {
+        [
+            self.street_address,
+            self.city,
+            self.state,
+            self.country,
+            self.zip_code,
+            self.email,
+            self.credit_card_number,
+            self.credit_card_ccv,
+            self.credit_card_expiration,
+        ]
+        .iter()
+        .all(Option::is_none)
+    }
+}
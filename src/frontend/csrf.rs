@@ -0,0 +1,144 @@
+//! CSRF protection using the double-submit cookie pattern. Every visitor gets
+//! a random token in a cookie, and every state-changing request must echo it
+//! back in a `csrf_token` form field or an `x-csrf-token` header. Another
+//! site can make the browser send the cookie, but cannot read it to fill in
+//! the form.
+
+use axum::{
+    body::{Body, to_bytes},
+    extract::Request,
+    http::{HeaderName, HeaderValue, Method, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use axum_extra::extract::cookie::{Cookie, SameSite};
+
+use super::error::FrontendError;
+
+const COOKIE_NAME: &str = "BOUTIQUE_CSRF";
+const FORM_FIELD: &str = "csrf_token";
+const HEADER_NAME: HeaderName = HeaderName::from_static("x-csrf-token");
+
+/// Forms in this app are small, so anything bigger is rejected outright
+/// rather than buffered.
+const MAX_FORM_BYTES: usize = 64 * 1024;
+
+tokio::task_local! {
+    static TOKEN: String;
+}
+
+/// Returns the CSRF token for the current request, to be rendered into forms.
+pub fn token() -> String {
+    TOKEN.try_with(|t| t.clone()).unwrap_or_default()
+}
+
+fn new_token() -> String {
+    rand::random::<[u8; 32]>()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+fn cookie_token(req: &Request) -> Option<String> {
+    req.headers()
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|x| x.to_str().ok())
+        .flat_map(Cookie::split_parse)
+        .filter_map(Result::ok)
+        .find(|c| c.name() == COOKIE_NAME)
+        .map(|c| c.value().to_owned())
+        .filter(|x| !x.is_empty())
+}
+
+fn is_form(req: &Request) -> bool {
+    req.headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|x| x.to_str().ok())
+        .is_some_and(|x| x.starts_with("application/x-www-form-urlencoded"))
+}
+
+/// Whether a browser would send a CORS preflight before letting another site
+/// make this request. This server never answers one, so such requests can
+/// only come from our own pages or from non-browser clients, and the JSON API
+/// doesn't need a token.
+fn needs_preflight(req: &Request) -> bool {
+    let json = req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.split(';').next())
+        .is_some_and(|x| x.trim().eq_ignore_ascii_case("application/json"));
+    json || !matches!(*req.method(), Method::GET | Method::HEAD | Method::POST)
+}
+
+/// Compares in constant time so the token can't be guessed byte by byte.
+fn tokens_match(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |acc, (x, y)| acc | (x ^ y))
+            == 0
+}
+
+/// Finds the submitted token, buffering the body if it has to be read from
+/// the form. The returned request carries the same body.
+async fn submitted_token(req: Request) -> Result<(Request, Option<String>), FrontendError> {
+    if let Some(x) = req.headers().get(&HEADER_NAME) {
+        let token = x.to_str().ok().map(|x| x.to_owned());
+        return Ok((req, token));
+    }
+    if !is_form(&req) {
+        return Ok((req, None));
+    }
+    let (parts, body) = req.into_parts();
+    let bytes = to_bytes(body, MAX_FORM_BYTES)
+        .await
+        .map_err(|_| FrontendError::Forbidden)?;
+    let token = form_urlencoded::parse(&bytes)
+        .find(|(k, _)| k == FORM_FIELD)
+        .map(|(_, v)| v.into_owned());
+    Ok((Request::from_parts(parts, Body::from(bytes)), token))
+}
+
+pub async fn protect(req: Request, next: Next) -> Response {
+    let existing = cookie_token(&req);
+    let token = existing.clone().unwrap_or_else(new_token);
+
+    let safe = matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS)
+        || needs_preflight(&req);
+    let req = if safe {
+        req
+    } else {
+        let (req, submitted) = match submitted_token(req).await {
+            Ok(x) => x,
+            Err(e) => return e.into_response(),
+        };
+        let ok = match (&existing, &submitted) {
+            (Some(x), Some(y)) => tokens_match(x, y),
+            _ => false,
+        };
+        if !ok {
+            log::warn!(
+                "rejecting {} {}: CSRF token mismatch",
+                req.method(),
+                req.uri()
+            );
+            return FrontendError::Forbidden.into_response();
+        }
+        req
+    };
+
+    let mut res = TOKEN.scope(token.clone(), next.run(req)).await;
+    if existing.is_none() {
+        let cookie = Cookie::build((COOKIE_NAME, token))
+            .path("/")
+            .http_only(true)
+            .same_site(SameSite::Strict)
+            .build();
+        if let Ok(value) = HeaderValue::from_str(&cookie.to_string()) {
+            res.headers_mut().append(header::SET_COOKIE, value);
+        }
+    }
+    res
+}
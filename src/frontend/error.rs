@@ -0,0 +1,165 @@
+use std::fmt;
+
+use amimono::rpc::RpcError;
+use axum::{
+    http::StatusCode,
+    response::{Html, IntoResponse, Response},
+};
+use serde::Serialize;
+
+use crate::{
+    frontend::{csrf, request, session, templates},
+    shared::ErrorKind,
+};
+
+#[derive(Debug)]
+pub enum FrontendError {
+    Rpc(RpcError),
+    Template(tinytemplate::error::Error),
+    NotFound,
+    Forbidden,
+}
+
+impl From<RpcError> for FrontendError {
+    fn from(err: RpcError) -> Self {
+        FrontendError::Rpc(err)
+    }
+}
+impl From<tinytemplate::error::Error> for FrontendError {
+    fn from(err: tinytemplate::error::Error) -> Self {
+        FrontendError::Template(err)
+    }
+}
+
+impl fmt::Display for FrontendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrontendError::Rpc(e) => write!(f, "RPC error: {:?}", e),
+            FrontendError::Template(e) => write!(f, "Template error: {}", e),
+            FrontendError::NotFound => write!(f, "Not found"),
+            FrontendError::Forbidden => write!(f, "Forbidden"),
+        }
+    }
+}
+
+/// What the user is shown for an error. Only the message of bad input, stock
+/// and declined payment errors comes from the backend, since those are
+/// written for the user; everything else gets a fixed message.
+struct ErrorPage {
+    status: StatusCode,
+    title: &'static str,
+    message: String,
+}
+
+impl FrontendError {
+    fn page(&self) -> ErrorPage {
+        let kind = match self {
+            FrontendError::Rpc(e) => ErrorKind::of(e),
+            FrontendError::Template(_) => ErrorKind::Internal,
+            FrontendError::NotFound => ErrorKind::NotFound,
+            FrontendError::Forbidden => {
+                return ErrorPage {
+                    status: StatusCode::FORBIDDEN,
+                    title: "Forbidden",
+                    message: "This form has expired or was submitted from another site. \
+                              Go back, reload the page and try again."
+                        .to_owned(),
+                };
+            }
+        };
+        let (status, title, message) = match kind {
+            ErrorKind::NotFound => (
+                StatusCode::NOT_FOUND,
+                "Not Found",
+                "The page you were looking for could not be found.".to_owned(),
+            ),
+            ErrorKind::InvalidArgument => (
+                StatusCode::BAD_REQUEST,
+                "Bad Request",
+                self.backend_message(),
+            ),
+            ErrorKind::OutOfStock => (StatusCode::CONFLICT, "Out of Stock", self.backend_message()),
+            ErrorKind::PaymentDeclined => (
+                StatusCode::PAYMENT_REQUIRED,
+                "Payment Declined",
+                self.backend_message(),
+            ),
+            ErrorKind::Unavailable => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "Service Unavailable",
+                "Part of the store is temporarily unavailable. Please try again shortly."
+                    .to_owned(),
+            ),
+            ErrorKind::Internal => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Internal Error",
+                "Something went wrong on our end.".to_owned(),
+            ),
+        };
+        ErrorPage {
+            status,
+            title,
+            message,
+        }
+    }
+
+    fn backend_message(&self) -> String {
+        match self {
+            FrontendError::Rpc(e) => ErrorKind::message(e),
+            _ => String::new(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorContext<'a> {
+    header: templates::HeaderContext<'a>,
+    footer: templates::FooterContext<'a>,
+    base_url: &'a str,
+    title: &'static str,
+    message: String,
+    request_id: String,
+}
+
+impl IntoResponse for FrontendError {
+    fn into_response(self) -> Response {
+        let page = self.page();
+        let request_id = request::request_id().unwrap_or_default();
+        if page.status.is_server_error() {
+            log::error!("{}", self);
+        } else {
+            log::info!("{}", self);
+        }
+
+        let base_url = request::base_url();
+        let ctx = ErrorContext {
+            header: templates::HeaderContext {
+                base_url: base_url.as_str(),
+                csrf_token: csrf::token(),
+                logged_in: session::is_logged_in(),
+                notices: request::notices(),
+            },
+            footer: templates::FooterContext {
+                base_url: base_url.as_str(),
+            },
+            base_url: base_url.as_str(),
+            title: page.title,
+            message: page.message,
+            request_id: request_id.clone(),
+        };
+        let rendered = match request::templates() {
+            Some(templates) => templates.render("error", &ctx),
+            None => Err(tinytemplate::error::Error::GenericError {
+                msg: "no templates outside of a request".to_owned(),
+            }),
+        };
+        match rendered {
+            Ok(html) => (page.status, Html(html)).into_response(),
+            Err(e) => {
+                log::error!("failed to render error page: {}", e);
+                let body = format!("{}\n\nRequest ID: {}", page.title, request_id);
+                (page.status, body).into_response()
+            }
+        }
+    }
+}
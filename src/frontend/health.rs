@@ -0,0 +1,156 @@
+//! Endpoints for probes. They sit outside the session and CSRF middleware,
+//! so probing doesn't hand out cookies.
+//!
+//! * `/healthz` answers as long as the frontend is serving at all.
+//! * `/readyz` also checks that every backend service answers its health
+//!   RPC, and fails with 503 if any doesn't.
+//! * `/version` reports the revision computed by `build.rs`.
+//!
+//! Metrics are not served here, as this port is public. See
+//! `metrics::serve_from_env`.
+
+use std::{collections::BTreeMap, time::Duration};
+
+use amimono::rpc::RpcResult;
+use axum::{Json, Router, http::StatusCode, routing::get};
+use serde::Serialize;
+use tokio::time::Instant;
+
+use super::FrontendServerData;
+use crate::shared::{ErrorKind, Health, RpcContext};
+
+/// A service slower than this to answer a probe counts as unreachable.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Serialize)]
+struct Version {
+    revision: &'static str,
+}
+
+#[derive(Serialize)]
+struct Check {
+    ok: bool,
+    latency_ms: u128,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    revision: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Readiness {
+    ready: bool,
+    revision: &'static str,
+    services: BTreeMap<&'static str, Check>,
+}
+
+async fn probe(
+    name: &'static str,
+    fut: impl Future<Output = RpcResult<Health>>,
+) -> (&'static str, Check) {
+    let start = Instant::now();
+    let res = match tokio::time::timeout(PROBE_TIMEOUT, fut).await {
+        Ok(res) => res,
+        Err(_) => Err(ErrorKind::Unavailable.error("timed out")),
+    };
+    let latency_ms = start.elapsed().as_millis();
+    let check = match res {
+        Ok(health) => Check {
+            ok: true,
+            latency_ms,
+            revision: Some(health.revision),
+            error: None,
+        },
+        Err(e) => {
+            log::warn!("readiness probe of {} failed: {:?}", name, e);
+            Check {
+                ok: false,
+                latency_ms,
+                revision: None,
+                error: Some(ErrorKind::message(&e)),
+            }
+        }
+    };
+    (name, check)
+}
+
+impl FrontendServerData {
+    async fn readiness(&self) -> Readiness {
+        let checks = futures::join!(
+            probe("adservice", self.ad.inner().health(RpcContext::current())),
+            probe(
+                "cartservice",
+                self.cart.inner().health(RpcContext::current())
+            ),
+            probe(
+                "checkoutservice",
+                self.checkout.inner().health(RpcContext::current())
+            ),
+            probe(
+                "currencyservice",
+                self.currency.inner().health(RpcContext::current())
+            ),
+            probe(
+                "inventoryservice",
+                self.inventory.inner().health(RpcContext::current())
+            ),
+            probe(
+                "paymentservice",
+                self.payment.inner().health(RpcContext::current())
+            ),
+            probe(
+                "productcatalogservice",
+                self.productcatalog.inner().health(RpcContext::current())
+            ),
+            probe(
+                "shippingservice",
+                self.shipping.inner().health(RpcContext::current())
+            ),
+            probe(
+                "recommendationservice",
+                self.recommendation.inner().health(RpcContext::current())
+            ),
+            probe(
+                "userservice",
+                self.user.inner().health(RpcContext::current())
+            ),
+        );
+        let services: BTreeMap<_, _> = [
+            checks.0, checks.1, checks.2, checks.3, checks.4, checks.5, checks.6, checks.7,
+            checks.8, checks.9,
+        ]
+        .into_iter()
+        .collect();
+        Readiness {
+            ready: services.values().all(|x| x.ok),
+            revision: env!("APP_REVISION"),
+            services,
+        }
+    }
+}
+
+pub fn router(data: &FrontendServerData) -> Router {
+    Router::new()
+        .route("/healthz", get(async || "ok"))
+        .route("/readyz", {
+            get({
+                let data = data.clone();
+                async move || {
+                    let readiness = data.readiness().await;
+                    let status = match readiness.ready {
+                        true => StatusCode::OK,
+                        false => StatusCode::SERVICE_UNAVAILABLE,
+                    };
+                    (status, Json(readiness))
+                }
+            })
+        })
+        .route(
+            "/version",
+            get(async || {
+                Json(Version {
+                    revision: env!("APP_REVISION"),
+                })
+            }),
+        )
+}
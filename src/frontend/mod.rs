@@ -1,68 +1,81 @@
-use std::{fmt, net::SocketAddr, time::Instant};
+use std::{
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
+use amimono::rpc::RpcResult;
 use amimono::{
     config::{Binding, ComponentConfig},
-    rpc::RpcError,
     runtime::{self, Component},
 };
 use axum::{
     Form, Router,
-    extract::Path,
-    response::{Html, IntoResponse, Redirect},
+    extract::{MatchedPath, Path},
+    response::{Html, IntoResponse, Redirect, Response},
     routing::{get, post},
 };
-use axum_extra::extract::{CookieJar, cookie::Cookie};
 
 use crate::backend::{
-    AdClient, CartClient, CheckoutClient, CurrencyClient, ProductCatalogClient,
-    RecommendationClient, ShippingClient,
+    self, AdClient, CartClient, CheckoutClient, CurrencyClient, InventoryClient, PaymentClient,
+    ProductCatalogClient, RecommendationClient, ShippingClient, UserClient, cart::Cart, checkout,
+};
+use crate::shared::{
+    Ad, CartItem, CreditCardInfo, ErrorKind, Money, OrderResult, PaymentMethod, Product,
+    RpcContext,
+    cache::{Cache, Source},
+    logging, metrics,
+    resilience::{Policy, Resilient},
+    trace,
 };
-use crate::shared::{Address, CartItem, CreditCardInfo, OrderResult};
 
+mod api;
+mod checkout_form;
+mod csrf;
+mod error;
+mod health;
+mod request;
+mod session;
 mod templates;
 
+use error::FrontendError;
+
 type Res<T> = Result<T, FrontendError>;
 
-type Page = Result<(CookieJar, Html<String>), FrontendError>;
-type Post = Result<(CookieJar, Redirect), FrontendError>;
+type Page = Res<Html<String>>;
+type Post = Res<Redirect>;
 
-const PORT: u16 = 8123;
-
-#[derive(Debug)]
-enum FrontendError {
-    Rpc(RpcError),
-    Template(tinytemplate::error::Error),
+/// The card an order was paid with, for saving to the user's profile.
+enum UsedCard {
+    /// Typed into the form, and not yet tokenized.
+    Entered(CreditCardInfo),
+    /// Tokenized when the order was reviewed.
+    Tokenized(PaymentMethod),
 }
 
-impl From<RpcError> for FrontendError {
-    fn from(err: RpcError) -> Self {
-        FrontendError::Rpc(err)
-    }
-}
-impl From<tinytemplate::error::Error> for FrontendError {
-    fn from(err: tinytemplate::error::Error) -> Self {
-        FrontendError::Template(err)
-    }
-}
+pub const LABEL: &str = "frontend";
 
-impl fmt::Display for FrontendError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            FrontendError::Rpc(e) => write!(f, "RPC error: {:?}", e),
-            FrontendError::Template(e) => write!(f, "Template error: {}", e),
-        }
-    }
-}
+const PORT: u16 = 8123;
 
-impl IntoResponse for FrontendError {
-    fn into_response(self) -> axum::response::Response {
-        let res = (
-            axum::http::StatusCode::INTERNAL_SERVER_ERROR,
-            format!("{}", self),
-        );
-        res.into_response()
-    }
-}
+/// Reads the pages can't do without.
+const READ: Policy = Policy::idempotent(Duration::from_secs(1));
+/// Reads for parts of a page that can be left out, such as ads. A page is as
+/// slow as its slowest call, so these get less time.
+const OPTIONAL: Policy = Policy::idempotent(Duration::from_millis(250));
+/// Pricing an order calls most of the backends in turn, and retries its own
+/// reads, so it is only tried once.
+const QUOTE: Policy = Policy::once(checkout::QUOTE_DEADLINE);
+const WRITE: Policy = Policy::once(Duration::from_secs(2));
+/// Placing an order; it must not be retried, as the card may have been
+/// charged. It gets as long as checkout's own steps can take.
+const CHECKOUT: Policy = Policy::once(checkout::PLACE_ORDER_BUDGET);
+
+/// How long catalog and currency answers are cached. Reloading the catalog
+/// or the rates empties the caches sooner.
+const CATALOG_TTL: Duration = Duration::from_secs(300);
+/// Ads are picked at random when none match, so they are kept for less time
+/// to keep some variety.
+const ADS_TTL: Duration = Duration::from_secs(30);
 
 struct FrontendServer {
     data: FrontendServerData,
@@ -73,13 +86,24 @@ struct FrontendServer {
 struct FrontendServerData {
     sock_addr: SocketAddr,
     base_url: String,
-    ad: AdClient,
-    cart: CartClient,
-    checkout: CheckoutClient,
-    currency: CurrencyClient,
-    productcatalog: ProductCatalogClient,
-    shipping: ShippingClient,
-    recommendation: RecommendationClient,
+    ad: Resilient<AdClient>,
+    cart: Resilient<CartClient>,
+    checkout: Resilient<CheckoutClient>,
+    currency: Resilient<CurrencyClient>,
+    inventory: Resilient<InventoryClient>,
+    payment: Resilient<PaymentClient>,
+    productcatalog: Resilient<ProductCatalogClient>,
+    shipping: Resilient<ShippingClient>,
+    recommendation: Resilient<RecommendationClient>,
+    user: Resilient<UserClient>,
+    products: Cache<(), Vec<Product>>,
+    product: Cache<String, Product>,
+    ads: Cache<Vec<String>, Vec<Ad>>,
+    currencies: Cache<(), Vec<String>>,
+    /// Keyed by the amount, then the target currency.
+    conversions: Cache<(Money, String), Money>,
+    templates: templates::Templates,
+    session: Arc<session::SessionConfig>,
 }
 
 impl FrontendServer {
@@ -94,26 +118,47 @@ impl FrontendServer {
             data: FrontendServerData {
                 sock_addr,
                 base_url,
-                ad: AdClient::new(),
-                cart: CartClient::new(),
-                checkout: CheckoutClient::new(),
-                currency: CurrencyClient::new(),
-                productcatalog: ProductCatalogClient::new(),
-                shipping: ShippingClient::new(),
-                recommendation: RecommendationClient::new(),
+                ad: Resilient::new(backend::ad::LABEL, AdClient::new()),
+                cart: Resilient::new(backend::cart::LABEL, CartClient::new()),
+                checkout: Resilient::new(backend::checkout::LABEL, CheckoutClient::new()),
+                currency: Resilient::new(backend::currency::LABEL, CurrencyClient::new()),
+                inventory: Resilient::new(backend::inventory::LABEL, InventoryClient::new()),
+                payment: Resilient::new(backend::payment::LABEL, PaymentClient::new()),
+                productcatalog: Resilient::new(
+                    backend::productcatalog::LABEL,
+                    ProductCatalogClient::new(),
+                ),
+                shipping: Resilient::new(backend::shipping::LABEL, ShippingClient::new()),
+                recommendation: Resilient::new(
+                    backend::recommendation::LABEL,
+                    RecommendationClient::new(),
+                ),
+                user: Resilient::new(backend::user::LABEL, UserClient::new()),
+                products: Cache::new("frontend.products", Some(Source::Catalog), 1, CATALOG_TTL),
+                product: Cache::new("frontend.product", Some(Source::Catalog), 1000, CATALOG_TTL),
+                ads: Cache::new("frontend.ads", None, 100, ADS_TTL),
+                currencies: Cache::new("frontend.currencies", Some(Source::Rates), 1, CATALOG_TTL),
+                conversions: Cache::new(
+                    "frontend.conversions",
+                    Some(Source::Rates),
+                    10_000,
+                    CATALOG_TTL,
+                ),
+                templates: templates::Templates::new(),
+                session: Arc::new(session::SessionConfig::from_env()),
             },
         }
     }
 
-    async fn start(&self) {
-        let app = Router::new()
+    fn router(&self) -> Router {
+        Router::new()
             .route("/", {
                 get({
                     let data = self.data.clone();
-                    async move |jar: CookieJar| -> Page {
-                        let (jar, ctx) = data.home_ctx(jar).await?;
-                        let html = templates::init().render("home", &ctx)?;
-                        Ok((jar, Html(html)))
+                    async move || -> Page {
+                        let ctx = data.home_ctx().await?;
+                        let html = data.templates.render("home", &ctx)?;
+                        Ok(Html(html))
                     }
                 })
             })
@@ -121,35 +166,41 @@ impl FrontendServer {
             .route("/product/{id}", {
                 get({
                     let data = self.data.clone();
-                    async move |jar: CookieJar, Path(id): Path<String>| -> Page {
+                    async move |Path(id): Path<String>| -> Page {
                         let ctx = data.product_ctx(&id).await?;
-                        Ok((jar, Html(templates::init().render("product", &ctx)?)))
+                        Ok(Html(data.templates.render("product", &ctx)?))
                     }
                 })
             })
             .route("/cart", {
                 get({
                     let data = self.data.clone();
-                    async move |jar: CookieJar| -> Page {
-                        let (jar, ctx) = data.cart_ctx(jar).await?;
-                        Ok((jar, Html(templates::init().render("cart", &ctx)?)))
+                    async move || -> Page {
+                        let ctx = data
+                            .cart_ctx(Default::default(), Default::default())
+                            .await?;
+                        Ok(Html(data.templates.render("cart", &ctx)?))
                     }
                 })
                 .post({
                     let data = self.data.clone();
-                    async move |jar: CookieJar, Form(form): Form<templates::CartForm>| -> Post {
-                        let jar = data.cart_form(jar, form).await?;
-                        Ok((jar, Redirect::to("/cart")))
+                    async move |Form(form): Form<templates::CartForm>| -> Post {
+                        data.cart_form(form).await?;
+                        Ok(Redirect::to("/cart"))
                     }
                 })
             })
             .route("/cart/empty", {
                 post({
                     let data = self.data.clone();
-                    async move |jar: CookieJar| -> Post {
-                        let (jar, user_id) = data.get_or_set_user_id(jar);
-                        data.cart.empty_cart(user_id).await?;
-                        Ok((jar, Redirect::to("/cart")))
+                    async move || -> Post {
+                        let user_id = session::user_id();
+                        data.cart
+                            .call("empty_cart", WRITE, |c| {
+                                c.empty_cart(RpcContext::current(), user_id.clone())
+                            })
+                            .await?;
+                        Ok(Redirect::to("/cart"))
                     }
                 })
             })
@@ -160,38 +211,211 @@ impl FrontendServer {
                 })
             })
             .route("/logout", {
+                post(async || -> Post {
+                    session::reset();
+                    Ok(Redirect::to("/"))
+                })
+            })
+            .route("/login", {
+                get({
+                    let data = self.data.clone();
+                    async move || -> Page {
+                        let ctx = data.auth_ctx(String::new(), None).await?;
+                        Ok(Html(data.templates.render("login", &ctx)?))
+                    }
+                })
+                .post({
+                    let data = self.data.clone();
+                    async move |Form(form): Form<templates::CredentialsForm>| -> Res<Response> {
+                        let res = data
+                            .user
+                            .call("login", WRITE, |c| {
+                                let (email, password) = (form.email.clone(), form.password.clone());
+                                c.login(RpcContext::current(), email, password)
+                            })
+                            .await;
+                        data.auth_result("login", form.email, res).await
+                    }
+                })
+            })
+            .route("/register", {
+                get({
+                    let data = self.data.clone();
+                    async move || -> Page {
+                        let ctx = data.auth_ctx(String::new(), None).await?;
+                        Ok(Html(data.templates.render("register", &ctx)?))
+                    }
+                })
+                .post({
+                    let data = self.data.clone();
+                    async move |Form(form): Form<templates::CredentialsForm>| -> Res<Response> {
+                        let res = data
+                            .user
+                            .call("register", WRITE, |c| {
+                                let (email, password) = (form.email.clone(), form.password.clone());
+                                c.register(RpcContext::current(), email, password)
+                            })
+                            .await;
+                        data.auth_result("register", form.email, res).await
+                    }
+                })
+            })
+            .route("/account", {
+                get({
+                    let data = self.data.clone();
+                    async move || -> Res<Response> {
+                        if !session::is_logged_in() {
+                            return Ok(Redirect::to("/login").into_response());
+                        }
+                        let ctx = data.account_ctx().await?;
+                        Ok(Html(data.templates.render("account", &ctx)?).into_response())
+                    }
+                })
+            })
+            .route("/cart/review", {
                 post({
-                    let _data = self.data.clone();
-                    async move || Html("<h1>TODO</h1>")
+                    let data = self.data.clone();
+                    async move |Form(form): Form<checkout_form::CheckoutForm>| -> Page {
+                        match form.validate() {
+                            Ok(details) => {
+                                let ctx = data.review_ctx(form.without_card(), details).await?;
+                                Ok(Html(data.templates.render("review", &ctx)?))
+                            }
+                            Err(errors) => {
+                                let ctx = data.cart_ctx(form, *errors).await?;
+                                Ok(Html(data.templates.render("cart", &ctx)?))
+                            }
+                        }
+                    }
                 })
             })
             .route("/cart/checkout", {
                 post({
                     let data = self.data.clone();
-                    async move |jar: CookieJar, Form(form): Form<templates::CheckoutForm>| -> Page {
-                        let (jar, order) = data.checkout_form(jar, form).await?;
+                    async move |Form(form): Form<checkout_form::CheckoutForm>| -> Page {
+                        // Coming from the review page, the card was tokenized
+                        // there and only the rest of the form is posted.
+                        let res = match session::pending_card() {
+                            Some(card) if form.credit_card_number.trim().is_empty() => {
+                                match form.validate_shipping() {
+                                    Ok(details) => Ok(data.checkout_token(details, card).await?),
+                                    Err(errors) => Err(errors),
+                                }
+                            }
+                            _ => match form.validate() {
+                                Ok(details) => Ok(data.checkout_form(details).await?),
+                                Err(errors) => Err(errors),
+                            },
+                        };
+                        match res {
+                            Ok(order) => {
+                                let ctx = data.checkout_ctx(order).await?;
+                                Ok(Html(data.templates.render("checkout", &ctx)?))
+                            }
+                            Err(errors) => {
+                                let ctx = data.cart_ctx(form, *errors).await?;
+                                Ok(Html(data.templates.render("cart", &ctx)?))
+                            }
+                        }
+                    }
+                })
+            })
+            .route("/cart/review/saved", {
+                post({
+                    let data = self.data.clone();
+                    async move |Form(form): Form<checkout_form::SavedCheckoutForm>| -> Res<Response> {
+                        if !session::is_logged_in() {
+                            return Ok(Redirect::to("/login").into_response());
+                        }
+                        let ctx = data.saved_review_ctx(form).await?;
+                        Ok(Html(data.templates.render("review", &ctx)?).into_response())
+                    }
+                })
+            })
+            .route("/cart/checkout/saved", {
+                post({
+                    let data = self.data.clone();
+                    async move |Form(form): Form<checkout_form::SavedCheckoutForm>| -> Res<Response> {
+                        if !session::is_logged_in() {
+                            return Ok(Redirect::to("/login").into_response());
+                        }
+                        let order = data.checkout_saved_form(form).await?;
                         let ctx = data.checkout_ctx(order).await?;
-                        Ok((jar, Html(templates::init().render("checkout", &ctx)?)))
+                        Ok(Html(data.templates.render("checkout", &ctx)?).into_response())
                     }
                 })
             })
+            .nest("/api/v1", api::router(&self.data))
+            .fallback(async || FrontendError::NotFound)
+            .layer(axum::middleware::from_fn(csrf::protect))
+            .layer({
+                use axum::extract::Request;
+                use axum::middleware::{self, Next};
+                let config = self.data.session.clone();
+                middleware::from_fn(move |req: Request, next: Next| {
+                    session::scope(config.clone(), req, next)
+                })
+            })
             .layer({
                 use axum::extract::Request;
                 use axum::middleware::{self, Next};
                 middleware::from_fn(async |req: Request, next: Next| {
                     let start = Instant::now();
                     let prefix = format!("{} {:?}", req.method(), req.uri());
+                    let method = req.method().clone();
+                    let route = route_of(&req);
                     let res = next.run(req).await;
-                    log::info!(
-                        "{} - {} - {}ms",
-                        prefix,
-                        res.status(),
-                        start.elapsed().as_millis()
+                    let elapsed = start.elapsed();
+                    log::info!("{} - {} - {}ms", prefix, res.status(), elapsed.as_millis());
+                    metrics::observe_http(method.as_str(), &route, res.status().as_u16(), elapsed);
+                    res
+                })
+            })
+            .layer({
+                use axum::extract::Request;
+                use axum::middleware::{self, Next};
+                middleware::from_fn(async |req: Request, next: Next| {
+                    let parent = req
+                        .headers()
+                        .get("traceparent")
+                        .and_then(|x| x.to_str().ok())
+                        .and_then(trace::SpanContext::parse);
+                    let route = route_of(&req);
+                    let mut span = trace::Span::start(
+                        "frontend",
+                        format!("{} {}", req.method(), route),
+                        trace::SpanKind::Server,
+                        parent,
                     );
+                    span.set_attribute("http.request.method", req.method());
+                    span.set_attribute("http.route", route);
+                    span.set_attribute("url.path", req.uri().path());
+                    if let Some(request_id) = request::request_id() {
+                        span.set_attribute("request.id", request_id);
+                    }
+                    let res = span.scope(next.run(req)).await;
+                    span.set_attribute("http.response.status_code", res.status().as_u16());
+                    if res.status().is_server_error() {
+                        span.set_error(res.status());
+                    }
+                    span.end();
                     res
                 })
-            });
+            })
+            .layer({
+                use axum::extract::Request;
+                use axum::middleware::{self, Next};
+                let base_url = self.data.base_url.clone();
+                let templates = self.data.templates.clone();
+                middleware::from_fn(move |req: Request, next: Next| {
+                    request::scope(base_url.clone(), templates.clone(), req, next)
+                })
+            })
+            .merge(health::router(&self.data))
+    }
 
+    async fn start(&self) {
+        let app = self.router();
         let listener = tokio::net::TcpListener::bind(self.data.sock_addr)
             .await
             .unwrap();
@@ -204,22 +428,39 @@ impl FrontendServer {
     }
 }
 
-impl FrontendServerData {
-    fn get_or_set_user_id(&self, jar: CookieJar) -> (CookieJar, String) {
-        let key = "BOUTIQUE_USER_ID";
-        let value = jar.get(key).map(|x| x.value().to_string());
-        match value {
-            Some(x) => (jar, x),
-            None => {
-                let id = uuid::Uuid::new_v4().to_string();
-                (jar.add(Cookie::new(key, id.clone())), id)
-            }
-        }
-    }
+/// Builds the frontend's router without binding a port, for driving it
+/// in-process.
+#[cfg(test)]
+pub async fn router() -> Router {
+    FrontendServer::new().await.router()
+}
+
+/// Falls back to nothing for a part of a page that can be left out, putting
+/// a notice on the page in its place rather than failing the whole page.
+fn or_degraded<T: Default>(res: RpcResult<T>, notice: &'static str) -> T {
+    res.unwrap_or_else(|e| {
+        log::warn!("{} ({:?})", notice, e);
+        request::degraded(notice);
+        T::default()
+    })
+}
 
+/// The route a request matched, for labelling it in metrics and traces.
+/// Routes rather than paths, so product IDs and bogus URLs don't each get a
+/// series of their own.
+fn route_of(req: &axum::extract::Request) -> String {
+    req.extensions()
+        .get::<MatchedPath>()
+        .map_or("unmatched".to_owned(), |x| x.as_str().to_owned())
+}
+
+impl FrontendServerData {
     async fn header_ctx(&'_ self) -> Res<templates::HeaderContext<'_>> {
         Ok(templates::HeaderContext {
             base_url: self.base_url.as_str(),
+            csrf_token: csrf::token(),
+            logged_in: session::is_logged_in(),
+            notices: request::notices(),
         })
     }
 
@@ -229,75 +470,163 @@ impl FrontendServerData {
         })
     }
 
-    async fn home_ctx(&'_ self, jar: CookieJar) -> Res<(CookieJar, templates::HomeContext<'_>)> {
-        let products = self.productcatalog.list_products().await?;
-        // Get user_id from cookie jar
-        let (jar, user_id) = self.get_or_set_user_id(jar);
+    async fn home_ctx(&'_ self) -> Res<templates::HomeContext<'_>> {
+        let products = self.list_products().await?;
+        // Get user_id from the session
+        let user_id = session::user_id();
         // Get recommended product ids from recommendation service
+        let product_ids: Vec<_> = products.iter().map(|p| p.id.clone()).collect();
         let recommended_ids = self
             .recommendation
-            .list_recommendations(
-                user_id.clone(),
-                products.iter().map(|p| p.id.clone()).collect(),
-            )
-            .await?;
+            .call("list_recommendations", OPTIONAL, |c| {
+                c.list_recommendations(RpcContext::current(), user_id.clone(), product_ids.clone())
+            })
+            .await;
+        let recommended_ids = or_degraded(recommended_ids, "Recommendations are unavailable.");
         // Join recommended ids with products
         let recommended: Vec<_> = recommended_ids
             .into_iter()
             .filter_map(|id| products.iter().find(|p| p.id == id).cloned())
             .collect();
-        Ok((
-            jar,
-            templates::HomeContext {
-                header: self.header_ctx().await?,
-                footer: self.footer_ctx().await?,
-                base_url: self.base_url.as_str(),
-                products,
-                recommended,
-            },
-        ))
+        Ok(templates::HomeContext {
+            header: self.header_ctx().await?,
+            footer: self.footer_ctx().await?,
+            base_url: self.base_url.as_str(),
+            products,
+            recommended,
+        })
     }
 
     async fn product_ctx(&'_ self, id: &str) -> Res<templates::ProductContext<'_>> {
-        let product = self.productcatalog.get_product(id.to_string()).await?;
+        let product = self.get_product(id.to_owned()).await?;
+        let in_stock = self
+            .inventory
+            .call("get_stock", READ, |c| {
+                c.get_stock(RpcContext::current(), product.id.clone())
+            })
+            .await?
+            > 0;
         // Fetch ads using product categories
-        let mut ads = self
-            .ad
-            .get_ads(product.categories.clone())
-            .await
-            .unwrap_or_default();
+        let ads = self.get_ads(product.categories.clone()).await;
+        let mut ads = or_degraded(ads, "Ads are unavailable.");
         // Filter out ads that match the current product id
         ads.retain(|ad| !ad.redirect_url.contains(&product.id));
+        metrics::ads_shown(ads.len());
         Ok(templates::ProductContext {
             header: self.header_ctx().await?,
             footer: self.footer_ctx().await?,
             base_url: self.base_url.as_str(),
             product,
+            in_stock,
             ads,
+            csrf_token: csrf::token(),
         })
     }
 
-    async fn cart_ctx(&'_ self, jar: CookieJar) -> Res<(CookieJar, templates::CartContext<'_>)> {
-        let (jar, user_id) = self.get_or_set_user_id(jar);
-        log::info!("loading cart for {}", user_id);
-        let cart = self.cart.get_cart(user_id).await?;
+    async fn cart_ctx(
+        &'_ self,
+        form: checkout_form::CheckoutForm,
+        errors: checkout_form::CheckoutFormErrors,
+    ) -> Res<templates::CartContext<'_>> {
+        let user_id = session::user_id();
+        log::info!("loading cart");
+        let cart = self.user_cart(user_id.clone()).await?;
+        let (saved_addresses, payment_methods) = match session::is_logged_in() {
+            true => {
+                let profile = self
+                    .user
+                    .call("get_profile", READ, |c| {
+                        c.get_profile(RpcContext::current(), user_id.clone())
+                    })
+                    .await?;
+                (profile.addresses, profile.payment_methods)
+            }
+            false => (Vec::new(), Vec::new()),
+        };
         let ctx = templates::CartContext {
             header: self.header_ctx().await?,
             footer: self.footer_ctx().await?,
             base_url: self.base_url.as_str(),
             items: cart.items,
+            form: form.without_card(),
+            errors,
+            saved_addresses,
+            payment_methods,
+            csrf_token: csrf::token(),
         };
-        Ok((jar, ctx))
+        Ok(ctx)
+    }
+
+    async fn list_products(&self) -> RpcResult<Vec<Product>> {
+        self.products
+            .get_or_load((), || {
+                self.productcatalog.call("list_products", READ, |c| {
+                    c.list_products(RpcContext::current())
+                })
+            })
+            .await
     }
 
-    async fn cart_form(&self, jar: CookieJar, form: templates::CartForm) -> Res<CookieJar> {
-        let (jar, user_id) = self.get_or_set_user_id(jar);
+    async fn get_product(&self, id: String) -> RpcResult<Product> {
+        self.product
+            .get_or_load(id.clone(), || {
+                self.productcatalog.call("get_product", READ, |c| {
+                    c.get_product(RpcContext::current(), id.clone())
+                })
+            })
+            .await
+    }
+
+    async fn get_ads(&self, categories: Vec<String>) -> RpcResult<Vec<Ad>> {
+        self.ads
+            .get_or_load(categories.clone(), || {
+                self.ad.call("get_ads", OPTIONAL, |c| {
+                    c.get_ads(RpcContext::current(), categories.clone())
+                })
+            })
+            .await
+    }
+
+    async fn supported_currencies(&self) -> RpcResult<Vec<String>> {
+        self.currencies
+            .get_or_load((), || {
+                self.currency.call("get_supported_currencies", READ, |c| {
+                    c.get_supported_currencies(RpcContext::current())
+                })
+            })
+            .await
+    }
+
+    async fn convert(&self, from: Money, to: String) -> RpcResult<Money> {
+        self.conversions
+            .get_or_load((from.clone(), to.clone()), || {
+                self.currency.call("convert", READ, |c| {
+                    c.convert(RpcContext::current(), from.clone(), to.clone())
+                })
+            })
+            .await
+    }
+
+    async fn user_cart(&self, user_id: String) -> RpcResult<Cart> {
+        self.cart
+            .call("get_cart", READ, |c| {
+                c.get_cart(RpcContext::current(), user_id.clone())
+            })
+            .await
+    }
+
+    async fn cart_form(&self, form: templates::CartForm) -> Res<()> {
+        let user_id = session::user_id();
         let item = CartItem {
             product_id: form.product_id,
             quantity: form.quantity,
         };
-        self.cart.add_item(user_id, item).await?;
-        Ok(jar)
+        self.cart
+            .call("add_item", WRITE, |c| {
+                c.add_item(RpcContext::current(), user_id.clone(), item.clone())
+            })
+            .await?;
+        Ok(())
     }
 
     async fn checkout_ctx<'svc>(
@@ -313,34 +642,311 @@ impl FrontendServerData {
             shipping_cost: order.shipping_cost,
             shipping_address: order.shipping_address,
             items: order.items,
+            discounts: order.discounts,
+            tax_lines: order.tax_lines,
+            tax: order.tax,
+            total: order.total,
         })
     }
 
-    async fn checkout_form(
+    async fn review_ctx(
+        &'_ self,
+        form: checkout_form::CheckoutForm,
+        details: checkout_form::CheckoutDetails,
+    ) -> Res<templates::ReviewContext<'_>> {
+        let user_id = session::user_id();
+        let user_currency = "USD".to_string(); // TODO: support user currency selection
+        let quote = self
+            .checkout
+            .call("quote_order", QUOTE, |c| {
+                c.quote_order(
+                    RpcContext::current(),
+                    user_id.clone(),
+                    user_currency.clone(),
+                    details.address.clone(),
+                    details.coupon_code.clone(),
+                )
+            })
+            .await?;
+        // The card stays out of the review page. It is tokenized now and the
+        // token kept in the session until the order is placed.
+        let card = self.tokenize(&user_id, &details.credit_card).await?;
+        session::set_pending_card(Some(card.clone()));
+        let ctx = templates::ReviewContext {
+            header: self.header_ctx().await?,
+            footer: self.footer_ctx().await?,
+            base_url: self.base_url.as_str(),
+            quote,
+            form,
+            saved: None,
+            payment_method: Some(card),
+            csrf_token: csrf::token(),
+        };
+        Ok(ctx)
+    }
+
+    async fn saved_review_ctx(
+        &'_ self,
+        saved: checkout_form::SavedCheckoutForm,
+    ) -> Res<templates::ReviewContext<'_>> {
+        let user_id = session::user_id();
+        let user_currency = "USD".to_string(); // TODO: support user currency selection
+        let profile = self
+            .user
+            .call("get_profile", READ, |c| {
+                c.get_profile(RpcContext::current(), user_id.clone())
+            })
+            .await?;
+        let address = profile
+            .addresses
+            .into_iter()
+            .find(|x| x.id == saved.address_id)
+            .ok_or_else(|| ErrorKind::InvalidArgument.error("Choose a saved address."))?
+            .address;
+        let payment_method = profile
+            .payment_methods
+            .into_iter()
+            .find(|x| x.token == saved.payment_token)
+            .ok_or_else(|| ErrorKind::InvalidArgument.error("Choose a saved card."))?;
+        let coupon_code = Some(saved.coupon_code.trim().to_owned()).filter(|x| !x.is_empty());
+        let quote = self
+            .checkout
+            .call("quote_order", QUOTE, |c| {
+                c.quote_order(
+                    RpcContext::current(),
+                    user_id.clone(),
+                    user_currency.clone(),
+                    address.clone(),
+                    coupon_code.clone(),
+                )
+            })
+            .await?;
+        // The review page shows the address from the form.
+        let form = checkout_form::CheckoutForm {
+            street_address: address.street_address,
+            city: address.city,
+            state: address.state,
+            country: address.country,
+            zip_code: address.zip_code,
+            coupon_code: saved.coupon_code.clone(),
+            ..Default::default()
+        };
+        let ctx = templates::ReviewContext {
+            header: self.header_ctx().await?,
+            footer: self.footer_ctx().await?,
+            base_url: self.base_url.as_str(),
+            quote,
+            form,
+            saved: Some(saved),
+            payment_method: Some(payment_method),
+            csrf_token: csrf::token(),
+        };
+        Ok(ctx)
+    }
+
+    async fn checkout_saved_form(
         &self,
-        jar: CookieJar,
-        form: templates::CheckoutForm,
-    ) -> Res<(CookieJar, OrderResult)> {
-        let (jar, user_id) = self.get_or_set_user_id(jar);
+        saved: checkout_form::SavedCheckoutForm,
+    ) -> Res<OrderResult> {
+        let user_id = session::user_id();
         let user_currency = "USD".to_string(); // TODO: support user currency selection
-        let address = Address {
-            street_address: form.street_address,
-            city: form.city,
-            state: form.state,
-            country: form.country,
-            zip_code: form.zip_code,
+        let coupon_code = Some(saved.coupon_code.trim().to_owned()).filter(|x| !x.is_empty());
+        let order = self
+            .checkout
+            .call("checkout_with_saved", CHECKOUT, |c| {
+                c.checkout_with_saved(
+                    RpcContext::current(),
+                    user_id.clone(),
+                    user_currency.clone(),
+                    saved.address_id.clone(),
+                    saved.payment_token.clone(),
+                    coupon_code.clone(),
+                )
+            })
+            .await?;
+        self.remember_checkout(user_id, &order, None).await;
+        Ok(order)
+    }
+
+    /// Tokenizes `card` for `user_id`, the only user who can then pay with
+    /// the token.
+    async fn tokenize(&self, user_id: &str, card: &CreditCardInfo) -> RpcResult<PaymentMethod> {
+        self.payment
+            .call("tokenize", WRITE, |c| {
+                c.tokenize(RpcContext::current(), user_id.to_owned(), card.clone())
+            })
+            .await
+    }
+
+    /// Saves the address and card of a logged-in user's order so they can be
+    /// picked next time. The card is tokenized by the payment service, which
+    /// is the only place the card number goes. Failures only cost the user
+    /// some typing next time, so they are logged and otherwise ignored.
+    async fn remember_checkout(
+        &self,
+        user_id: String,
+        order: &OrderResult,
+        card: Option<UsedCard>,
+    ) {
+        if !session::is_logged_in() {
+            return;
+        }
+        logging::set_order_id(&order.order_id);
+        let address = order.shipping_address.clone();
+        if let Err(e) = self
+            .user
+            .call("save_address", WRITE, |c| {
+                c.save_address(RpcContext::current(), user_id.clone(), address.clone())
+            })
+            .await
+        {
+            log::warn!("failed to save address: {:?}", e);
+        }
+        let method = match card {
+            Some(UsedCard::Entered(card)) => self.tokenize(&user_id, &card).await,
+            Some(UsedCard::Tokenized(method)) => Ok(method),
+            None => return,
         };
-        let credit_card = CreditCardInfo {
-            credit_card_number: form.credit_card_number,
-            credit_card_ccv: form.credit_card_ccv,
-            credit_card_expiration_year: form.credit_card_expiration_year,
-            credit_card_expiration_month: form.credit_card_expiration_month,
+        let saved = match method {
+            Ok(method) => {
+                self.user
+                    .call("save_payment_method", WRITE, |c| {
+                        c.save_payment_method(
+                            RpcContext::current(),
+                            user_id.clone(),
+                            method.clone(),
+                        )
+                    })
+                    .await
+            }
+            Err(e) => Err(e),
         };
+        if let Err(e) = saved {
+            log::warn!("failed to save card: {:?}", e);
+        }
+    }
+
+    /// Places an order with the card tokenized when it was reviewed.
+    async fn checkout_token(
+        &self,
+        details: checkout_form::ShippingDetails,
+        card: PaymentMethod,
+    ) -> Res<OrderResult> {
+        let user_id = session::user_id();
+        let user_currency = "USD".to_string(); // TODO: support user currency selection
+        let order = self
+            .checkout
+            .call("checkout_with_token", CHECKOUT, |c| {
+                c.checkout_with_token(
+                    RpcContext::current(),
+                    user_id.clone(),
+                    user_currency.clone(),
+                    details.address.clone(),
+                    details.email.clone(),
+                    card.token.clone(),
+                    details.coupon_code.clone(),
+                )
+            })
+            .await?;
+        session::set_pending_card(None);
+        self.remember_checkout(user_id, &order, Some(UsedCard::Tokenized(card)))
+            .await;
+        Ok(order)
+    }
+
+    async fn checkout_form(&self, details: checkout_form::CheckoutDetails) -> Res<OrderResult> {
+        let user_id = session::user_id();
+        let user_currency = "USD".to_string(); // TODO: support user currency selection
         let order = self
             .checkout
-            .checkout(user_id, user_currency, address, form.email, credit_card)
+            .call("checkout", CHECKOUT, |c| {
+                c.checkout(
+                    RpcContext::current(),
+                    user_id.clone(),
+                    user_currency.clone(),
+                    details.address.clone(),
+                    details.email.clone(),
+                    details.credit_card.clone(),
+                    details.coupon_code.clone(),
+                )
+            })
+            .await?;
+        self.remember_checkout(
+            user_id,
+            &order,
+            Some(UsedCard::Entered(details.credit_card)),
+        )
+        .await;
+        Ok(order)
+    }
+
+    async fn auth_ctx(
+        &'_ self,
+        email: String,
+        error: Option<String>,
+    ) -> Res<templates::AuthContext<'_>> {
+        Ok(templates::AuthContext {
+            header: self.header_ctx().await?,
+            footer: self.footer_ctx().await?,
+            base_url: self.base_url.as_str(),
+            csrf_token: csrf::token(),
+            email,
+            error,
+        })
+    }
+
+    /// Finishes a login or registration attempt. Bad credentials re-render
+    /// the form with the reason; anything else is a real error.
+    async fn auth_result(
+        &self,
+        template: &str,
+        email: String,
+        res: RpcResult<String>,
+    ) -> Res<Response> {
+        match res {
+            Ok(user_id) => {
+                self.log_in(user_id).await;
+                Ok(Redirect::to("/").into_response())
+            }
+            Err(e) if ErrorKind::of(&e) == ErrorKind::InvalidArgument => {
+                let ctx = self.auth_ctx(email, Some(ErrorKind::message(&e))).await?;
+                Ok(Html(self.templates.render(template, &ctx)?).into_response())
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Switches the session to the given account, bringing along anything
+    /// put in the cart before logging in.
+    async fn log_in(&self, user_id: String) {
+        if !session::is_logged_in() {
+            let anonymous_id = session::user_id();
+            if let Err(e) = self
+                .cart
+                .call("merge_cart", WRITE, |c| {
+                    c.merge_cart(RpcContext::current(), anonymous_id.clone(), user_id.clone())
+                })
+                .await
+            {
+                log::warn!("failed to merge cart into {}: {:?}", user_id, e);
+            }
+        }
+        session::login(user_id);
+    }
+
+    async fn account_ctx(&'_ self) -> Res<templates::AccountContext<'_>> {
+        let profile = self
+            .user
+            .call("get_profile", READ, |c| {
+                c.get_profile(RpcContext::current(), session::user_id())
+            })
             .await?;
-        Ok((jar, order))
+        Ok(templates::AccountContext {
+            header: self.header_ctx().await?,
+            footer: self.footer_ctx().await?,
+            base_url: self.base_url.as_str(),
+            profile,
+        })
     }
 }
 
@@ -350,7 +956,7 @@ impl runtime::Component for FrontendServer {
 
 pub fn component() -> ComponentConfig {
     ComponentConfig {
-        label: "frontend".to_string(),
+        label: LABEL.to_owned(),
         id: FrontendServer::id(),
         binding: Binding::Tcp(PORT),
         is_stateful: false,
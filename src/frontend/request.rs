@@ -0,0 +1,97 @@
+use std::cell::RefCell;
+
+use axum::{
+    extract::Request,
+    http::{HeaderName, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+
+use super::templates::Templates;
+use crate::shared::logging::{self, LogContext};
+
+pub const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Per-request information that needs to be reachable from places that don't
+/// have access to the request, such as `FrontendError::into_response`.
+#[derive(Clone)]
+pub struct RequestInfo {
+    pub id: String,
+    pub base_url: String,
+    pub templates: Templates,
+}
+
+tokio::task_local! {
+    static NOTICES: RefCell<Vec<&'static str>>;
+}
+
+tokio::task_local! {
+    static REQUEST: RequestInfo;
+}
+
+/// Returns the ID of the request being handled by the current task.
+pub fn request_id() -> Option<String> {
+    REQUEST.try_with(|r| r.id.clone()).ok()
+}
+
+pub fn base_url() -> String {
+    REQUEST.try_with(|r| r.base_url.clone()).unwrap_or_default()
+}
+
+pub fn templates() -> Option<Templates> {
+    REQUEST.try_with(|r| r.templates.clone()).ok()
+}
+
+/// Notes that the page is missing something because a backend couldn't
+/// provide it, so the page can say so.
+pub fn degraded(notice: &'static str) {
+    let _ = NOTICES.try_with(|x| {
+        let mut notices = x.borrow_mut();
+        if !notices.contains(&notice) {
+            notices.push(notice);
+        }
+    });
+}
+
+/// What the page being rendered is missing; see `degraded`.
+pub fn notices() -> Vec<&'static str> {
+    NOTICES.try_with(|x| x.borrow().clone()).unwrap_or_default()
+}
+
+/// Reuses a request ID supplied by a proxy in front of us when it looks sane,
+/// so logs can be correlated across both.
+fn incoming_request_id(req: &Request) -> Option<String> {
+    let id = req.headers().get(&REQUEST_ID_HEADER)?.to_str().ok()?;
+    let ok = !id.is_empty()
+        && id.len() <= 64
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    ok.then(|| id.to_owned())
+}
+
+/// Assigns a request ID, makes it available for the rest of the request and
+/// its logs, and echoes it back in the response headers.
+pub async fn scope(base_url: String, templates: Templates, req: Request, next: Next) -> Response {
+    let id = incoming_request_id(&req).unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+    let info = RequestInfo {
+        id: id.clone(),
+        base_url,
+        templates,
+    };
+    let log = LogContext {
+        component: Some("frontend"),
+        request_id: Some(id.clone()),
+        ..LogContext::default()
+    };
+    let mut res = REQUEST
+        .scope(
+            info,
+            NOTICES.scope(RefCell::default(), logging::scope(log, next.run(req))),
+        )
+        .await;
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        res.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    res
+}
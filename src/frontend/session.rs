@@ -0,0 +1,248 @@
+//! Encrypted session cookies. The session is decrypted once per request and
+//! kept in a task-local, so handlers just ask for `session::user_id()`. If the
+//! handler changes the session, or it is due for a refresh, the cookie is
+//! reissued on the way out.
+
+use std::{cell::RefCell, sync::Arc};
+
+use axum::{
+    extract::Request,
+    http::HeaderMap,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use axum_extra::extract::{
+    PrivateCookieJar,
+    cookie::{Cookie, Key, SameSite},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::shared::{PaymentMethod, logging, now_secs};
+
+const COOKIE_NAME: &str = "BOUTIQUE_SESSION";
+
+/// Secrets shorter than this are rejected, since the cookie keys are derived
+/// from them.
+const MIN_SECRET_LEN: usize = 32;
+
+const DEFAULT_IDLE_TIMEOUT_SECS: u64 = 7 * 24 * 60 * 60;
+const DEFAULT_MAX_LIFETIME_SECS: u64 = 30 * 24 * 60 * 60;
+
+pub struct SessionConfig {
+    /// The first key encrypts new cookies. The rest are older keys that are
+    /// still accepted, so keys can be rotated without logging everyone out.
+    keys: Vec<Key>,
+    /// A session not used for this long expires.
+    idle_timeout_secs: u64,
+    /// A session expires this long after it was created, however active.
+    max_lifetime_secs: u64,
+    secure: bool,
+}
+
+fn env_secs(name: &str, default: u64) -> u64 {
+    match std::env::var(name) {
+        Ok(x) => x
+            .parse()
+            .unwrap_or_else(|_| panic!("{} must be a number of seconds", name)),
+        Err(_) => default,
+    }
+}
+
+impl SessionConfig {
+    /// Reads the configuration from the environment:
+    ///
+    /// * `BOUTIQUE_SESSION_KEYS`: comma-separated secrets of at least 32
+    ///   bytes, newest first. If unset, a random key is used and sessions do
+    ///   not survive a restart.
+    /// * `BOUTIQUE_SESSION_IDLE_SECS` and `BOUTIQUE_SESSION_MAX_SECS`: the
+    ///   idle timeout and maximum lifetime, defaulting to 7 and 30 days.
+    /// * `BOUTIQUE_SECURE_COOKIES`: set to `false` to allow the cookie over
+    ///   plain HTTP on hosts other than localhost.
+    pub fn from_env() -> SessionConfig {
+        let keys = match std::env::var("BOUTIQUE_SESSION_KEYS") {
+            Ok(x) => x
+                .split(',')
+                .map(str::trim)
+                .filter(|x| !x.is_empty())
+                .map(|secret| {
+                    if secret.len() < MIN_SECRET_LEN {
+                        panic!(
+                            "BOUTIQUE_SESSION_KEYS entries must be at least {} bytes",
+                            MIN_SECRET_LEN
+                        );
+                    }
+                    Key::derive_from(secret.as_bytes())
+                })
+                .collect(),
+            Err(_) => Vec::new(),
+        };
+        let keys = if keys.is_empty() {
+            log::warn!("BOUTIQUE_SESSION_KEYS is not set; using a random session key");
+            vec![Key::generate()]
+        } else {
+            keys
+        };
+        SessionConfig {
+            keys,
+            idle_timeout_secs: env_secs("BOUTIQUE_SESSION_IDLE_SECS", DEFAULT_IDLE_TIMEOUT_SECS),
+            max_lifetime_secs: env_secs("BOUTIQUE_SESSION_MAX_SECS", DEFAULT_MAX_LIFETIME_SECS),
+            secure: std::env::var("BOUTIQUE_SECURE_COOKIES").map_or(true, |x| x != "false"),
+        }
+    }
+
+    /// Decrypts the session cookie, returning the session and whether it
+    /// was encrypted with the current key.
+    fn load(&self, headers: &HeaderMap) -> Option<(SessionData, bool)> {
+        self.keys.iter().enumerate().find_map(|(i, key)| {
+            let cookie = PrivateCookieJar::from_headers(headers, key.clone()).get(COOKIE_NAME)?;
+            let session = serde_json::from_str(cookie.value()).ok()?;
+            Some((session, i == 0))
+        })
+    }
+
+    fn is_live(&self, session: &SessionData, now: u64) -> bool {
+        now < session.created_at.saturating_add(self.max_lifetime_secs)
+            && now < session.refreshed_at.saturating_add(self.idle_timeout_secs)
+    }
+
+    /// Sliding the idle timeout forward on every request would mean a new
+    /// cookie on every response, so it only happens once some of it is used.
+    fn needs_refresh(&self, session: &SessionData, now: u64) -> bool {
+        now.saturating_sub(session.refreshed_at) > self.idle_timeout_secs / 8
+    }
+
+    fn cookie(&self, session: &SessionData) -> Cookie<'static> {
+        let expires_at = session
+            .created_at
+            .saturating_add(self.max_lifetime_secs)
+            .min(session.refreshed_at.saturating_add(self.idle_timeout_secs));
+        let max_age = expires_at.saturating_sub(session.refreshed_at);
+        Cookie::build((COOKIE_NAME, serde_json::to_string(session).unwrap()))
+            .path("/")
+            .http_only(true)
+            .secure(self.secure)
+            .same_site(SameSite::Lax)
+            .max_age(time::Duration::seconds(max_age as i64))
+            .build()
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct SessionData {
+    user_id: String,
+    /// Whether `user_id` belongs to a registered account rather than an
+    /// anonymous visitor.
+    #[serde(default)]
+    logged_in: bool,
+    created_at: u64,
+    refreshed_at: u64,
+    /// The card entered for the order being reviewed, tokenized so the card
+    /// number doesn't have to go through the review page.
+    #[serde(default)]
+    pending_card: Option<PaymentMethod>,
+}
+
+impl SessionData {
+    fn anonymous(now: u64) -> SessionData {
+        SessionData {
+            user_id: uuid::Uuid::new_v4().to_string(),
+            logged_in: false,
+            created_at: now,
+            refreshed_at: now,
+            pending_card: None,
+        }
+    }
+}
+
+#[derive(Clone)]
+struct State {
+    session: SessionData,
+    dirty: bool,
+}
+
+tokio::task_local! {
+    static SESSION: RefCell<State>;
+}
+
+/// Returns the user ID of the current session.
+pub fn user_id() -> String {
+    SESSION.with(|s| s.borrow().session.user_id.clone())
+}
+
+pub fn is_logged_in() -> bool {
+    SESSION
+        .try_with(|s| s.borrow().session.logged_in)
+        .unwrap_or(false)
+}
+
+/// Returns the card entered for the order being reviewed, if there is one.
+pub fn pending_card() -> Option<PaymentMethod> {
+    SESSION.with(|s| s.borrow().session.pending_card.clone())
+}
+
+pub fn set_pending_card(card: Option<PaymentMethod>) {
+    SESSION.with(|s| {
+        let mut state = s.borrow_mut();
+        state.session.pending_card = card;
+        state.dirty = true;
+    });
+}
+
+fn replace(session: SessionData) {
+    logging::set_user_id(&session.user_id);
+    SESSION.with(|s| {
+        *s.borrow_mut() = State {
+            session,
+            dirty: true,
+        };
+    });
+}
+
+/// Discards the current session and starts a fresh anonymous one, which also
+/// means a fresh, empty cart.
+pub fn reset() {
+    replace(SessionData::anonymous(now_secs()));
+}
+
+/// Starts a new session for a registered user. The session is replaced
+/// rather than updated so that its lifetime starts over.
+pub fn login(user_id: String) {
+    let now = now_secs();
+    replace(SessionData {
+        user_id,
+        logged_in: true,
+        created_at: now,
+        refreshed_at: now,
+        pending_card: None,
+    });
+}
+
+pub async fn scope(config: Arc<SessionConfig>, req: Request, next: Next) -> Response {
+    let now = now_secs();
+    let state = match config.load(req.headers()) {
+        Some((session, current)) if config.is_live(&session, now) => State {
+            dirty: !current || config.needs_refresh(&session, now),
+            session,
+        },
+        _ => State {
+            session: SessionData::anonymous(now),
+            dirty: true,
+        },
+    };
+
+    logging::set_user_id(&state.session.user_id);
+    let (res, state) = SESSION
+        .scope(RefCell::new(state), async move {
+            let res = next.run(req).await;
+            (res, SESSION.with(|s| s.borrow().clone()))
+        })
+        .await;
+
+    if !state.dirty {
+        return res;
+    }
+    let mut session = state.session;
+    session.refreshed_at = now;
+    let jar = PrivateCookieJar::new(config.keys[0].clone()).add(config.cookie(&session));
+    (jar, res).into_response()
+}
@@ -1,19 +1,39 @@
 use serde::{Deserialize, Serialize};
-use std::fmt::Write;
-use tinytemplate::TinyTemplate;
+use serde_json::Value;
+use std::{
+    fmt::Write,
+    fs,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tinytemplate::{TinyTemplate, error::Error};
 
-use crate::shared::{Address, CartItem, Money, OrderItem, Product};
+use super::checkout_form::{CheckoutForm, CheckoutFormErrors, SavedCheckoutForm};
+use crate::backend::user::{Profile, SavedAddress};
+use crate::shared::{
+    Address, CartItem, Discount, Money, OrderItem, OrderQuote, PaymentMethod, Product, TaxLine,
+};
 
+const ACCOUNT_TEMPLATE: &'static str = include_str!("account.html");
 const CART_TEMPLATE: &'static str = include_str!("cart.html");
 const CHECKOUT_TEMPLATE: &'static str = include_str!("checkout.html");
+const ERROR_TEMPLATE: &'static str = include_str!("error.html");
 const FOOTER_TEMPLATE: &'static str = include_str!("footer.html");
 const HEADER_TEMPLATE: &'static str = include_str!("header.html");
 const HOME_TEMPLATE: &'static str = include_str!("home.html");
+const LOGIN_TEMPLATE: &'static str = include_str!("login.html");
 const PRODUCT_TEMPLATE: &'static str = include_str!("product.html");
+const REGISTER_TEMPLATE: &'static str = include_str!("register.html");
+const REVIEW_TEMPLATE: &'static str = include_str!("review.html");
 
 #[derive(Serialize)]
 pub struct HeaderContext<'svc> {
     pub base_url: &'svc str,
+    pub csrf_token: String,
+    pub logged_in: bool,
+    /// Parts of the page that couldn't be shown.
+    pub notices: Vec<&'static str>,
 }
 
 #[derive(Serialize)]
@@ -36,7 +56,9 @@ pub struct ProductContext<'svc> {
     pub footer: FooterContext<'svc>,
     pub base_url: &'svc str,
     pub product: Product,
+    pub in_stock: bool,
     pub ads: Vec<crate::shared::Ad>,
+    pub csrf_token: String,
 }
 
 #[derive(Serialize)]
@@ -45,6 +67,25 @@ pub struct CartContext<'svc> {
     pub footer: FooterContext<'svc>,
     pub base_url: &'svc str,
     pub items: Vec<CartItem>,
+    pub form: CheckoutForm,
+    pub errors: CheckoutFormErrors,
+    pub saved_addresses: Vec<SavedAddress>,
+    pub payment_methods: Vec<PaymentMethod>,
+    pub csrf_token: String,
+}
+
+#[derive(Serialize)]
+pub struct ReviewContext<'svc> {
+    pub header: HeaderContext<'svc>,
+    pub footer: FooterContext<'svc>,
+    pub base_url: &'svc str,
+    pub quote: OrderQuote,
+    pub form: CheckoutForm,
+    /// Set when checking out with saved details, in which case `form` only
+    /// carries the address for display.
+    pub saved: Option<SavedCheckoutForm>,
+    pub payment_method: Option<PaymentMethod>,
+    pub csrf_token: String,
 }
 
 #[derive(Serialize)]
@@ -57,6 +98,36 @@ pub struct CheckoutContext<'svc> {
     pub shipping_cost: Money,
     pub shipping_address: Address,
     pub items: Vec<OrderItem>,
+    pub discounts: Vec<Discount>,
+    pub tax_lines: Vec<TaxLine>,
+    pub tax: Money,
+    pub total: Money,
+}
+
+/// Shared by the login and registration pages.
+#[derive(Serialize)]
+pub struct AuthContext<'svc> {
+    pub header: HeaderContext<'svc>,
+    pub footer: FooterContext<'svc>,
+    pub base_url: &'svc str,
+    pub csrf_token: String,
+    pub email: String,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct AccountContext<'svc> {
+    pub header: HeaderContext<'svc>,
+    pub footer: FooterContext<'svc>,
+    pub base_url: &'svc str,
+    pub profile: Profile,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default)]
+pub struct CredentialsForm {
+    pub email: String,
+    pub password: String,
 }
 
 #[derive(Deserialize)]
@@ -65,38 +136,634 @@ pub struct CartForm {
     pub quantity: u32,
 }
 
-#[derive(Deserialize)]
-pub struct CheckoutForm {
-    pub street_address: String,
-    pub city: String,
-    pub state: String,
-    pub country: String,
-    pub zip_code: i32,
-    pub email: String,
-    pub credit_card_number: String,
-    pub credit_card_ccv: i32,
-    pub credit_card_expiration_year: i32,
-    pub credit_card_expiration_month: i32,
+const TEMPLATES: [(&'static str, &'static str); 11] = [
+    ("account", ACCOUNT_TEMPLATE),
+    ("cart", CART_TEMPLATE),
+    ("checkout", CHECKOUT_TEMPLATE),
+    ("error", ERROR_TEMPLATE),
+    ("footer", FOOTER_TEMPLATE),
+    ("header", HEADER_TEMPLATE),
+    ("home", HOME_TEMPLATE),
+    ("login", LOGIN_TEMPLATE),
+    ("product", PRODUCT_TEMPLATE),
+    ("register", REGISTER_TEMPLATE),
+    ("review", REVIEW_TEMPLATE),
+];
+
+thread_local! {
+    /// The templates built into the binary, parsed once per thread.
+    /// `TinyTemplate` can't be shared between threads, as it keeps its
+    /// formatters as plain `dyn Fn`.
+    static COMPILED: TinyTemplate<'static> = build(&TEMPLATES).unwrap();
 }
 
-pub fn init() -> TinyTemplate<'static> {
+/// The template registry, shared by every request.
+///
+/// When `BOUTIQUE_TEMPLATE_DIR` is set (e.g. to `src/frontend/templates`), the
+/// templates are instead read from that directory and reloaded whenever one
+/// of them changes, so they can be edited without recompiling.
+#[derive(Clone)]
+pub struct Templates {
+    inner: Arc<Inner>,
+}
+
+enum Inner {
+    Compiled,
+    Dev(DevRegistry),
+}
+
+impl Templates {
+    pub fn new() -> Templates {
+        let inner = match std::env::var("BOUTIQUE_TEMPLATE_DIR") {
+            Ok(dir) => {
+                log::info!("loading templates from {:?} with hot reload", dir);
+                Inner::Dev(DevRegistry {
+                    dir: PathBuf::from(dir),
+                    state: Mutex::new(None),
+                })
+            }
+            Err(_) => {
+                // Fail at startup rather than on the first request.
+                build(&TEMPLATES).unwrap();
+                Inner::Compiled
+            }
+        };
+        Templates {
+            inner: Arc::new(inner),
+        }
+    }
+
+    pub fn render<C: Serialize>(&self, name: &str, ctx: &C) -> Result<String, Error> {
+        match &*self.inner {
+            Inner::Compiled => COMPILED.with(|tt| tt.render(name, ctx)),
+            Inner::Dev(dev) => dev.render(name, ctx),
+        }
+    }
+}
+
+fn build<'a>(templates: &[(&'a str, &'a str)]) -> Result<TinyTemplate<'a>, Error> {
     let mut tt = TinyTemplate::new();
 
-    tt.add_template("cart", CART_TEMPLATE).unwrap();
-    tt.add_template("footer", FOOTER_TEMPLATE).unwrap();
-    tt.add_template("header", HEADER_TEMPLATE).unwrap();
-    tt.add_template("home", HOME_TEMPLATE).unwrap();
-    tt.add_template("product", PRODUCT_TEMPLATE).unwrap();
-    tt.add_template("checkout", CHECKOUT_TEMPLATE).unwrap();
+    for (name, text) in templates.iter() {
+        tt.add_template(name, text)?;
+    }
+
+    tt.set_default_formatter(&format_html);
+    tt.add_formatter("unescaped", |_, _| {
+        Err(Error::GenericError {
+            msg: "the unescaped formatter is disabled".to_owned(),
+        })
+    });
+    tt.add_formatter("url", format_url);
+    tt.add_formatter("path", format_path);
 
     tt.add_formatter("money", |val, s| {
         let money = val.as_object().unwrap();
         let currency_code = money.get("currency_code").unwrap().as_str().unwrap();
         let units = money.get("units").unwrap().as_i64().unwrap();
         let nanos = money.get("nanos").unwrap().as_i64().unwrap();
-        write!(s, "{} {}.{:09}", currency_code, units, nanos)?;
+        escape_html(currency_code, s);
+        write!(s, " {}.{:09}", units, nanos)?;
         Ok(())
     });
 
-    tt
+    Ok(tt)
+}
+
+// Escaping is chosen by where a value is interpolated:
+//
+// * `{x}` is HTML-escaped, which is safe both in text and in quoted attribute
+//   values. Every attribute in the templates must be quoted.
+// * `{x | url}` is for a whole URL in an `href`. Only relative URLs and
+//   allow-listed schemes get through; anything else becomes `#`.
+// * `{x | path}` is for a single path segment inside a URL, such as a product
+//   ID, and is percent-encoded.
+//
+// The stock `unescaped` formatter is replaced with one that always fails.
+
+const ALLOWED_URL_SCHEMES: [&str; 3] = ["http", "https", "mailto"];
+
+/// Replacement for URLs that fail the allow-list.
+const BLOCKED_URL: &str = "#";
+
+fn escape_html(value: &str, out: &mut String) {
+    for c in value.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            '`' => out.push_str("&#96;"),
+            c => out.push(c),
+        }
+    }
+}
+
+fn format_html(val: &Value, out: &mut String) -> Result<(), Error> {
+    match val {
+        Value::String(x) => {
+            escape_html(x, out);
+            Ok(())
+        }
+        _ => tinytemplate::format(val, out),
+    }
+}
+
+/// Returns the URL if it is relative or uses an allow-listed scheme.
+fn safe_url(url: &str) -> Option<&str> {
+    let url = url.trim();
+    // Browsers strip tabs and newlines inside URLs and treat `\` like `/`,
+    // both of which can be used to disguise a scheme or host.
+    if url.chars().any(|c| c.is_control() || c == '\\') {
+        return None;
+    }
+    // Protocol-relative URLs point at another host.
+    if url.starts_with("//") {
+        return None;
+    }
+    let scheme = match url.find(':') {
+        Some(i) => &url[..i],
+        None => return Some(url),
+    };
+    // A colon after the first `/`, `?` or `#` is part of a relative URL.
+    if scheme.contains(['/', '?', '#']) {
+        return Some(url);
+    }
+    ALLOWED_URL_SCHEMES
+        .iter()
+        .any(|x| scheme.eq_ignore_ascii_case(x))
+        .then_some(url)
+}
+
+fn format_url(val: &Value, out: &mut String) -> Result<(), Error> {
+    let url = match val {
+        Value::String(x) => x,
+        Value::Null => return Ok(()),
+        _ => return format_html(val, out),
+    };
+    match safe_url(url) {
+        Some(url) => escape_html(url, out),
+        None => {
+            log::warn!("blocked unsafe URL in template: {:?}", url);
+            out.push_str(BLOCKED_URL);
+        }
+    }
+    Ok(())
+}
+
+fn format_path(val: &Value, out: &mut String) -> Result<(), Error> {
+    let segment = match val {
+        Value::String(x) => x,
+        _ => return format_html(val, out),
+    };
+    for b in segment.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                out.push(b as char)
+            }
+            _ => write!(out, "%{:02X}", b)?,
+        }
+    }
+    Ok(())
+}
+
+/// Template sources read from disk, by name.
+type Sources = Arc<Vec<(&'static str, String)>>;
+
+struct DevRegistry {
+    dir: PathBuf,
+    state: Mutex<Option<(SystemTime, Sources)>>,
+}
+
+impl DevRegistry {
+    fn path(&self, name: &str) -> PathBuf {
+        self.dir.join(format!("{}.html", name))
+    }
+
+    fn last_modified(&self) -> Option<SystemTime> {
+        TEMPLATES
+            .iter()
+            .filter_map(|(name, _)| fs::metadata(self.path(name)).ok()?.modified().ok())
+            .max()
+    }
+
+    fn load(&self) -> Result<Sources, Error> {
+        let mut sources = Vec::new();
+        for (name, _) in TEMPLATES.iter() {
+            let path = self.path(name);
+            let text = fs::read_to_string(&path).map_err(|e| Error::GenericError {
+                msg: format!("failed to read {}: {}", path.display(), e),
+            })?;
+            sources.push((*name, text));
+        }
+        Ok(Arc::new(sources))
+    }
+
+    /// Returns the current sources, reading them again if any file changed
+    /// since they were last read.
+    fn sources(&self) -> Result<Sources, Error> {
+        let modified = self.last_modified().unwrap_or(UNIX_EPOCH);
+        let mut state = self.state.lock().unwrap();
+        match &*state {
+            Some((loaded, sources)) if modified <= *loaded => Ok(sources.clone()),
+            _ => {
+                let sources = self.load()?;
+                *state = Some((modified, sources.clone()));
+                log::info!("reloaded templates from {}", self.dir.display());
+                Ok(sources)
+            }
+        }
+    }
+
+    /// Parses the templates afresh for every page, which is slow but only
+    /// happens in dev mode, and renders without holding the lock.
+    fn render<C: Serialize>(&self, name: &str, ctx: &C) -> Result<String, Error> {
+        let sources = self.sources()?;
+        let templates: Vec<(&str, &str)> = sources
+            .iter()
+            .map(|(name, text)| (*name, text.as_str()))
+            .collect();
+        build(&templates)?.render(name, ctx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shared::Ad;
+
+    const PAYLOADS: [&str; 10] = [
+        "<script>alert(1)</script>",
+        "\"><img src=x onerror=alert(1)>",
+        "' onmouseover='alert(1)",
+        "` onmouseover=alert(1)",
+        "javascript:alert(1)",
+        " JaVaScRiPt:alert(1)",
+        "java\tscript:alert(1)",
+        "data:text/html,<script>alert(1)</script>",
+        "//evil.example/",
+        "/\\evil.example/",
+    ];
+
+    #[derive(Serialize)]
+    struct ErrorContext<'a> {
+        header: HeaderContext<'a>,
+        footer: FooterContext<'a>,
+        base_url: &'a str,
+        title: &'a str,
+        message: &'a str,
+        request_id: &'a str,
+    }
+
+    fn money(p: &str) -> Money {
+        Money::zero(p)
+    }
+
+    fn address(p: &str) -> Address {
+        Address {
+            street_address: p.to_owned(),
+            city: p.to_owned(),
+            state: p.to_owned(),
+            country: p.to_owned(),
+            zip_code: p.to_owned(),
+        }
+    }
+
+    fn saved_address(p: &str) -> SavedAddress {
+        SavedAddress {
+            id: p.to_owned(),
+            address: address(p),
+        }
+    }
+
+    fn payment_method(p: &str) -> PaymentMethod {
+        PaymentMethod {
+            token: p.to_owned(),
+            card_type: p.to_owned(),
+            last_four: p.to_owned(),
+            expiration_year: 2030,
+            expiration_month: 1,
+        }
+    }
+
+    fn product(p: &str) -> Product {
+        Product {
+            id: p.to_owned(),
+            name: p.to_owned(),
+            description: p.to_owned(),
+            picture: p.to_owned(),
+            price_usd: money(p),
+            categories: vec![p.to_owned()],
+        }
+    }
+
+    fn order_item(p: &str) -> OrderItem {
+        OrderItem {
+            item: CartItem {
+                product_id: p.to_owned(),
+                quantity: 1,
+            },
+            cost: money(p),
+        }
+    }
+
+    fn form(p: &str) -> CheckoutForm {
+        CheckoutForm {
+            street_address: p.to_owned(),
+            city: p.to_owned(),
+            state: p.to_owned(),
+            country: p.to_owned(),
+            zip_code: p.to_owned(),
+            email: p.to_owned(),
+            credit_card_number: p.to_owned(),
+            credit_card_ccv: p.to_owned(),
+            credit_card_expiration_year: p.to_owned(),
+            credit_card_expiration_month: p.to_owned(),
+            coupon_code: p.to_owned(),
+        }
+    }
+
+    fn errors(p: &'static str) -> CheckoutFormErrors {
+        CheckoutFormErrors {
+            street_address: Some(p),
+            city: Some(p),
+            state: Some(p),
+            country: Some(p),
+            zip_code: Some(p),
+            email: Some(p),
+            credit_card_number: Some(p),
+            credit_card_ccv: Some(p),
+            credit_card_expiration: Some(p),
+        }
+    }
+
+    /// Renders every template with the payload in every string field.
+    fn render_all(p: &'static str) -> Vec<(&'static str, String)> {
+        let tt = build(&TEMPLATES).unwrap();
+        let header = || HeaderContext {
+            base_url: "",
+            csrf_token: p.to_owned(),
+            logged_in: true,
+            notices: vec![p],
+        };
+        let footer = || FooterContext { base_url: "" };
+        let render = |name: &'static str, res: Result<String, Error>| (name, res.unwrap());
+
+        vec![
+            render(
+                "home",
+                tt.render(
+                    "home",
+                    &HomeContext {
+                        header: header(),
+                        footer: footer(),
+                        base_url: "",
+                        products: vec![product(p)],
+                        recommended: vec![product(p)],
+                    },
+                ),
+            ),
+            render(
+                "product",
+                tt.render(
+                    "product",
+                    &ProductContext {
+                        header: header(),
+                        footer: footer(),
+                        base_url: "",
+                        product: product(p),
+                        in_stock: true,
+                        ads: vec![Ad::new(p, p)],
+                        csrf_token: p.to_owned(),
+                    },
+                ),
+            ),
+            render(
+                "cart",
+                tt.render(
+                    "cart",
+                    &CartContext {
+                        header: header(),
+                        footer: footer(),
+                        base_url: "",
+                        items: vec![CartItem {
+                            product_id: p.to_owned(),
+                            quantity: 1,
+                        }],
+                        form: form(p),
+                        errors: errors(p),
+                        saved_addresses: vec![saved_address(p)],
+                        payment_methods: vec![payment_method(p)],
+                        csrf_token: p.to_owned(),
+                    },
+                ),
+            ),
+            render(
+                "review",
+                tt.render(
+                    "review",
+                    &ReviewContext {
+                        header: header(),
+                        footer: footer(),
+                        base_url: "",
+                        quote: OrderQuote {
+                            items: vec![order_item(p)],
+                            shipping_cost: money(p),
+                            discounts: vec![Discount {
+                                promotion_id: p.to_owned(),
+                                description: p.to_owned(),
+                                amount: money(p),
+                            }],
+                            tax_lines: vec![TaxLine {
+                                description: p.to_owned(),
+                                amount: money(p),
+                            }],
+                            tax: money(p),
+                            total: money(p),
+                        },
+                        form: form(p),
+                        saved: Some(SavedCheckoutForm {
+                            address_id: p.to_owned(),
+                            payment_token: p.to_owned(),
+                            coupon_code: p.to_owned(),
+                        }),
+                        payment_method: Some(payment_method(p)),
+                        csrf_token: p.to_owned(),
+                    },
+                ),
+            ),
+            render(
+                "checkout",
+                tt.render(
+                    "checkout",
+                    &CheckoutContext {
+                        header: header(),
+                        footer: footer(),
+                        base_url: "",
+                        order_id: p.to_owned(),
+                        shipping_tracking_id: p.to_owned(),
+                        shipping_cost: money(p),
+                        shipping_address: address(p),
+                        items: vec![order_item(p)],
+                        discounts: vec![Discount {
+                            promotion_id: p.to_owned(),
+                            description: p.to_owned(),
+                            amount: money(p),
+                        }],
+                        tax_lines: vec![TaxLine {
+                            description: p.to_owned(),
+                            amount: money(p),
+                        }],
+                        tax: money(p),
+                        total: money(p),
+                    },
+                ),
+            ),
+            render(
+                "login",
+                tt.render(
+                    "login",
+                    &AuthContext {
+                        header: header(),
+                        footer: footer(),
+                        base_url: "",
+                        csrf_token: p.to_owned(),
+                        email: p.to_owned(),
+                        error: Some(p.to_owned()),
+                    },
+                ),
+            ),
+            render(
+                "register",
+                tt.render(
+                    "register",
+                    &AuthContext {
+                        header: header(),
+                        footer: footer(),
+                        base_url: "",
+                        csrf_token: p.to_owned(),
+                        email: p.to_owned(),
+                        error: Some(p.to_owned()),
+                    },
+                ),
+            ),
+            render(
+                "account",
+                tt.render(
+                    "account",
+                    &AccountContext {
+                        header: header(),
+                        footer: footer(),
+                        base_url: "",
+                        profile: Profile {
+                            user_id: p.to_owned(),
+                            email: p.to_owned(),
+                            addresses: vec![saved_address(p)],
+                            payment_methods: vec![payment_method(p)],
+                        },
+                    },
+                ),
+            ),
+            render(
+                "error",
+                tt.render(
+                    "error",
+                    &ErrorContext {
+                        header: header(),
+                        footer: footer(),
+                        base_url: "",
+                        title: p,
+                        message: p,
+                        request_id: p,
+                    },
+                ),
+            ),
+        ]
+    }
+
+    fn hrefs(html: &str) -> Vec<&str> {
+        html.split("href=\"")
+            .skip(1)
+            .map(|x| &x[..x.find('"').unwrap()])
+            .collect()
+    }
+
+    #[test]
+    fn every_template_is_covered() {
+        let rendered: Vec<_> = render_all(PAYLOADS[0]).into_iter().map(|x| x.0).collect();
+        for (name, _) in TEMPLATES.iter() {
+            let partial = *name == "header" || *name == "footer";
+            assert!(partial || rendered.contains(name), "{} is not tested", name);
+        }
+    }
+
+    #[test]
+    fn payloads_are_escaped_in_text_and_attributes() {
+        for p in PAYLOADS {
+            for (name, html) in render_all(p) {
+                let lower = html.to_ascii_lowercase();
+                assert!(!lower.contains("<script"), "{}: {:?}", name, p);
+                assert!(!lower.contains("<img"), "{}: {:?}", name, p);
+                if p.contains(['<', '>', '"', '\'', '`']) {
+                    assert!(!html.contains(p), "{}: {:?} unescaped", name, p);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn hrefs_only_contain_safe_urls() {
+        for p in PAYLOADS {
+            for (name, html) in render_all(p) {
+                for href in hrefs(&html) {
+                    let href = href.trim().to_ascii_lowercase();
+                    assert!(!href.contains("script:"), "{}: {:?} in {}", name, p, href);
+                    assert!(!href.starts_with("data:"), "{}: {:?} in {}", name, p, href);
+                    assert!(!href.starts_with("//"), "{}: {:?} in {}", name, p, href);
+                    assert!(!href.contains('\\'), "{}: {:?} in {}", name, p, href);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn url_allow_list() {
+        for url in [
+            "2ZYFJ3GM2N",
+            "/product/2ZYFJ3GM2N",
+            "/search?q=a:b",
+            "#top",
+            "https://example.com/",
+            "HTTP://example.com/",
+            "mailto:shop@example.com",
+        ] {
+            assert_eq!(safe_url(url), Some(url), "{}", url);
+        }
+        for url in [
+            "javascript:alert(1)",
+            "vbscript:msgbox(1)",
+            "data:text/html,x",
+            "java\nscript:alert(1)",
+            "//evil.example/",
+            "/\\evil.example/",
+        ] {
+            assert_eq!(safe_url(url), None, "{:?}", url);
+        }
+    }
+
+    #[test]
+    fn path_segments_are_percent_encoded() {
+        let mut out = String::new();
+        format_path(&Value::from("../a b/?#\"<"), &mut out).unwrap();
+        assert_eq!(out, "..%2Fa%20b%2F%3F%23%22%3C");
+    }
+
+    #[test]
+    fn unescaped_formatter_is_disabled() {
+        let mut tt = build(&TEMPLATES).unwrap();
+        tt.add_template("raw", "{x | unescaped}").unwrap();
+        let ctx = serde_json::json!({ "x": "<b>" });
+        assert!(tt.render("raw", &ctx).is_err());
+    }
 }
@@ -1,58 +1,28 @@
-use amimono::config::{AppBuilder, AppConfig, JobBuilder};
-
 pub mod backend;
 pub mod frontend;
 pub mod shared;
+pub mod topology;
 
-#[allow(unused)]
-fn configure_strict_monolith() -> AppConfig {
-    AppBuilder::new(env!("APP_REVISION"))
-        .install(amimono_haze::installer())
-        .add_job(
-            JobBuilder::new()
-                .with_label("boutique")
-                .add_component(frontend::component())
-                .add_component(backend::ad::component())
-                .add_component(backend::cart::component())
-                .add_component(backend::checkout::component())
-                .add_component(backend::currency::component())
-                .add_component(backend::email::component())
-                .add_component(backend::payment::component())
-                .add_component(backend::productcatalog::component())
-                .add_component(backend::recommendation::component())
-                .add_component(backend::shipping::component())
-                .build(),
-        )
-        .build()
-}
-
-#[allow(unused)]
-fn configure_strict_microservices() -> AppConfig {
-    AppBuilder::new(env!("APP_REVISION"))
-        .install(amimono_haze::installer())
-        .add_job(JobBuilder::new().add_component(frontend::component()))
-        .add_job(JobBuilder::new().add_component(backend::ad::component()))
-        .add_job(JobBuilder::new().add_component(backend::cart::component()))
-        .add_job(JobBuilder::new().add_component(backend::checkout::component()))
-        .add_job(JobBuilder::new().add_component(backend::currency::component()))
-        .add_job(JobBuilder::new().add_component(backend::email::component()))
-        .add_job(JobBuilder::new().add_component(backend::payment::component()))
-        .add_job(JobBuilder::new().add_component(backend::productcatalog::component()))
-        .add_job(JobBuilder::new().add_component(backend::recommendation::component()))
-        .add_job(JobBuilder::new().add_component(backend::shipping::component()))
-        .build()
-}
-
-fn configure() -> AppConfig {
-    configure_strict_microservices()
-}
+#[cfg(test)]
+mod testing;
 
 fn main() {
-    env_logger::init();
+    shared::logging::init();
+    shared::metrics::serve_from_env();
+    amimono_haze::dashboard::add_directory("caches", shared::cache::DashboardDirectory);
+    amimono_haze::dashboard::add_directory("faults", shared::faults::DashboardDirectory);
     amimono_haze::dashboard::add_directory("currency", backend::currency::DashboardDirectory);
     amimono_haze::dashboard::add_directory(
         "productcatalog",
         backend::productcatalog::DashboardDirectory,
     );
-    amimono::entry(configure());
+    let topology = topology::selected();
+    let config = match topology::configure_named(&topology) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("topology {:?}: {}", topology, e);
+            std::process::exit(2);
+        }
+    };
+    amimono::entry(config);
 }
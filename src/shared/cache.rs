@@ -0,0 +1,354 @@
+//! Read-through caches for RPC results that rarely change, such as the
+//! catalog and exchange rates. Entries expire after a TTL, and each cache
+//! holds a bounded number of them, dropping the oldest to make room.
+//!
+//! A cache can also follow a `Source`. When the catalog service starts or the
+//! rates are refreshed, the service bumps the source's generation in the haze
+//! CRDT store, and every cache following it empties itself the next time it
+//! notices, which takes at most `GENERATION_CHECK_INTERVAL`.
+//!
+//! The `caches` directory of the haze dashboard shows how well each cache in
+//! the dashboard's process is doing.
+
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    sync::{
+        Arc, LazyLock, Mutex, Weak,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use amimono::rpc::RpcResult;
+use amimono_haze::{
+    crdt::{Crdt, StoredCrdt, crdt::Max},
+    dashboard::tree,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::shared::{CrdtClient, metrics};
+
+/// How often a source's generation is looked up.
+const GENERATION_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Data that caches can follow, so they empty when it is reloaded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Source {
+    Catalog,
+    Rates,
+}
+
+impl Source {
+    fn key(self) -> &'static str {
+        match self {
+            Source::Catalog => "catalog",
+            Source::Rates => "rates",
+        }
+    }
+}
+
+/// How many times a source has been reloaded. Each reload publishes one more
+/// than the highest it knows of, so clocks that disagree between hosts can't
+/// hide a reload.
+#[derive(Serialize, Deserialize)]
+struct Generation {
+    at: Max<u64>,
+}
+
+impl Crdt for Generation {
+    fn merge_from(&mut self, other: Self) {
+        self.at.merge_from(other.at);
+    }
+}
+
+impl StoredCrdt for Generation {}
+
+impl Default for Generation {
+    fn default() -> Self {
+        Self { at: Max(0) }
+    }
+}
+
+/// When a source's generation was last looked up, and what it was.
+type Seen = (Option<Instant>, u64);
+
+static GENERATIONS: LazyLock<Mutex<HashMap<Source, Seen>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+fn generations() -> CrdtClient<Generation> {
+    CrdtClient::new("cache_generations".to_owned())
+}
+
+/// Tells every cache following `source` that it has been reloaded.
+pub async fn reloaded(source: Source) {
+    let last = GENERATIONS
+        .lock()
+        .unwrap()
+        .get(&source)
+        .map_or(0, |(_, at)| *at);
+    let res: RpcResult<u64> = async {
+        let stored = generations().get_or_default(source.key()).await?;
+        let at = stored.at.0.max(last) + 1;
+        generations()
+            .put(source.key(), Generation { at: Max(at) })
+            .await?;
+        Ok(at)
+    }
+    .await;
+    let at = res.unwrap_or_else(|e| {
+        log::warn!("failed to publish reload of {}: {:?}", source.key(), e);
+        last + 1
+    });
+    GENERATIONS
+        .lock()
+        .unwrap()
+        .insert(source, (Some(Instant::now()), at));
+}
+
+/// The current generation of `source`, looked up at most once per
+/// `GENERATION_CHECK_INTERVAL`. If it can't be looked up, the last one seen
+/// stands.
+async fn generation(source: Source) -> u64 {
+    let last = {
+        let mut seen = GENERATIONS.lock().unwrap();
+        let (checked, at) = seen.entry(source).or_insert((None, 0));
+        if checked.is_some_and(|x| x.elapsed() < GENERATION_CHECK_INTERVAL) {
+            return *at;
+        }
+        // Let other callers use what we have while this one looks.
+        *checked = Some(Instant::now());
+        *at
+    };
+    let res: RpcResult<Generation> =
+        async { Ok(generations().get_or_default(source.key()).await?) }.await;
+    match res {
+        Ok(generation) => {
+            let at = generation.at.0.max(last);
+            GENERATIONS
+                .lock()
+                .unwrap()
+                .insert(source, (Some(Instant::now()), at));
+            at
+        }
+        Err(e) => {
+            log::warn!("failed to look up generation of {}: {:?}", source.key(), e);
+            last
+        }
+    }
+}
+
+struct Entry<V> {
+    value: V,
+    inserted: Instant,
+}
+
+struct State<K, V> {
+    entries: HashMap<K, Entry<V>>,
+    generation: u64,
+}
+
+struct Inner<K, V> {
+    name: &'static str,
+    source: Option<Source>,
+    capacity: usize,
+    ttl: Duration,
+    state: Mutex<State<K, V>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+/// A read-through cache. Clones share their entries.
+pub struct Cache<K, V> {
+    inner: Arc<Inner<K, V>>,
+}
+
+impl<K, V> Clone for Cache<K, V> {
+    fn clone(&self) -> Self {
+        Cache {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<K, V> Cache<K, V>
+where
+    K: Hash + Eq + Clone + Send + 'static,
+    V: Clone + Send + 'static,
+{
+    /// A cache holding up to `capacity` entries for `ttl` each, emptied
+    /// whenever `source`, if given, is reloaded. `name` is what it goes by
+    /// on the dashboard and in metrics.
+    pub fn new(
+        name: &'static str,
+        source: Option<Source>,
+        capacity: usize,
+        ttl: Duration,
+    ) -> Cache<K, V> {
+        let inner = Arc::new(Inner {
+            name,
+            source,
+            capacity,
+            ttl,
+            state: Mutex::new(State {
+                entries: HashMap::new(),
+                generation: 0,
+            }),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        });
+        let weak: Weak<dyn Stats> = Arc::downgrade(&inner) as Weak<dyn Stats>;
+        REGISTRY.lock().unwrap().insert(name, weak);
+        Cache { inner }
+    }
+
+    /// Returns the value cached for `key`, or loads it with `load` and caches
+    /// it. Errors are passed on and not cached.
+    pub async fn get_or_load<F>(&self, key: K, load: impl FnOnce() -> F) -> RpcResult<V>
+    where
+        F: Future<Output = RpcResult<V>>,
+    {
+        let generation = match self.inner.source {
+            Some(source) => generation(source).await,
+            None => 0,
+        };
+        let cached = {
+            let mut state = self.inner.state.lock().unwrap();
+            if state.generation != generation {
+                state.entries.clear();
+                state.generation = generation;
+            }
+            state
+                .entries
+                .get(&key)
+                .filter(|x| x.inserted.elapsed() < self.inner.ttl)
+                .map(|x| x.value.clone())
+        };
+        if let Some(value) = cached {
+            self.inner.hits.fetch_add(1, Ordering::Relaxed);
+            metrics::cache_lookup(self.inner.name, "hit");
+            return Ok(value);
+        }
+        self.inner.misses.fetch_add(1, Ordering::Relaxed);
+        metrics::cache_lookup(self.inner.name, "miss");
+
+        let value = load().await?;
+        let mut state = self.inner.state.lock().unwrap();
+        if state.generation == generation {
+            self.insert(&mut state, key, value.clone());
+        }
+        Ok(value)
+    }
+
+    fn insert(&self, state: &mut State<K, V>, key: K, value: V) {
+        if state.entries.len() >= self.inner.capacity {
+            state
+                .entries
+                .retain(|_, x| x.inserted.elapsed() < self.inner.ttl);
+        }
+        if state.entries.len() >= self.inner.capacity {
+            let oldest = state
+                .entries
+                .iter()
+                .min_by_key(|(_, x)| x.inserted)
+                .map(|(k, _)| k.clone());
+            if let Some(oldest) = oldest {
+                state.entries.remove(&oldest);
+            }
+        }
+        let entry = Entry {
+            value,
+            inserted: Instant::now(),
+        };
+        state.entries.insert(key, entry);
+    }
+}
+
+/// What the dashboard shows of a cache.
+trait Stats: Send + Sync {
+    fn stats(&self) -> serde_json::Value;
+}
+
+impl<K: Send, V: Send> Stats for Inner<K, V> {
+    fn stats(&self) -> serde_json::Value {
+        let hits = self.hits.load(Ordering::Relaxed);
+        let misses = self.misses.load(Ordering::Relaxed);
+        let lookups = hits + misses;
+        json!({
+            "hits": hits,
+            "misses": misses,
+            "hit_rate": match lookups {
+                0 => 0.0,
+                _ => hits as f64 / lookups as f64,
+            },
+            "entries": self.state.lock().unwrap().entries.len(),
+            "capacity": self.capacity,
+            "ttl_secs": self.ttl.as_secs_f64(),
+            "source": self.source.map(Source::key),
+        })
+    }
+}
+
+/// The caches of this process by name. The newest cache of a name wins.
+static REGISTRY: LazyLock<Mutex<HashMap<&'static str, Weak<dyn Stats>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+pub struct DashboardDirectory;
+
+impl tree::Directory for DashboardDirectory {
+    async fn list(&self) -> tree::TreeResult<Vec<tree::DirEntry>> {
+        let mut registry = REGISTRY.lock().unwrap();
+        registry.retain(|_, x| x.strong_count() > 0);
+        let mut names: Vec<_> = registry.keys().copied().collect();
+        names.sort();
+        Ok(names.into_iter().map(tree::DirEntry::item).collect())
+    }
+
+    async fn open_dir(&self, _name: &str) -> tree::TreeResult<tree::BoxDirectory> {
+        Err(tree::TreeError::NotFound)
+    }
+
+    async fn open_item(&self, name: &str) -> tree::TreeResult<tree::Item> {
+        let cache = REGISTRY.lock().unwrap().get(name).and_then(Weak::upgrade);
+        let stats = cache.ok_or(tree::TreeError::NotFound)?.stats();
+        match serde_json::to_string_pretty(&stats) {
+            Ok(s) => Ok(tree::Item::new(s)),
+            Err(e) => Err(tree::TreeError::Other(e.to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shared::ErrorKind;
+
+    const TTL: Duration = Duration::from_secs(60);
+
+    #[tokio::test]
+    async fn caches_values_but_not_errors() {
+        let cache: Cache<u32, u32> = Cache::new("test.values", None, 2, TTL);
+        let res = cache
+            .get_or_load(1, || async { Err(ErrorKind::Unavailable.error("down")) })
+            .await;
+        assert!(res.is_err());
+        assert_eq!(cache.get_or_load(1, || async { Ok(10) }).await.unwrap(), 10);
+        assert_eq!(cache.get_or_load(1, || async { Ok(11) }).await.unwrap(), 10);
+        assert_eq!(cache.inner.hits.load(Ordering::Relaxed), 1);
+        assert_eq!(cache.inner.misses.load(Ordering::Relaxed), 2);
+
+        // Past capacity, the oldest entry makes way.
+        cache.get_or_load(2, || async { Ok(20) }).await.unwrap();
+        cache.get_or_load(3, || async { Ok(30) }).await.unwrap();
+        assert_eq!(cache.get_or_load(1, || async { Ok(12) }).await.unwrap(), 12);
+    }
+
+    #[tokio::test]
+    async fn reloading_the_source_empties_the_cache() {
+        let cache: Cache<(), u32> = Cache::new("test.reload", Some(Source::Rates), 1, TTL);
+        cache.get_or_load((), || async { Ok(1) }).await.unwrap();
+        reloaded(Source::Rates).await;
+        assert_eq!(cache.get_or_load((), || async { Ok(2) }).await.unwrap(), 2);
+    }
+}
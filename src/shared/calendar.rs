@@ -0,0 +1,26 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The current time in whole seconds since the epoch, as kept in stored
+/// timestamps.
+pub fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Returns the current UTC year and month, for checking card expiry dates.
+pub fn current_year_month() -> (i32, i32) {
+    let secs = now_secs();
+    // Convert days since the epoch to a civil date. See
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = (secs / 86_400) as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year as i32, month as i32)
+}
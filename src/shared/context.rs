@@ -0,0 +1,66 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+
+use crate::shared::{logging, trace};
+
+/// The first argument of every RPC. It carries what the caller knows about
+/// the request being served into the handler, so the handler's work shows up
+/// as part of the same trace and its logs can be found by the same IDs.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct RpcContext {
+    /// W3C `traceparent` of the caller's span, if it has one.
+    #[serde(default)]
+    pub traceparent: Option<String>,
+    /// ID of the frontend request this call is made on behalf of.
+    #[serde(default)]
+    pub request_id: Option<String>,
+    #[serde(default)]
+    pub user_id: Option<String>,
+    #[serde(default)]
+    pub order_id: Option<String>,
+    /// How long the caller will wait, in milliseconds from when the call was
+    /// made. It is relative so that the two hosts' clocks needn't agree.
+    #[serde(default)]
+    pub deadline_ms: Option<u64>,
+}
+
+tokio::task_local! {
+    static DEADLINE: Instant;
+}
+
+/// Returns when whoever is waiting on the current task gives up, if anyone
+/// said.
+pub fn current_deadline() -> Option<Instant> {
+    DEADLINE.try_with(|x| *x).ok()
+}
+
+/// Runs `fut` with `deadline` as its deadline, unless the current one is
+/// sooner. Calls made from `fut` pass the deadline on in their `RpcContext`.
+pub async fn with_deadline<F: Future>(deadline: Instant, fut: F) -> F::Output {
+    let deadline = current_deadline().map_or(deadline, |x| x.min(deadline));
+    DEADLINE.scope(deadline, fut).await
+}
+
+impl RpcContext {
+    /// Returns the context to pass along with a call made from the current
+    /// task.
+    pub fn current() -> RpcContext {
+        let log = logging::current();
+        RpcContext {
+            traceparent: trace::current().map(|x| x.traceparent()),
+            request_id: log.request_id,
+            user_id: log.user_id,
+            order_id: log.order_id,
+            deadline_ms: current_deadline()
+                .map(|x| x.saturating_duration_since(Instant::now()).as_millis() as u64),
+        }
+    }
+
+    /// The caller's deadline, measured from now.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline_ms
+            .map(|ms| Instant::now() + Duration::from_millis(ms))
+    }
+}
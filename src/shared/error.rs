@@ -0,0 +1,84 @@
+use std::fmt;
+
+use amimono::rpc::RpcError;
+
+/// Broad classes of RPC failure. Services tag the message of an
+/// `RpcError::Misc` with one of these so that callers can tell a missing
+/// product from an outage without matching on free-form text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    NotFound,
+    InvalidArgument,
+    /// Asked for more of a product than is left.
+    OutOfStock,
+    PaymentDeclined,
+    Unavailable,
+    Internal,
+}
+
+const KINDS: [ErrorKind; 6] = [
+    ErrorKind::NotFound,
+    ErrorKind::InvalidArgument,
+    ErrorKind::OutOfStock,
+    ErrorKind::PaymentDeclined,
+    ErrorKind::Unavailable,
+    ErrorKind::Internal,
+];
+
+impl ErrorKind {
+    fn tag(self) -> &'static str {
+        match self {
+            ErrorKind::NotFound => "[not_found] ",
+            ErrorKind::InvalidArgument => "[invalid_argument] ",
+            ErrorKind::OutOfStock => "[out_of_stock] ",
+            ErrorKind::PaymentDeclined => "[payment_declined] ",
+            ErrorKind::Unavailable => "[unavailable] ",
+            ErrorKind::Internal => "[internal] ",
+        }
+    }
+
+    /// A stable name for the kind, used where errors leave the store as data
+    /// rather than as a page.
+    pub fn code(self) -> &'static str {
+        match self {
+            ErrorKind::NotFound => "not_found",
+            ErrorKind::InvalidArgument => "invalid_argument",
+            ErrorKind::OutOfStock => "out_of_stock",
+            ErrorKind::PaymentDeclined => "payment_declined",
+            ErrorKind::Unavailable => "unavailable",
+            ErrorKind::Internal => "internal",
+        }
+    }
+
+    /// The kind with the given `code`.
+    pub fn from_code(code: &str) -> Option<ErrorKind> {
+        KINDS.into_iter().find(|k| k.code() == code)
+    }
+
+    pub fn error<S: fmt::Display>(self, msg: S) -> RpcError {
+        RpcError::Misc(format!("{}{}", self.tag(), msg))
+    }
+
+    /// Untagged `Misc` errors are treated as internal errors, and any other
+    /// `RpcError` is assumed to mean the service could not be reached.
+    pub fn of(err: &RpcError) -> ErrorKind {
+        match err {
+            RpcError::Misc(msg) => KINDS
+                .into_iter()
+                .find(|k| msg.starts_with(k.tag()))
+                .unwrap_or(ErrorKind::Internal),
+            _ => ErrorKind::Unavailable,
+        }
+    }
+
+    /// Returns the message of an error with its tag removed.
+    pub fn message(err: &RpcError) -> String {
+        match err {
+            RpcError::Misc(msg) => {
+                let tag = ErrorKind::of(err).tag();
+                msg.strip_prefix(tag).unwrap_or(msg).to_owned()
+            }
+            _ => format!("{:?}", err),
+        }
+    }
+}
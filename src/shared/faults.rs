@@ -0,0 +1,421 @@
+//! Fault injection, for rehearsing failures such as shipping failing after
+//! payment went through. Every RPC handler method runs through `serve_rpc`,
+//! which asks this module whether to delay the call or fail it before the
+//! handler runs.
+//!
+//! Rules come from `BOUTIQUE_FAULTS`, read at startup, and from the file
+//! named by `BOUTIQUE_FAULTS_FILE`, which is re-read whenever it changes so
+//! faults can be switched on and off while the app runs. Each process reads
+//! its own, so in a multi-job topology every job needs the variables set.
+//!
+//! The `faults` directory of the haze dashboard shows the rules in force in
+//! the dashboard's process and how often each has fired, and changes them
+//! by writing the file: opening `add/<component>/<method>/<faults>` adds a
+//! rule, and opening `clear` removes every rule the file holds. Processes
+//! sharing the file pick the change up within `RELOAD_INTERVAL`. A rule is a
+//! target followed by the faults to inject:
+//!
+//! ```text
+//! shippingservice/ship_order error=1
+//! paymentservice/* latency=300ms error=0.1 kind=internal
+//! ```
+//!
+//! Targets are `component/method`, and either half may be `*`. `latency`
+//! delays every matching call, `error` is the fraction of them to fail, and
+//! `kind` is the error kind they fail with, `unavailable` unless given.
+//! Rules are separated by newlines or `;`, and `#` starts a comment. When
+//! more than one rule matches a call, the first wins, with `BOUTIQUE_FAULTS`
+//! coming before the file.
+
+use std::{
+    fmt,
+    path::PathBuf,
+    sync::{
+        Arc, LazyLock, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use amimono::rpc::RpcResult;
+use amimono_haze::dashboard::tree;
+use serde_json::json;
+
+use crate::{
+    shared::{ErrorKind, metrics},
+    topology,
+};
+
+/// How often the rules file is checked for changes.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Faults the dashboard offers for each target. Others can be added by
+/// opening an item named after them.
+const PRESETS: [&str; 4] = ["error=1", "error=0.1", "latency=300ms", "latency=2s"];
+
+pub struct Rule {
+    component: String,
+    method: String,
+    latency: Duration,
+    error_rate: f64,
+    kind: ErrorKind,
+    fired: AtomicU64,
+}
+
+impl Rule {
+    fn parse(text: &str) -> Result<Rule, String> {
+        let mut words = text.split_whitespace();
+        let target = words.next().unwrap_or_default();
+        let (component, method) = target
+            .split_once('/')
+            .ok_or_else(|| format!("expected component/method, got {:?}", target))?;
+        let mut rule = Rule {
+            component: component.to_owned(),
+            method: method.to_owned(),
+            latency: Duration::ZERO,
+            error_rate: 0.0,
+            kind: ErrorKind::Unavailable,
+            fired: AtomicU64::new(0),
+        };
+        for word in words {
+            let (key, value) = word
+                .split_once('=')
+                .ok_or_else(|| format!("expected key=value, got {:?}", word))?;
+            match key {
+                "latency" => rule.latency = parse_latency(value)?,
+                "error" => {
+                    rule.error_rate = value
+                        .parse()
+                        .ok()
+                        .filter(|x| (0.0..=1.0).contains(x))
+                        .ok_or_else(|| format!("error must be between 0 and 1, got {:?}", value))?
+                }
+                "kind" => {
+                    rule.kind = ErrorKind::from_code(value)
+                        .ok_or_else(|| format!("unknown error kind {:?}", value))?
+                }
+                _ => return Err(format!("unknown fault {:?}", key)),
+            }
+        }
+        Ok(rule)
+    }
+
+    fn matches(&self, component: &str, method: &str) -> bool {
+        (self.component == "*" || self.component == component)
+            && (self.method == "*" || self.method == method)
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.component, self.method)?;
+        if !self.latency.is_zero() {
+            write!(f, " latency={}ms", self.latency.as_millis())?;
+        }
+        if self.error_rate > 0.0 {
+            write!(f, " error={} kind={}", self.error_rate, self.kind.code())?;
+        }
+        Ok(())
+    }
+}
+
+fn parse_latency(s: &str) -> Result<Duration, String> {
+    let invalid = || format!("expected a latency like 250ms or 2s, got {:?}", s);
+    if let Some(ms) = s.strip_suffix("ms") {
+        return ms.parse().map(Duration::from_millis).map_err(|_| invalid());
+    }
+    let secs = s.strip_suffix('s').ok_or_else(invalid)?;
+    secs.parse::<f64>()
+        .ok()
+        .and_then(|x| Duration::try_from_secs_f64(x).ok())
+        .ok_or_else(invalid)
+}
+
+/// Parses a list of rules.
+pub fn parse(spec: &str) -> Result<Vec<Arc<Rule>>, String> {
+    spec.split(['\n', ';'])
+        .map(|line| line.split('#').next().unwrap_or_default().trim())
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            Rule::parse(line)
+                .map(Arc::new)
+                .map_err(|e| format!("{}: {}", line, e))
+        })
+        .collect()
+}
+
+struct RulesFile {
+    path: PathBuf,
+    /// When the file was last checked, its modification time then, and the
+    /// rules it held.
+    state: Mutex<(Instant, SystemTime, Vec<Arc<Rule>>)>,
+}
+
+impl RulesFile {
+    fn new(path: PathBuf) -> RulesFile {
+        RulesFile {
+            path,
+            state: Mutex::new((
+                Instant::now()
+                    .checked_sub(RELOAD_INTERVAL)
+                    .unwrap_or_else(Instant::now),
+                UNIX_EPOCH,
+                Vec::new(),
+            )),
+        }
+    }
+
+    /// Returns the rules in the file, re-reading it if it has changed. A file
+    /// that doesn't parse leaves the previous rules in force, and a missing
+    /// file means no rules.
+    fn rules(&self) -> Vec<Arc<Rule>> {
+        let mut state = self.state.lock().unwrap();
+        let (checked, loaded, rules) = &mut *state;
+        if checked.elapsed() < RELOAD_INTERVAL {
+            return rules.clone();
+        }
+        *checked = Instant::now();
+        let modified = std::fs::metadata(&self.path)
+            .and_then(|x| x.modified())
+            .unwrap_or(UNIX_EPOCH);
+        if modified == *loaded {
+            return rules.clone();
+        }
+        let text = std::fs::read_to_string(&self.path).unwrap_or_default();
+        match parse(&text) {
+            Ok(new) => {
+                log::info!(
+                    "loaded {} fault rules from {}",
+                    new.len(),
+                    self.path.display()
+                );
+                *rules = new;
+                *loaded = modified;
+            }
+            Err(e) => log::warn!("ignoring {}: {}", self.path.display(), e),
+        }
+        rules.clone()
+    }
+
+    /// Appends a rule to the file, if it parses.
+    fn add(&self, line: &str) -> Result<(), String> {
+        Rule::parse(line)?;
+        let mut text = std::fs::read_to_string(&self.path).unwrap_or_default();
+        if !text.is_empty() && !text.ends_with('\n') {
+            text.push('\n');
+        }
+        text.push_str(line);
+        text.push('\n');
+        self.write(&text)
+    }
+
+    /// Replaces what the file holds, and has the next lookup re-read it
+    /// whatever its modification time says.
+    fn write(&self, text: &str) -> Result<(), String> {
+        std::fs::write(&self.path, text)
+            .map_err(|e| format!("cannot write {}: {}", self.path.display(), e))?;
+        let mut state = self.state.lock().unwrap();
+        state.0 = Instant::now()
+            .checked_sub(RELOAD_INTERVAL)
+            .unwrap_or_else(Instant::now);
+        state.1 = UNIX_EPOCH;
+        Ok(())
+    }
+}
+
+struct Faults {
+    env: Vec<Arc<Rule>>,
+    file: Option<RulesFile>,
+}
+
+impl Faults {
+    fn from_env() -> Faults {
+        let env = match std::env::var("BOUTIQUE_FAULTS") {
+            Ok(spec) => parse(&spec).unwrap_or_else(|e| panic!("invalid BOUTIQUE_FAULTS: {}", e)),
+            Err(_) => Vec::new(),
+        };
+        if !env.is_empty() {
+            log::warn!("injecting faults from BOUTIQUE_FAULTS");
+        }
+        let file = std::env::var("BOUTIQUE_FAULTS_FILE")
+            .ok()
+            .map(|path| RulesFile::new(PathBuf::from(path)));
+        Faults { env, file }
+    }
+
+    /// Every rule in force, with where it came from.
+    fn rules(&self) -> Vec<(&'static str, Arc<Rule>)> {
+        let mut rules: Vec<_> = self.env.iter().map(|x| ("env", x.clone())).collect();
+        if let Some(file) = &self.file {
+            rules.extend(file.rules().into_iter().map(|x| ("file", x)));
+        }
+        #[cfg(test)]
+        THREAD_RULES.with(|x| rules.extend(x.borrow().iter().map(|x| ("test", x.clone()))));
+        rules
+    }
+}
+
+static FAULTS: LazyLock<Faults> = LazyLock::new(Faults::from_env);
+
+#[cfg(test)]
+thread_local! {
+    static THREAD_RULES: std::cell::RefCell<Vec<Arc<Rule>>> = const { std::cell::RefCell::new(Vec::new()) };
+}
+
+/// Injects `spec` into calls handled on this thread, for tests.
+#[cfg(test)]
+pub fn inject_on_thread(spec: &str) {
+    let rules = parse(spec).unwrap();
+    THREAD_RULES.with(|x| *x.borrow_mut() = rules);
+}
+
+/// Applies the first rule matching a call, if there is one: waits out its
+/// latency, then returns the error to fail the call with, if it should fail.
+pub async fn inject(component: &'static str, method: &'static str) -> RpcResult<()> {
+    let rule = FAULTS
+        .rules()
+        .into_iter()
+        .map(|(_, rule)| rule)
+        .find(|x| x.matches(component, method));
+    let rule = match rule {
+        Some(x) => x,
+        None => return Ok(()),
+    };
+    rule.fired.fetch_add(1, Ordering::Relaxed);
+    if !rule.latency.is_zero() {
+        metrics::fault_injected(component, method, "latency");
+        tokio::time::sleep(rule.latency).await;
+    }
+    if rule.error_rate > 0.0 && rand::random::<f64>() < rule.error_rate {
+        metrics::fault_injected(component, method, "error");
+        return Err(rule
+            .kind
+            .error(format!("injected fault in {}/{}", component, method)));
+    }
+    Ok(())
+}
+
+/// The rules in force, with where each came from and how often it fired.
+fn rules_item() -> tree::TreeResult<tree::Item> {
+    let rules: Vec<_> = FAULTS
+        .rules()
+        .into_iter()
+        .map(|(source, rule)| {
+            json!({
+                "rule": rule.to_string(),
+                "source": source,
+                "fired": rule.fired.load(Ordering::Relaxed),
+            })
+        })
+        .collect();
+    match serde_json::to_string_pretty(&rules) {
+        Ok(s) => Ok(tree::Item::new(s)),
+        Err(e) => Err(tree::TreeError::Other(e.to_string())),
+    }
+}
+
+fn rules_file() -> tree::TreeResult<&'static RulesFile> {
+    FAULTS.file.as_ref().ok_or_else(|| {
+        tree::TreeError::Other("set BOUTIQUE_FAULTS_FILE to change faults here".to_owned())
+    })
+}
+
+/// Shows the fault rules in force in the dashboard's process, and changes
+/// them through the rules file.
+pub struct DashboardDirectory;
+
+impl tree::Directory for DashboardDirectory {
+    async fn list(&self) -> tree::TreeResult<Vec<tree::DirEntry>> {
+        Ok(vec![
+            tree::DirEntry::item("rules"),
+            tree::DirEntry::dir("add"),
+            tree::DirEntry::item("clear"),
+        ])
+    }
+
+    async fn open_dir(&self, name: &str) -> tree::TreeResult<tree::BoxDirectory> {
+        match name {
+            "add" => Ok(Box::new(AddDirectory { target: Vec::new() })),
+            _ => Err(tree::TreeError::NotFound),
+        }
+    }
+
+    async fn open_item(&self, name: &str) -> tree::TreeResult<tree::Item> {
+        match name {
+            "rules" => rules_item(),
+            "clear" => {
+                rules_file()?.write("").map_err(tree::TreeError::Other)?;
+                rules_item()
+            }
+            _ => Err(tree::TreeError::NotFound),
+        }
+    }
+}
+
+/// `add/<component>/<method>/<faults>`, with the component and method chosen
+/// so far. Either may be `*`, as in a rule.
+struct AddDirectory {
+    target: Vec<String>,
+}
+
+impl tree::Directory for AddDirectory {
+    async fn list(&self) -> tree::TreeResult<Vec<tree::DirEntry>> {
+        let res = match self.target.len() {
+            0 => topology::COMPONENTS
+                .iter()
+                .map(|(label, _)| *label)
+                .chain(["*"])
+                .map(tree::DirEntry::dir)
+                .collect(),
+            1 => vec![tree::DirEntry::dir("*")],
+            _ => PRESETS.into_iter().map(tree::DirEntry::item).collect(),
+        };
+        Ok(res)
+    }
+
+    async fn open_dir(&self, name: &str) -> tree::TreeResult<tree::BoxDirectory> {
+        if self.target.len() >= 2 {
+            return Err(tree::TreeError::NotFound);
+        }
+        let mut target = self.target.clone();
+        target.push(name.to_owned());
+        Ok(Box::new(AddDirectory { target }))
+    }
+
+    async fn open_item(&self, name: &str) -> tree::TreeResult<tree::Item> {
+        let [component, method] = &self.target[..] else {
+            return Err(tree::TreeError::NotFound);
+        };
+        let rule = format!("{}/{} {}", component, method, name);
+        rules_file()?.add(&rule).map_err(tree::TreeError::Other)?;
+        rules_item()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rules_added_from_the_dashboard_take_effect() {
+        let path = std::env::temp_dir().join(format!("faults-{}", uuid::Uuid::new_v4()));
+        let file = RulesFile::new(path.clone());
+        assert!(file.rules().is_empty());
+
+        file.add("shippingservice/ship_order error=1").unwrap();
+        file.add("paymentservice/* latency=300ms").unwrap();
+        assert!(file.add("paymentservice/* bogus=1").is_err());
+        let rules: Vec<_> = file.rules().iter().map(|x| x.to_string()).collect();
+        assert_eq!(
+            rules,
+            [
+                "shippingservice/ship_order error=1 kind=unavailable",
+                "paymentservice/* latency=300ms",
+            ]
+        );
+
+        file.write("").unwrap();
+        assert!(file.rules().is_empty());
+        std::fs::remove_file(path).unwrap();
+    }
+}
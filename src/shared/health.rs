@@ -0,0 +1,19 @@
+use serde::{Deserialize, Serialize};
+
+/// A service's answer to a health probe. Answering at all is what shows the
+/// service is up; the revision shows whether every component is running the
+/// same build.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Health {
+    pub component: String,
+    pub revision: String,
+}
+
+impl Health {
+    pub fn new(component: &str) -> Health {
+        Health {
+            component: component.to_owned(),
+            revision: env!("APP_REVISION").to_owned(),
+        }
+    }
+}
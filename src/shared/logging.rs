@@ -0,0 +1,82 @@
+//! Structured logging. Records are written to stderr as one JSON object per
+//! line, carrying the fields of the log context of the task that logged them:
+//! the component and RPC method being served, and the request, user and order
+//! the work is for. The frontend assigns the request ID and it travels to the
+//! backends in `RpcContext`, so grepping for it finds every line logged on
+//! behalf of one request, in every component.
+//!
+//! Filtering still follows `RUST_LOG`. Set `BOUTIQUE_LOG_FORMAT=text` for
+//! env_logger's usual human-readable lines instead.
+
+use std::{cell::RefCell, io::Write};
+
+use serde_json::{Value, json};
+
+use crate::shared::trace;
+
+#[derive(Clone, Debug, Default)]
+pub struct LogContext {
+    pub component: Option<&'static str>,
+    pub method: Option<&'static str>,
+    pub request_id: Option<String>,
+    pub user_id: Option<String>,
+    pub order_id: Option<String>,
+}
+
+tokio::task_local! {
+    static CONTEXT: RefCell<LogContext>;
+}
+
+/// Runs `fut` with `cx` as its log context.
+pub async fn scope<F: Future>(cx: LogContext, fut: F) -> F::Output {
+    CONTEXT.scope(RefCell::new(cx), fut).await
+}
+
+/// Returns the log context of the current task, which is empty outside of
+/// any scope.
+pub fn current() -> LogContext {
+    CONTEXT
+        .try_with(|cx| cx.borrow().clone())
+        .unwrap_or_default()
+}
+
+/// Sets the user for the rest of the current scope.
+pub fn set_user_id(user_id: &str) {
+    let _ = CONTEXT.try_with(|cx| cx.borrow_mut().user_id = Some(user_id.to_owned()));
+}
+
+/// Sets the order for the rest of the current scope.
+pub fn set_order_id(order_id: &str) {
+    let _ = CONTEXT.try_with(|cx| cx.borrow_mut().order_id = Some(order_id.to_owned()));
+}
+
+/// Installs the logger. Call this once, first thing in `main`.
+pub fn init() {
+    let mut builder = env_logger::Builder::from_default_env();
+    if std::env::var("BOUTIQUE_LOG_FORMAT").as_deref() != Ok("text") {
+        builder.format(|buf, record| {
+            let mut line = json!({
+                "ts": buf.timestamp_millis().to_string(),
+                "level": record.level().as_str(),
+                "target": record.target(),
+                "msg": record.args().to_string(),
+            });
+            let cx = current();
+            let fields = [
+                ("component", cx.component.map(str::to_owned)),
+                ("method", cx.method.map(str::to_owned)),
+                ("request_id", cx.request_id),
+                ("user_id", cx.user_id),
+                ("order_id", cx.order_id),
+                ("trace_id", trace::current().map(|x| x.trace_id())),
+            ];
+            for (key, value) in fields {
+                if let Some(value) = value {
+                    line[key] = Value::from(value);
+                }
+            }
+            writeln!(buf, "{}", line)
+        });
+    }
+    builder.init();
+}
@@ -0,0 +1,209 @@
+//! Prometheus metrics for the process. Every component in the process
+//! records into the same registry, which is served at `/metrics` on a port
+//! of its own; see `serve_from_env`.
+
+use std::{sync::LazyLock, time::Duration};
+
+use amimono::rpc::RpcResult;
+use prometheus::{
+    CounterVec, Encoder, HistogramVec, IntCounter, IntCounterVec, TextEncoder,
+    register_counter_vec, register_histogram_vec, register_int_counter, register_int_counter_vec,
+};
+
+use crate::shared::{ErrorKind, Money};
+
+static HTTP_REQUESTS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "boutique_http_requests_total",
+        "HTTP requests handled by the frontend.",
+        &["method", "route", "status"]
+    )
+    .unwrap()
+});
+
+static HTTP_SECONDS: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "boutique_http_request_duration_seconds",
+        "Time taken to handle HTTP requests.",
+        &["method", "route", "status"]
+    )
+    .unwrap()
+});
+
+static RPC_CALLS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "boutique_rpc_calls_total",
+        "RPC handler calls.",
+        &["component", "method"]
+    )
+    .unwrap()
+});
+
+static RPC_ERRORS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "boutique_rpc_errors_total",
+        "RPC handler calls that returned an error, by error kind.",
+        &["component", "method", "kind"]
+    )
+    .unwrap()
+});
+
+static RPC_SECONDS: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "boutique_rpc_duration_seconds",
+        "Time taken by RPC handlers.",
+        &["component", "method"]
+    )
+    .unwrap()
+});
+
+static ORDERS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "boutique_orders_placed_total",
+        "Orders placed, by currency.",
+        &["currency"]
+    )
+    .unwrap()
+});
+
+static REVENUE: LazyLock<CounterVec> = LazyLock::new(|| {
+    register_counter_vec!(
+        "boutique_revenue_total",
+        "Order totals charged, in units of each currency.",
+        &["currency"]
+    )
+    .unwrap()
+});
+
+static CART_ADDS: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "boutique_cart_adds_total",
+        "Items added to carts, counting each unit."
+    )
+    .unwrap()
+});
+
+static AD_IMPRESSIONS: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "boutique_ad_impressions_total",
+        "Ads shown on product pages."
+    )
+    .unwrap()
+});
+
+static FAULTS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "boutique_faults_injected_total",
+        "Faults injected into RPC handlers, by fault.",
+        &["component", "method", "fault"]
+    )
+    .unwrap()
+});
+
+static CLIENT_EVENTS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "boutique_rpc_client_events_total",
+        "Timeouts, retries and calls refused by an open circuit breaker, as seen by callers.",
+        &["service", "method", "event"]
+    )
+    .unwrap()
+});
+
+static CACHE_LOOKUPS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "boutique_cache_lookups_total",
+        "Lookups in read-through caches, by whether they hit.",
+        &["cache", "result"]
+    )
+    .unwrap()
+});
+
+pub fn observe_http(method: &str, route: &str, status: u16, elapsed: Duration) {
+    let status = status.to_string();
+    let labels = [method, route, status.as_str()];
+    HTTP_REQUESTS.with_label_values(&labels).inc();
+    HTTP_SECONDS
+        .with_label_values(&labels)
+        .observe(elapsed.as_secs_f64());
+}
+
+pub fn observe_rpc<T>(component: &str, method: &str, res: &RpcResult<T>, elapsed: Duration) {
+    RPC_CALLS.with_label_values(&[component, method]).inc();
+    RPC_SECONDS
+        .with_label_values(&[component, method])
+        .observe(elapsed.as_secs_f64());
+    if let Err(e) = res {
+        let kind = ErrorKind::of(e).code();
+        RPC_ERRORS
+            .with_label_values(&[component, method, kind])
+            .inc();
+    }
+}
+
+pub fn order_placed(total: &Money) {
+    let currency = total.currency_code.as_str();
+    ORDERS.with_label_values(&[currency]).inc();
+    let amount = total.units as f64 + total.nanos as f64 / 1e9;
+    // Counters can't go down, and a negative total would be a pricing bug
+    // rather than revenue anyway.
+    if amount > 0.0 {
+        REVENUE.with_label_values(&[currency]).inc_by(amount);
+    }
+}
+
+pub fn cart_added(quantity: u32) {
+    CART_ADDS.inc_by(quantity as u64);
+}
+
+pub fn ads_shown(count: usize) {
+    AD_IMPRESSIONS.inc_by(count as u64);
+}
+
+pub fn fault_injected(component: &str, method: &str, fault: &str) {
+    FAULTS.with_label_values(&[component, method, fault]).inc();
+}
+
+pub fn client_event(service: &str, method: &str, event: &str) {
+    CLIENT_EVENTS
+        .with_label_values(&[service, method, event])
+        .inc();
+}
+
+pub fn cache_lookup(cache: &str, result: &str) {
+    CACHE_LOOKUPS.with_label_values(&[cache, result]).inc();
+}
+
+/// Renders every metric in the Prometheus text format.
+pub fn render() -> String {
+    let mut buf = Vec::new();
+    if let Err(e) = TextEncoder::new().encode(&prometheus::gather(), &mut buf) {
+        log::error!("failed to encode metrics: {}", e);
+    }
+    String::from_utf8(buf).unwrap_or_default()
+}
+
+/// If `BOUTIQUE_METRICS_PORT` is set, serves `/metrics` on that port from a
+/// thread of its own. This is the only place metrics are served, so they can
+/// be kept off the public frontend port.
+pub fn serve_from_env() {
+    let Ok(port) = std::env::var("BOUTIQUE_METRICS_PORT") else {
+        return;
+    };
+    let port: u16 = port
+        .parse()
+        .unwrap_or_else(|_| panic!("BOUTIQUE_METRICS_PORT must be a port number"));
+    std::thread::spawn(move || {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async move {
+            let app = axum::Router::new().route("/metrics", axum::routing::get(async || render()));
+            let listener = tokio::net::TcpListener::bind(("0.0.0.0", port))
+                .await
+                .unwrap();
+            log::info!("serving metrics on port {}", port);
+            axum::serve(listener, app).await.unwrap();
+        });
+    });
+}
@@ -1,5 +1,31 @@
+pub mod cache;
+pub mod faults;
+pub mod logging;
+pub mod metrics;
+pub mod resilience;
+pub mod trace;
+
+mod calendar;
+mod context;
+mod error;
+mod health;
 mod money;
+mod rpc;
 mod types;
+mod validate;
 
+pub use calendar::*;
+pub use context::*;
+pub use error::*;
+pub use health::*;
 pub use money::*;
+pub use rpc::*;
 pub use types::*;
+pub use validate::*;
+
+#[cfg(test)]
+pub use crate::testing::MemoryCrdt as CrdtClient;
+/// Backends keep their data through this, which the test harness replaces
+/// with an in-memory store.
+#[cfg(not(test))]
+pub use amimono_haze::crdt::CrdtClient;
@@ -1,24 +1,46 @@
 use std::{
+    cmp::Ordering,
     fmt,
+    hash::{Hash, Hasher},
     iter::Sum,
-    ops::{Add, Mul},
+    ops::{Add, Mul, Sub},
 };
 
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
-#[derive(Clone, Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize, JsonSchema)]
 pub struct Money {
     pub currency_code: String,
     pub units: i64,
     pub nanos: i32,
 }
 
+const NANOS_PER_UNIT: i128 = 1_000_000_000;
+
 impl Money {
+    fn total_nanos(&self) -> i128 {
+        self.units as i128 * NANOS_PER_UNIT + self.nanos as i128
+    }
+
+    fn from_total_nanos(currency_code: String, total: i128) -> Money {
+        Money {
+            currency_code,
+            units: (total / NANOS_PER_UNIT) as i64,
+            nanos: (total % NANOS_PER_UNIT) as i32,
+        }
+    }
+
     fn normalize(self) -> Money {
+        let total = self.total_nanos();
+        Money::from_total_nanos(self.currency_code, total)
+    }
+
+    pub fn zero(currency_code: &str) -> Money {
         Money {
-            currency_code: self.currency_code,
-            units: self.units + (self.nanos / 1_000_000_000) as i64,
-            nanos: self.nanos % 1_000_000_000,
+            currency_code: currency_code.to_owned(),
+            units: 0,
+            nanos: 0,
         }
     }
 
@@ -30,6 +52,32 @@ impl Money {
         };
         res.normalize()
     }
+
+    /// Returns `percent`% of this amount, rounded towards zero.
+    pub fn percent(&self, percent: u32) -> Money {
+        self.basis_points(percent * 100)
+    }
+
+    /// Returns `bps` hundredths of a percent of this amount, rounded towards
+    /// zero.
+    pub fn basis_points(&self, bps: u32) -> Money {
+        let total = self.total_nanos() * bps as i128 / 10_000;
+        Money::from_total_nanos(self.currency_code.clone(), total)
+    }
+
+    /// Returns the same fraction of this amount that `part` is of `whole`,
+    /// rounded towards zero, or zero if `whole` is.
+    pub fn share(&self, part: &Money, whole: &Money) -> Money {
+        let total = match whole.total_nanos() {
+            0 => 0,
+            w => self.total_nanos() * part.total_nanos() / w,
+        };
+        Money::from_total_nanos(self.currency_code.clone(), total)
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.units == 0 && self.nanos == 0
+    }
 }
 
 impl Default for Money {
@@ -70,6 +118,46 @@ impl Add<Money> for Money {
     }
 }
 
+impl Sub<Money> for Money {
+    type Output = Money;
+    fn sub(self, rhs: Money) -> Self::Output {
+        if self.currency_code != rhs.currency_code {
+            panic!(
+                "attempted to subtract currencies of different types ({} and {})",
+                self.currency_code, rhs.currency_code
+            );
+        }
+        let total = self.total_nanos() - rhs.total_nanos();
+        Money::from_total_nanos(self.currency_code, total)
+    }
+}
+
+impl PartialEq for Money {
+    fn eq(&self, other: &Self) -> bool {
+        self.partial_cmp(other) == Some(Ordering::Equal)
+    }
+}
+
+impl Eq for Money {}
+
+/// Hashes the amount as it compares, so `1.5` and `1.500` are one key.
+impl Hash for Money {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.currency_code.hash(state);
+        self.total_nanos().hash(state);
+    }
+}
+
+impl PartialOrd for Money {
+    /// Amounts in different currencies are not comparable.
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        if self.currency_code != other.currency_code {
+            return None;
+        }
+        Some(self.total_nanos().cmp(&other.total_nanos()))
+    }
+}
+
 impl Sum<Money> for Money {
     fn sum<I: Iterator<Item = Money>>(mut it: I) -> Self {
         let mut tot = match it.next() {
@@ -86,11 +174,7 @@ impl Sum<Money> for Money {
 impl Mul<Money> for u32 {
     type Output = Money;
     fn mul(self, rhs: Money) -> Self::Output {
-        let res = Money {
-            currency_code: rhs.currency_code,
-            units: (self as i64) * rhs.units,
-            nanos: (self as i32) * rhs.nanos,
-        };
-        res.normalize()
+        let total = (self as i128) * rhs.total_nanos();
+        Money::from_total_nanos(rhs.currency_code, total)
     }
 }
@@ -0,0 +1,342 @@
+//! Client-side protection for RPC calls. Services wrap each client they hold
+//! in a `Resilient`, and make every call through it with a `Policy` saying
+//! how long an attempt may take and whether the call is safe to repeat.
+//!
+//! * Each attempt is cut off after the policy's timeout, or sooner if the
+//!   task's deadline comes first, and fails as `unavailable`. The attempt's
+//!   deadline goes along with the call, so the server stops when we do.
+//! * Idempotent calls that fail as `unavailable` are retried, after a random
+//!   wait that doubles with each attempt, so callers don't retry in lockstep.
+//!   Anything else, such as a declined card, is returned as is.
+//! * Each service gets a circuit breaker. After `BREAKER_THRESHOLD` failures
+//!   in a row it opens, and calls fail straight away for `BREAKER_COOLDOWN`
+//!   rather than piling up behind a service that is down. Then a single call
+//!   is let through to test the water, and closes the breaker if it works.
+//!
+//! Callers that can do without an answer decide what to show instead; the
+//! frontend renders the rest of the page with a notice.
+
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use amimono::rpc::RpcResult;
+
+use crate::shared::{ErrorKind, current_deadline, metrics, with_deadline};
+
+/// Consecutive failures that open a breaker.
+const BREAKER_THRESHOLD: u32 = 5;
+
+/// How long an open breaker fails calls before letting one through.
+const BREAKER_COOLDOWN: Duration = Duration::from_secs(10);
+
+/// Wait before the first retry, doubled for each one after.
+const RETRY_BACKOFF: Duration = Duration::from_millis(25);
+
+/// How a call is made.
+#[derive(Clone, Copy, Debug)]
+pub struct Policy {
+    timeout: Duration,
+    attempts: u32,
+}
+
+impl Policy {
+    /// For calls that can safely be repeated, such as reads. They get up to
+    /// three attempts.
+    pub const fn idempotent(timeout: Duration) -> Policy {
+        Policy {
+            timeout,
+            attempts: 3,
+        }
+    }
+
+    /// For calls that must not be repeated, such as charging a card, since a
+    /// timed-out attempt may still have gone through.
+    pub const fn once(timeout: Duration) -> Policy {
+        Policy {
+            timeout,
+            attempts: 1,
+        }
+    }
+
+    /// The longest a call can take, counting every attempt and the waits
+    /// between them.
+    pub const fn budget(&self) -> Duration {
+        let mut total = self.timeout.saturating_mul(self.attempts);
+        let mut retry = 1;
+        while retry < self.attempts {
+            total = total.saturating_add(RETRY_BACKOFF.saturating_mul(1 << (retry - 1)));
+            retry += 1;
+        }
+        total
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum State {
+    Closed {
+        failures: u32,
+    },
+    Open {
+        until: Instant,
+    },
+    /// One call has been let through to see if the service is back. If it
+    /// never finishes, another is let through after a cooldown.
+    HalfOpen {
+        since: Instant,
+    },
+}
+
+struct Breaker {
+    state: Mutex<State>,
+}
+
+impl Breaker {
+    fn new() -> Breaker {
+        Breaker {
+            state: Mutex::new(State::Closed { failures: 0 }),
+        }
+    }
+
+    /// Whether a call may go ahead.
+    fn admit(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        match *state {
+            State::Closed { .. } => true,
+            State::Open { until } | State::HalfOpen { since: until } if now < until => false,
+            State::Open { .. } | State::HalfOpen { .. } => {
+                *state = State::HalfOpen {
+                    since: now + BREAKER_COOLDOWN,
+                };
+                true
+            }
+        }
+    }
+
+    fn succeeded(&self) {
+        *self.state.lock().unwrap() = State::Closed { failures: 0 };
+    }
+
+    /// Records a failure, returning true if it opened the breaker.
+    fn failed(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let failures = match *state {
+            State::Closed { failures } => failures + 1,
+            _ => BREAKER_THRESHOLD,
+        };
+        *state = match failures >= BREAKER_THRESHOLD {
+            true => State::Open {
+                until: Instant::now() + BREAKER_COOLDOWN,
+            },
+            false => State::Closed { failures },
+        };
+        failures >= BREAKER_THRESHOLD
+    }
+}
+
+/// An RPC client with timeouts, retries and a circuit breaker. Clones share
+/// the breaker.
+#[derive(Clone)]
+pub struct Resilient<C> {
+    service: &'static str,
+    client: C,
+    breaker: Arc<Breaker>,
+}
+
+impl<C> Resilient<C> {
+    pub fn new(service: &'static str, client: C) -> Resilient<C> {
+        Resilient {
+            service,
+            client,
+            breaker: Arc::new(Breaker::new()),
+        }
+    }
+
+    /// The client itself, for calls that should see the service as it is,
+    /// such as readiness probes.
+    pub fn inner(&self) -> &C {
+        &self.client
+    }
+
+    /// Calls `method` as `policy` says, with `f` making one attempt.
+    pub async fn call<'a, T, F>(
+        &'a self,
+        method: &'static str,
+        policy: Policy,
+        f: impl Fn(&'a C) -> F,
+    ) -> RpcResult<T>
+    where
+        F: Future<Output = RpcResult<T>> + 'a,
+    {
+        let mut attempt = 1;
+        loop {
+            if !self.breaker.admit() {
+                metrics::client_event(self.service, method, "circuit_open");
+                return Err(ErrorKind::Unavailable.error(format!(
+                    "{} is failing, not calling it for now",
+                    self.service
+                )));
+            }
+            let deadline = tokio::time::Instant::now() + policy.timeout;
+            if current_deadline().is_some_and(|x| x <= tokio::time::Instant::now()) {
+                metrics::client_event(self.service, method, "timeout");
+                return Err(ErrorKind::Unavailable.error(format!(
+                    "{}/{} not called, the deadline has passed",
+                    self.service, method
+                )));
+            }
+            let attempt_fut = with_deadline(deadline, async {
+                // The deadline is whichever is sooner, ours or the task's.
+                let deadline = current_deadline().unwrap_or(deadline);
+                tokio::time::timeout_at(deadline, f(&self.client)).await
+            });
+            let res = match attempt_fut.await {
+                Ok(res) => res,
+                Err(_) => {
+                    metrics::client_event(self.service, method, "timeout");
+                    Err(ErrorKind::Unavailable.error(format!(
+                        "{}/{} timed out after {}ms",
+                        self.service,
+                        method,
+                        policy.timeout.as_millis()
+                    )))
+                }
+            };
+            let err = match res {
+                Err(e) if ErrorKind::of(&e) == ErrorKind::Unavailable => e,
+                res => {
+                    // Only an outage counts against the breaker; a service
+                    // saying no is still a service that works.
+                    self.breaker.succeeded();
+                    return res;
+                }
+            };
+            if self.breaker.failed() {
+                log::warn!("circuit breaker for {} opened: {:?}", self.service, err);
+            }
+            if attempt >= policy.attempts {
+                return Err(err);
+            }
+            let backoff = RETRY_BACKOFF * 2u32.pow(attempt - 1);
+            tokio::time::sleep(backoff.mul_f64(rand::random::<f64>())).await;
+            metrics::client_event(self.service, method, "retry");
+            attempt += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use super::*;
+    use crate::shared::{RpcContext, serve_rpc};
+
+    const QUICK: Duration = Duration::from_millis(50);
+
+    #[tokio::test]
+    async fn retries_idempotent_calls_only() {
+        let client = Resilient::new("test", AtomicU32::new(0));
+        let flaky = |calls: &AtomicU32| {
+            let n = calls.fetch_add(1, Ordering::Relaxed);
+            async move {
+                match n {
+                    0 => Err(ErrorKind::Unavailable.error("down")),
+                    n => Ok(n),
+                }
+            }
+        };
+        let res = client.call("m", Policy::idempotent(QUICK), flaky).await;
+        assert_eq!(res.unwrap(), 1);
+
+        client.inner().store(0, Ordering::Relaxed);
+        let res = client.call("m", Policy::once(QUICK), flaky).await;
+        assert!(res.is_err());
+        assert_eq!(client.inner().load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn other_errors_are_not_retried() {
+        let client = Resilient::new("test", AtomicU32::new(0));
+        let res: RpcResult<()> = client
+            .call("m", Policy::idempotent(QUICK), |calls| {
+                calls.fetch_add(1, Ordering::Relaxed);
+                async { Err(ErrorKind::PaymentDeclined.error("no")) }
+            })
+            .await;
+        assert_eq!(ErrorKind::of(&res.unwrap_err()), ErrorKind::PaymentDeclined);
+        assert_eq!(client.inner().load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn slow_calls_time_out() {
+        let client = Resilient::new("test", ());
+        let res = client
+            .call("m", Policy::once(QUICK), |_| async {
+                tokio::time::sleep(Duration::from_secs(5)).await;
+                Ok(())
+            })
+            .await;
+        assert_eq!(ErrorKind::of(&res.unwrap_err()), ErrorKind::Unavailable);
+    }
+
+    #[test]
+    fn budget_counts_every_attempt() {
+        let second = Duration::from_secs(1);
+        assert_eq!(Policy::once(second).budget(), second);
+        assert_eq!(
+            Policy::idempotent(second).budget(),
+            3 * second + RETRY_BACKOFF * 3
+        );
+    }
+
+    #[tokio::test]
+    async fn deadlines_travel_with_calls() {
+        let client = Resilient::new("test", ());
+        let deadline = tokio::time::Instant::now() + QUICK;
+        let sent = with_deadline(deadline, async {
+            client
+                .call("m", Policy::once(Duration::from_secs(5)), |_| async {
+                    Ok(RpcContext::current().deadline_ms)
+                })
+                .await
+        })
+        .await;
+        assert!(sent.unwrap().is_some_and(|ms| ms <= 50));
+
+        // The server gives up when the caller does.
+        let cx = RpcContext {
+            deadline_ms: Some(10),
+            ..RpcContext::default()
+        };
+        let res = serve_rpc("test", "m", cx, async {
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            Ok(())
+        })
+        .await;
+        assert_eq!(ErrorKind::of(&res.unwrap_err()), ErrorKind::Unavailable);
+    }
+
+    #[test]
+    fn breaker_opens_after_repeated_failures() {
+        let breaker = Breaker::new();
+        for _ in 1..BREAKER_THRESHOLD {
+            assert!(breaker.admit());
+            assert!(!breaker.failed());
+        }
+        assert!(breaker.failed());
+        assert!(!breaker.admit());
+
+        // Once the cooldown is over, one call goes through, and its success
+        // closes the breaker.
+        *breaker.state.lock().unwrap() = State::Open {
+            until: Instant::now(),
+        };
+        assert!(breaker.admit());
+        assert!(!breaker.admit());
+        breaker.succeeded();
+        assert!(breaker.admit());
+    }
+}
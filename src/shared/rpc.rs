@@ -0,0 +1,173 @@
+use std::time::Instant;
+
+use amimono::rpc::RpcResult;
+
+use crate::shared::{
+    ErrorKind, RpcContext, faults,
+    logging::{self, LogContext},
+    metrics,
+    trace::{Span, SpanContext, SpanKind},
+    with_deadline,
+};
+
+/// Runs the body of an RPC handler method. Every `ops::Handler` method goes
+/// through here, so anything that should apply to all of them, such as
+/// metrics, tracing, log context, fault injection and the caller's deadline,
+/// lives in one place.
+///
+/// A body still running when the caller gives up is dropped, and calls it
+/// makes carry the same deadline on, so no work is done that nobody waits
+/// for.
+pub async fn serve_rpc<T>(
+    component: &'static str,
+    method: &'static str,
+    cx: RpcContext,
+    body: impl Future<Output = RpcResult<T>>,
+) -> RpcResult<T> {
+    let parent = cx.traceparent.as_deref().and_then(SpanContext::parse);
+    let mut span = Span::start(
+        component,
+        format!("{}/{}", component, method),
+        SpanKind::Server,
+        parent,
+    );
+    span.set_attribute("rpc.service", component);
+    span.set_attribute("rpc.method", method);
+
+    let deadline = cx.deadline();
+    let log = LogContext {
+        component: Some(component),
+        method: Some(method),
+        request_id: cx.request_id,
+        user_id: cx.user_id,
+        order_id: cx.order_id,
+    };
+
+    let start = Instant::now();
+    let res = span
+        .scope(logging::scope(log, async {
+            faults::inject(component, method).await?;
+            let Some(deadline) = deadline else {
+                return body.await;
+            };
+            let expired = || {
+                ErrorKind::Unavailable.error(format!(
+                    "{}/{} ran past the caller's deadline",
+                    component, method
+                ))
+            };
+            if deadline <= tokio::time::Instant::now() {
+                return Err(expired());
+            }
+            with_deadline(deadline, tokio::time::timeout_at(deadline, body))
+                .await
+                .unwrap_or_else(|_| Err(expired()))
+        }))
+        .await;
+    metrics::observe_rpc(component, method, &res, start.elapsed());
+
+    if let Err(e) = &res {
+        span.set_error(format!(
+            "{}: {}",
+            ErrorKind::of(e).code(),
+            ErrorKind::message(e)
+        ));
+    }
+    span.end();
+    res
+}
+
+/// Declares the operations of an RPC service. It wraps `amimono::rpc_ops!`,
+/// adding what the in-process test harness needs, and nothing else differs
+/// under `cfg(test)`:
+///
+/// * `component` also records how to serve the service in-process, so the
+///   harness can boot whatever the topology lists.
+/// * The generated `Client` calls whichever handler the test registered for
+///   the service with `serve`, on the same thread, instead of going over the
+///   network. Arguments and results are still put through serde on the way,
+///   so the tests catch anything that wouldn't survive the trip.
+#[macro_export]
+macro_rules! rpc_ops {
+    ($(fn $name:ident($($arg:ident: $ty:ty),* $(,)?) -> $ret:ty;)*) => {
+        mod remote {
+            use super::*;
+
+            amimono::rpc_ops! {
+                $(fn $name($($arg: $ty),*) -> $ret;)*
+            }
+        }
+
+        pub use remote::Handler;
+
+        #[cfg(not(test))]
+        pub use remote::component;
+
+        #[cfg(not(test))]
+        pub use remote::Client;
+
+        /// `Handler` in a form that can be boxed, so a service can be served
+        /// by a fake in tests.
+        #[cfg(test)]
+        pub trait Dispatch: Send + Sync + 'static {
+            $(fn $name(
+                &self,
+                $($arg: $ty),*
+            ) -> futures::future::BoxFuture<'_, amimono::rpc::RpcResult<$ret>>;)*
+        }
+
+        #[cfg(test)]
+        impl<T: Handler> Dispatch for T {
+            $(fn $name(
+                &self,
+                $($arg: $ty),*
+            ) -> futures::future::BoxFuture<'_, amimono::rpc::RpcResult<$ret>> {
+                Box::pin(<T as Handler>::$name(self, $($arg),*))
+            })*
+        }
+
+        /// Serves the service `S` with `handler` for clients on this thread.
+        #[cfg(test)]
+        pub fn serve<S: Handler>(handler: impl Dispatch) {
+            $crate::testing::serve::<S, dyn Dispatch>(std::sync::Arc::new(handler));
+        }
+
+        #[cfg(test)]
+        pub fn component<S: Handler>(label: String) -> amimono::config::ComponentConfig {
+            $crate::testing::register(|| {
+                Box::pin(async { serve::<S>(<S as Handler>::new().await) })
+            });
+            remote::component::<S>(label)
+        }
+
+        #[cfg(test)]
+        pub struct Client<S>(std::marker::PhantomData<fn() -> S>);
+
+        #[cfg(test)]
+        impl<S> Clone for Client<S> {
+            fn clone(&self) -> Self {
+                Client(std::marker::PhantomData)
+            }
+        }
+
+        #[cfg(test)]
+        impl<S: Handler> Default for Client<S> {
+            fn default() -> Self {
+                Client(std::marker::PhantomData)
+            }
+        }
+
+        #[cfg(test)]
+        impl<S: Handler> Client<S> {
+            pub fn new() -> Self {
+                Client(std::marker::PhantomData)
+            }
+
+            $(pub async fn $name(&self, $($arg: $ty),*) -> amimono::rpc::RpcResult<$ret> {
+                let handler = $crate::testing::handler::<S, dyn Dispatch>();
+                let res = handler.$name($($crate::testing::over_wire($arg)),*).await;
+                res.map($crate::testing::over_wire)
+            })*
+        }
+    };
+}
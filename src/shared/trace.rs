@@ -0,0 +1,355 @@
+//! Spans in the W3C trace-context model. The frontend starts a span for
+//! each request, continuing the trace of an incoming `traceparent` header if
+//! there is one, and every RPC handler method runs in a child span of its
+//! caller's. The current span is kept in a task-local and passed between
+//! components in `RpcContext`.
+//!
+//! Finished spans are exported in batches to wherever is configured:
+//!
+//! * `OTEL_EXPORTER_OTLP_ENDPOINT`: an OTLP/HTTP collector, such as
+//!   `http://localhost:4318`. Spans are posted as JSON to `/v1/traces`.
+//! * `BOUTIQUE_TRACE_FILE`: a file that gets one JSON object per span
+//!   appended to it, for inspecting traces offline with `jq` and the like.
+//!
+//! With neither set, spans still get IDs and are propagated, but nothing is
+//! recorded.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    sync::{LazyLock, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde_json::{Value, json};
+
+/// How often buffered spans are exported.
+const EXPORT_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Spans beyond this many waiting for export are dropped rather than
+/// letting a stuck exporter eat memory.
+const MAX_BUFFERED_SPANS: usize = 10_000;
+
+#[derive(Clone, Copy, Debug)]
+pub struct SpanContext {
+    trace_id: u128,
+    span_id: u64,
+}
+
+impl SpanContext {
+    /// Parses a version 00 `traceparent` header.
+    pub fn parse(traceparent: &str) -> Option<SpanContext> {
+        let mut parts = traceparent.trim().split('-');
+        let (version, trace_id, span_id, flags) =
+            (parts.next()?, parts.next()?, parts.next()?, parts.next()?);
+        let hex = |x: &str, len: usize| x.len() == len && x.bytes().all(|b| b.is_ascii_hexdigit());
+        if version != "00"
+            || parts.next().is_some()
+            || !hex(trace_id, 32)
+            || !hex(span_id, 16)
+            || !hex(flags, 2)
+        {
+            return None;
+        }
+        let ctx = SpanContext {
+            trace_id: u128::from_str_radix(trace_id, 16).ok()?,
+            span_id: u64::from_str_radix(span_id, 16).ok()?,
+        };
+        (ctx.trace_id != 0 && ctx.span_id != 0).then_some(ctx)
+    }
+
+    pub fn traceparent(&self) -> String {
+        format!("00-{:032x}-{:016x}-01", self.trace_id, self.span_id)
+    }
+
+    pub fn trace_id(&self) -> String {
+        format!("{:032x}", self.trace_id)
+    }
+}
+
+tokio::task_local! {
+    static CURRENT: SpanContext;
+}
+
+/// Returns the span the current task is running in, if any.
+pub fn current() -> Option<SpanContext> {
+    CURRENT.try_with(|x| *x).ok()
+}
+
+#[derive(Clone, Copy)]
+pub enum SpanKind {
+    Internal,
+    /// Handling a request from outside the component.
+    Server,
+}
+
+impl SpanKind {
+    /// The number OTLP uses for the kind.
+    fn otlp(self) -> u8 {
+        match self {
+            SpanKind::Internal => 1,
+            SpanKind::Server => 2,
+        }
+    }
+}
+
+pub struct Span {
+    component: &'static str,
+    name: String,
+    kind: SpanKind,
+    ctx: SpanContext,
+    parent_span_id: Option<u64>,
+    start: SystemTime,
+    attributes: Vec<(&'static str, String)>,
+    error: Option<String>,
+}
+
+fn unix_nanos(t: SystemTime) -> u128 {
+    t.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or(0)
+}
+
+impl Span {
+    /// Starts a span as a child of `parent`, or of a new trace if there is no
+    /// parent.
+    pub fn start(
+        component: &'static str,
+        name: String,
+        kind: SpanKind,
+        parent: Option<SpanContext>,
+    ) -> Span {
+        let span_id = loop {
+            let x = rand::random::<u64>();
+            if x != 0 {
+                break x;
+            }
+        };
+        let trace_id = match parent {
+            Some(p) => p.trace_id,
+            None => rand::random::<u128>().max(1),
+        };
+        Span {
+            component,
+            name,
+            kind,
+            ctx: SpanContext { trace_id, span_id },
+            parent_span_id: parent.map(|p| p.span_id),
+            start: SystemTime::now(),
+            attributes: Vec::new(),
+            error: None,
+        }
+    }
+
+    pub fn context(&self) -> SpanContext {
+        self.ctx
+    }
+
+    pub fn set_attribute<V: ToString>(&mut self, key: &'static str, value: V) {
+        self.attributes.push((key, value.to_string()));
+    }
+
+    pub fn set_error<S: ToString>(&mut self, message: S) {
+        self.error = Some(message.to_string());
+    }
+
+    /// Runs `fut` with this span as the current one.
+    pub async fn scope<F: Future>(&self, fut: F) -> F::Output {
+        CURRENT.scope(self.ctx, fut).await
+    }
+
+    pub fn end(self) {
+        if let Some(exporter) = EXPORTER.as_ref() {
+            exporter.push(FinishedSpan {
+                span: self,
+                end: SystemTime::now(),
+            });
+        }
+    }
+}
+
+struct FinishedSpan {
+    span: Span,
+    end: SystemTime,
+}
+
+impl FinishedSpan {
+    fn to_line(&self) -> Value {
+        let span = &self.span;
+        json!({
+            "trace_id": span.ctx.trace_id(),
+            "span_id": format!("{:016x}", span.ctx.span_id),
+            "parent_span_id": span.parent_span_id.map(|x| format!("{:016x}", x)),
+            "component": span.component,
+            "name": span.name,
+            "start_unix_nanos": unix_nanos(span.start).to_string(),
+            "duration_ms": self
+                .end
+                .duration_since(span.start)
+                .unwrap_or_default()
+                .as_secs_f64() * 1000.0,
+            "attributes": span
+                .attributes
+                .iter()
+                .map(|(k, v)| (k.to_string(), Value::from(v.as_str())))
+                .collect::<serde_json::Map<_, _>>(),
+            "error": span.error,
+        })
+    }
+
+    fn to_otlp(&self) -> Value {
+        let span = &self.span;
+        let mut otlp = json!({
+            "traceId": span.ctx.trace_id(),
+            "spanId": format!("{:016x}", span.ctx.span_id),
+            "name": span.name,
+            "kind": span.kind.otlp(),
+            "startTimeUnixNano": unix_nanos(span.start).to_string(),
+            "endTimeUnixNano": unix_nanos(self.end).to_string(),
+            "attributes": span
+                .attributes
+                .iter()
+                .map(|(k, v)| json!({ "key": k, "value": { "stringValue": v } }))
+                .collect::<Vec<_>>(),
+            "status": match &span.error {
+                Some(message) => json!({ "code": 2, "message": message }),
+                None => json!({ "code": 1 }),
+            },
+        });
+        if let Some(parent) = span.parent_span_id {
+            otlp["parentSpanId"] = Value::from(format!("{:016x}", parent));
+        }
+        otlp
+    }
+}
+
+enum Sink {
+    File(File),
+    Otlp {
+        client: reqwest::Client,
+        url: String,
+    },
+}
+
+impl Sink {
+    async fn export(&mut self, spans: Vec<FinishedSpan>) {
+        match self {
+            Sink::File(file) => {
+                let mut buf = String::new();
+                for span in spans.iter() {
+                    buf.push_str(&span.to_line().to_string());
+                    buf.push('\n');
+                }
+                if let Err(e) = file.write_all(buf.as_bytes()) {
+                    log::warn!("failed to write {} spans: {}", spans.len(), e);
+                }
+            }
+            Sink::Otlp { client, url } => {
+                let body = otlp_request(&spans);
+                let res = client
+                    .post(url.as_str())
+                    .header(reqwest::header::CONTENT_TYPE, "application/json")
+                    .body(body.to_string())
+                    .send()
+                    .await;
+                match res.and_then(|x| x.error_for_status()) {
+                    Ok(_) => (),
+                    Err(e) => log::warn!("failed to export {} spans: {}", spans.len(), e),
+                }
+            }
+        }
+    }
+}
+
+/// Groups spans by component, since OTLP puts the service name on the
+/// resource rather than the span.
+fn otlp_request(spans: &[FinishedSpan]) -> Value {
+    let mut components: Vec<&'static str> = spans.iter().map(|x| x.span.component).collect();
+    components.sort();
+    components.dedup();
+    let resource_spans: Vec<_> = components
+        .into_iter()
+        .map(|component| {
+            json!({
+                "resource": {
+                    "attributes": [
+                        { "key": "service.name", "value": { "stringValue": component } },
+                        {
+                            "key": "service.version",
+                            "value": { "stringValue": env!("APP_REVISION") },
+                        },
+                    ],
+                },
+                "scopeSpans": [{
+                    "scope": { "name": env!("CARGO_PKG_NAME") },
+                    "spans": spans
+                        .iter()
+                        .filter(|x| x.span.component == component)
+                        .map(FinishedSpan::to_otlp)
+                        .collect::<Vec<_>>(),
+                }],
+            })
+        })
+        .collect();
+    json!({ "resourceSpans": resource_spans })
+}
+
+struct Exporter {
+    buffer: Mutex<Vec<FinishedSpan>>,
+}
+
+impl Exporter {
+    fn from_env() -> Option<Exporter> {
+        let sink = if let Ok(endpoint) = std::env::var("OTEL_EXPORTER_OTLP_ENDPOINT") {
+            Sink::Otlp {
+                client: reqwest::Client::new(),
+                url: format!("{}/v1/traces", endpoint.trim_end_matches('/')),
+            }
+        } else if let Ok(path) = std::env::var("BOUTIQUE_TRACE_FILE") {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .unwrap_or_else(|e| panic!("cannot open BOUTIQUE_TRACE_FILE {}: {}", path, e));
+            Sink::File(file)
+        } else {
+            return None;
+        };
+        std::thread::spawn(move || {
+            let rt = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+            rt.block_on(export_loop(sink));
+        });
+        Some(Exporter {
+            buffer: Mutex::new(Vec::new()),
+        })
+    }
+
+    fn push(&self, span: FinishedSpan) {
+        let mut buffer = self.buffer.lock().unwrap();
+        if buffer.len() < MAX_BUFFERED_SPANS {
+            buffer.push(span);
+        }
+    }
+
+    fn take(&self) -> Vec<FinishedSpan> {
+        std::mem::take(&mut *self.buffer.lock().unwrap())
+    }
+}
+
+static EXPORTER: LazyLock<Option<Exporter>> = LazyLock::new(Exporter::from_env);
+
+async fn export_loop(mut sink: Sink) {
+    loop {
+        tokio::time::sleep(EXPORT_INTERVAL).await;
+        let Some(exporter) = EXPORTER.as_ref() else {
+            return;
+        };
+        let spans = exporter.take();
+        if !spans.is_empty() {
+            sink.export(spans).await;
+        }
+    }
+}
@@ -1,3 +1,4 @@
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
 use crate::shared::Money;
@@ -17,45 +18,83 @@ impl Ad {
     }
 }
 
-#[derive(Clone, Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize, JsonSchema)]
 pub struct Address {
     pub street_address: String,
     pub city: String,
     pub state: String,
     pub country: String,
-    pub zip_code: i32,
+    pub zip_code: String,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct CartItem {
     pub product_id: String,
     pub quantity: u32,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct CreditCardInfo {
     pub credit_card_number: String,
-    pub credit_card_ccv: i32,
+    pub credit_card_ccv: String,
     pub credit_card_expiration_year: i32,
     pub credit_card_expiration_month: i32,
 }
 
-#[derive(Clone, Serialize, Deserialize)]
+/// A card saved by the payment service. Only enough of the card to show the
+/// user which one it is leaves the payment service.
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct PaymentMethod {
+    pub token: String,
+    pub card_type: String,
+    pub last_four: String,
+    pub expiration_year: i32,
+    pub expiration_month: i32,
+}
+
+#[derive(Clone, Serialize, Deserialize, JsonSchema)]
+pub struct Discount {
+    pub promotion_id: String,
+    pub description: String,
+    pub amount: Money,
+}
+
+#[derive(Clone, Serialize, Deserialize, JsonSchema)]
 pub struct OrderItem {
     pub item: CartItem,
     pub cost: Money,
 }
 
-#[derive(Clone, Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize, JsonSchema)]
+pub struct TaxLine {
+    pub description: String,
+    pub amount: Money,
+}
+
+#[derive(Clone, Serialize, Deserialize, JsonSchema)]
+pub struct OrderQuote {
+    pub items: Vec<OrderItem>,
+    pub shipping_cost: Money,
+    pub discounts: Vec<Discount>,
+    pub tax_lines: Vec<TaxLine>,
+    pub tax: Money,
+    pub total: Money,
+}
+
+#[derive(Clone, Serialize, Deserialize, JsonSchema)]
 pub struct OrderResult {
     pub order_id: String,
     pub shipping_tracking_id: String,
     pub shipping_cost: Money,
     pub shipping_address: Address,
     pub items: Vec<OrderItem>,
+    pub discounts: Vec<Discount>,
+    pub tax_lines: Vec<TaxLine>,
+    pub tax: Money,
+    pub total: Money,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct Product {
     pub id: String,
     pub name: String,
@@ -0,0 +1,140 @@
+//! Checks on what users type in, shared by the frontend's forms and the
+//! backends that take the same fields over RPC.
+
+pub fn is_valid_country(country: &str) -> bool {
+    (2..=56).contains(&country.len())
+        && country
+            .chars()
+            .all(|c| c.is_alphabetic() || c == ' ' || c == '-' || c == '.')
+}
+
+pub fn is_valid_postal_code(zip_code: &str) -> bool {
+    (2..=10).contains(&zip_code.len())
+        && zip_code
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == ' ' || c == '-')
+}
+
+pub fn is_valid_email(email: &str) -> bool {
+    let (local, domain) = match email.split_once('@') {
+        Some(x) => x,
+        None => return false,
+    };
+    !local.is_empty()
+        && email.len() <= 254
+        && !domain.contains('@')
+        && !email.chars().any(char::is_whitespace)
+        && domain.split('.').count() >= 2
+        && domain.split('.').all(|x| !x.is_empty())
+}
+
+/// Checks the length and Luhn checksum of a card number with separators
+/// already removed.
+pub fn is_valid_card_number(number: &str) -> bool {
+    if !(12..=19).contains(&number.len()) || !number.chars().all(|c| c.is_ascii_digit()) {
+        return false;
+    }
+    let sum: u32 = number
+        .chars()
+        .rev()
+        .filter_map(|c| c.to_digit(10))
+        .enumerate()
+        .map(|(i, d)| match (i % 2 == 1, d * 2) {
+            (true, x) if x > 9 => x - 9,
+            (true, x) => x,
+            (false, _) => d,
+        })
+        .sum();
+    sum.is_multiple_of(10)
+}
+
+/// Parses an expiration date, accepting two or four digit years, and returns
+/// `None` if it is malformed, before `today`'s month or implausibly far
+/// after it. `today` is a (year, month) such as `current_year_month()`.
+pub fn parse_expiration(year: &str, month: &str, today: (i32, i32)) -> Option<(i32, i32)> {
+    let month: i32 = month.parse().ok().filter(|m| (1..=12).contains(m))?;
+    let year: i32 = match year.len() {
+        2 => 2000 + year.parse::<i32>().ok()?,
+        4 => year.parse().ok()?,
+        _ => return None,
+    };
+    if (year, month) < today || year > today.0 + 20 {
+        return None;
+    }
+    Some((year, month))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn card_numbers() {
+        let cases = [
+            ("4432801561520454", true),
+            ("4111111111111111", true),
+            ("5555555555554444", true),
+            ("378282246310005", true),
+            ("4111111111111112", false),
+            ("5555555555554445", false),
+            // The classic Luhn example passes the checksum but is too short.
+            ("79927398713", false),
+            ("000000000000", true),
+            ("41111111111111111111", false),
+            ("4111a11111111111", false),
+            ("4111 1111 1111 1111", false),
+            ("", false),
+        ];
+        for (number, valid) in cases {
+            assert_eq!(is_valid_card_number(number), valid, "{:?}", number);
+        }
+    }
+
+    #[test]
+    fn expiration_dates() {
+        let cases = [
+            ((2026, 10), "2026", "10", Some((2026, 10))),
+            ((2026, 10), "2026", "9", None),
+            ((2026, 10), "26", "11", Some((2026, 11))),
+            ((2026, 10), "2027", "01", Some((2027, 1))),
+            ((2026, 10), "2046", "12", Some((2046, 12))),
+            ((2026, 10), "2047", "1", None),
+            ((2026, 12), "2026", "12", Some((2026, 12))),
+            ((2026, 12), "27", "1", Some((2027, 1))),
+            ((2027, 1), "2026", "12", None),
+            ((2026, 10), "2027", "0", None),
+            ((2026, 10), "2027", "13", None),
+            ((2026, 10), "2027", "", None),
+            ((2026, 10), "202", "5", None),
+            ((2026, 10), "-1", "5", None),
+            ((2026, 10), "year", "5", None),
+        ];
+        for (today, year, month, expected) in cases {
+            assert_eq!(
+                parse_expiration(year, month, today),
+                expected,
+                "{}/{} on {:?}",
+                month,
+                year,
+                today
+            );
+        }
+    }
+
+    #[test]
+    fn email_addresses() {
+        let cases = [
+            ("someone@example.com", true),
+            ("a.b+c@mail.example.co.uk", true),
+            ("someone", false),
+            ("@example.com", false),
+            ("someone@localhost", false),
+            ("someone@example..com", false),
+            ("some one@example.com", false),
+            ("a@b@example.com", false),
+        ];
+        for (email, valid) in cases {
+            assert_eq!(is_valid_email(email), valid, "{:?}", email);
+        }
+    }
+}
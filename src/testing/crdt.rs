@@ -0,0 +1,77 @@
+use std::{cell::RefCell, collections::HashMap, fmt, marker::PhantomData};
+
+use amimono::rpc::RpcError;
+use amimono_haze::crdt::Crdt;
+use serde::{Serialize, de::DeserializeOwned};
+use serde_json::Value;
+
+thread_local! {
+    static STORE: RefCell<HashMap<(String, String), Value>> = RefCell::new(HashMap::new());
+}
+
+/// Forgets everything stored on this thread.
+pub fn clear() {
+    STORE.with(|x| x.borrow_mut().clear());
+}
+
+pub struct StoreError(String);
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl From<StoreError> for RpcError {
+    fn from(e: StoreError) -> RpcError {
+        RpcError::Misc(e.to_string())
+    }
+}
+
+/// Stands in for `CrdtClient` in tests. Values are kept as JSON per store
+/// and key, and `put` merges into what is there, as the real store does.
+pub struct MemoryCrdt<T> {
+    name: String,
+    _data: PhantomData<fn() -> T>,
+}
+
+impl<T> Clone for MemoryCrdt<T> {
+    fn clone(&self) -> Self {
+        MemoryCrdt {
+            name: self.name.clone(),
+            _data: PhantomData,
+        }
+    }
+}
+
+impl<T: Crdt + Default + Serialize + DeserializeOwned> MemoryCrdt<T> {
+    pub fn new(name: String) -> MemoryCrdt<T> {
+        MemoryCrdt {
+            name,
+            _data: PhantomData,
+        }
+    }
+
+    pub async fn get_or_default(&self, key: &str) -> Result<T, StoreError> {
+        let stored = STORE.with(|x| {
+            x.borrow()
+                .get(&(self.name.clone(), key.to_owned()))
+                .cloned()
+        });
+        match stored {
+            Some(value) => serde_json::from_value(value).map_err(|e| StoreError(e.to_string())),
+            None => Ok(T::default()),
+        }
+    }
+
+    pub async fn put(&self, key: &str, value: T) -> Result<(), StoreError> {
+        let mut merged = self.get_or_default(key).await?;
+        merged.merge_from(value);
+        let value = serde_json::to_value(merged).map_err(|e| StoreError(e.to_string()))?;
+        STORE.with(|x| {
+            x.borrow_mut()
+                .insert((self.name.clone(), key.to_owned()), value)
+        });
+        Ok(())
+    }
+}
@@ -0,0 +1,133 @@
+use std::sync::{Arc, Mutex};
+
+use amimono::rpc::RpcResult;
+
+use crate::backend::{email, payment};
+use crate::shared::{
+    CreditCardInfo, ErrorKind, Health, Money, OrderResult, PaymentMethod, RpcContext,
+};
+
+/// Payment service that records what it charges and refunds, or declines
+/// everything.
+#[derive(Clone, Default)]
+pub struct FakePayment {
+    charges: Arc<Mutex<Vec<Money>>>,
+    refunds: Arc<Mutex<Vec<String>>>,
+    declining: bool,
+}
+
+impl FakePayment {
+    pub fn declining() -> FakePayment {
+        FakePayment {
+            declining: true,
+            ..FakePayment::default()
+        }
+    }
+
+    /// Amounts charged so far, oldest first.
+    pub fn charges(&self) -> Vec<Money> {
+        self.charges.lock().unwrap().clone()
+    }
+
+    /// Transactions refunded so far, oldest first.
+    pub fn refunds(&self) -> Vec<String> {
+        self.refunds.lock().unwrap().clone()
+    }
+
+    fn record(&self, amount: Money) -> RpcResult<String> {
+        if self.declining {
+            return Err(ErrorKind::PaymentDeclined.error("card was declined"));
+        }
+        let mut charges = self.charges.lock().unwrap();
+        charges.push(amount);
+        Ok(format!("fake-transaction-{}", charges.len()))
+    }
+}
+
+impl payment::ops::Handler for FakePayment {
+    async fn new() -> FakePayment {
+        FakePayment::default()
+    }
+
+    async fn charge(
+        &self,
+        _cx: RpcContext,
+        amount: Money,
+        _credit_card: CreditCardInfo,
+    ) -> RpcResult<String> {
+        self.record(amount)
+    }
+
+    async fn tokenize(
+        &self,
+        _cx: RpcContext,
+        _user_id: String,
+        credit_card: CreditCardInfo,
+    ) -> RpcResult<PaymentMethod> {
+        let number = credit_card.credit_card_number;
+        Ok(PaymentMethod {
+            token: format!("fake-token-{}", number),
+            card_type: "Card".to_owned(),
+            last_four: number[number.len().saturating_sub(4)..].to_owned(),
+            expiration_year: credit_card.credit_card_expiration_year,
+            expiration_month: credit_card.credit_card_expiration_month,
+        })
+    }
+
+    async fn charge_token(
+        &self,
+        _cx: RpcContext,
+        amount: Money,
+        _user_id: String,
+        _token: String,
+    ) -> RpcResult<String> {
+        self.record(amount)
+    }
+
+    async fn refund(
+        &self,
+        _cx: RpcContext,
+        transaction_id: String,
+        _amount: Money,
+    ) -> RpcResult<()> {
+        self.refunds.lock().unwrap().push(transaction_id);
+        Ok(())
+    }
+
+    async fn health(&self, _cx: RpcContext) -> RpcResult<Health> {
+        Ok(Health::new("paymentservice"))
+    }
+}
+
+/// Email service that keeps the confirmations it is asked to send.
+#[derive(Clone, Default)]
+pub struct FakeEmail {
+    sent: Arc<Mutex<Vec<(String, OrderResult)>>>,
+}
+
+impl FakeEmail {
+    /// Confirmations sent so far, as (address, order), oldest first.
+    pub fn sent(&self) -> Vec<(String, OrderResult)> {
+        self.sent.lock().unwrap().clone()
+    }
+}
+
+impl email::ops::Handler for FakeEmail {
+    async fn new() -> FakeEmail {
+        FakeEmail::default()
+    }
+
+    async fn send_order_confirmation(
+        &self,
+        _cx: RpcContext,
+        email: String,
+        order: OrderResult,
+    ) -> RpcResult<()> {
+        self.sent.lock().unwrap().push((email, order));
+        Ok(())
+    }
+
+    async fn health(&self, _cx: RpcContext) -> RpcResult<Health> {
+        Ok(Health::new("emailservice"))
+    }
+}
@@ -0,0 +1,25 @@
+//! Data shared by the journey tests.
+
+use std::sync::LazyLock;
+
+use crate::shared::current_year_month;
+
+/// Two years from now, so the card in the form never expires.
+static EXPIRATION_YEAR: LazyLock<String> =
+    LazyLock::new(|| (current_year_month().0 + 2).to_string());
+
+/// The card and address upstream's load generator checks out with.
+pub fn checkout_form() -> [(&'static str, &'static str); 10] {
+    [
+        ("email", "someone@example.com"),
+        ("street_address", "1600 Amphitheatre Parkway"),
+        ("zip_code", "94043"),
+        ("city", "Mountain View"),
+        ("state", "CA"),
+        ("country", "United States"),
+        ("credit_card_number", "4432-8015-6152-0454"),
+        ("credit_card_expiration_month", "1"),
+        ("credit_card_expiration_year", EXPIRATION_YEAR.as_str()),
+        ("credit_card_ccv", "672"),
+    ]
+}
@@ -0,0 +1,169 @@
+use axum::http::StatusCode;
+
+use super::{FakeEmail, FakePayment, Harness, fixtures::checkout_form};
+
+const PRODUCT: &str = "OLJCESPC7Z";
+
+async fn add_to_cart(shop: &mut Harness, quantity: &str) {
+    let page = shop.get(&format!("/product/{}", PRODUCT)).await;
+    assert_eq!(page.status, StatusCode::OK);
+    let page = shop
+        .post_form("/cart", &[("product_id", PRODUCT), ("quantity", quantity)])
+        .await;
+    assert_eq!(page.status, StatusCode::SEE_OTHER);
+    assert_eq!(page.location.as_deref(), Some("/cart"));
+}
+
+#[tokio::test]
+async fn checkout_empties_cart_and_confirms_order() {
+    let payment = FakePayment::default();
+    let email = FakeEmail::default();
+    let mut shop = Harness::builder()
+        .payment(payment.clone())
+        .email(email.clone())
+        .boot()
+        .await;
+
+    assert_eq!(shop.get("/").await.status, StatusCode::OK);
+    add_to_cart(&mut shop, "2").await;
+    let cart = shop.get("/cart").await;
+    assert!(
+        cart.body.contains(&format!("{} x2", PRODUCT)),
+        "{}",
+        cart.body
+    );
+
+    let page = shop.post_form("/cart/checkout", &checkout_form()).await;
+    assert_eq!(page.status, StatusCode::OK, "{}", page.body);
+    assert!(page.body.contains("Order Confirmation"), "{}", page.body);
+
+    let sent = email.sent();
+    assert_eq!(sent.len(), 1);
+    let (to, order) = &sent[0];
+    assert_eq!(to, "someone@example.com");
+    assert!(page.body.contains(&order.order_id));
+    assert_eq!(payment.charges().len(), 1);
+    assert_eq!(
+        payment.charges()[0].currency_code,
+        order.total.currency_code
+    );
+
+    let cart = shop.get("/cart").await;
+    assert!(!cart.body.contains(PRODUCT), "{}", cart.body);
+
+    let orders = shop.get("/api/v1/orders").await;
+    assert_eq!(orders.status, StatusCode::OK);
+    assert!(orders.body.contains(&order.order_id));
+}
+
+#[tokio::test]
+async fn reviewed_card_is_not_sent_back_to_the_browser() {
+    let payment = FakePayment::default();
+    let mut shop = Harness::builder().payment(payment.clone()).boot().await;
+    add_to_cart(&mut shop, "1").await;
+
+    let review = shop.post_form("/cart/review", &checkout_form()).await;
+    assert_eq!(review.status, StatusCode::OK, "{}", review.body);
+    assert!(!review.body.contains("4432-8015"), "{}", review.body);
+    assert!(!review.body.contains("credit_card_ccv"), "{}", review.body);
+    assert!(review.body.contains("ending in 0454"), "{}", review.body);
+
+    // What the review page posts: everything but the card.
+    let form: Vec<_> = checkout_form()
+        .into_iter()
+        .filter(|(k, _)| !k.starts_with("credit_card"))
+        .collect();
+    let page = shop.post_form("/cart/checkout", &form).await;
+    assert_eq!(page.status, StatusCode::OK, "{}", page.body);
+    assert!(page.body.contains("Order Confirmation"), "{}", page.body);
+    assert_eq!(payment.charges().len(), 1);
+}
+
+#[tokio::test]
+async fn declined_payment_keeps_cart() {
+    let email = FakeEmail::default();
+    let mut shop = Harness::builder()
+        .payment(FakePayment::declining())
+        .email(email.clone())
+        .boot()
+        .await;
+
+    add_to_cart(&mut shop, "1").await;
+    let page = shop.post_form("/cart/checkout", &checkout_form()).await;
+    assert_eq!(page.status, StatusCode::PAYMENT_REQUIRED);
+    assert!(email.sent().is_empty());
+
+    let cart = shop.get("/cart").await;
+    assert!(
+        cart.body.contains(&format!("{} x1", PRODUCT)),
+        "{}",
+        cart.body
+    );
+}
+
+#[tokio::test]
+async fn checkout_form_errors_rerender_cart() {
+    let mut shop = Harness::boot().await;
+
+    add_to_cart(&mut shop, "1").await;
+    let page = shop
+        .post_form(
+            "/cart/checkout",
+            &[
+                ("email", "not an email"),
+                ("credit_card_number", "4432-8015-6152-0454"),
+            ],
+        )
+        .await;
+    assert_eq!(page.status, StatusCode::OK);
+    assert!(page.body.contains("Enter a valid email address."));
+    assert!(!page.body.contains("Order Confirmation"));
+    assert!(!page.body.contains("4432-8015"), "{}", page.body);
+}
+
+#[tokio::test]
+async fn shipping_failure_after_payment_refunds_and_keeps_cart() {
+    let payment = FakePayment::default();
+    let email = FakeEmail::default();
+    let mut shop = Harness::builder()
+        .payment(payment.clone())
+        .email(email.clone())
+        .faults("shippingservice/ship_order error=1")
+        .boot()
+        .await;
+
+    add_to_cart(&mut shop, "1").await;
+    let page = shop.post_form("/cart/checkout", &checkout_form()).await;
+    assert_eq!(
+        page.status,
+        StatusCode::SERVICE_UNAVAILABLE,
+        "{}",
+        page.body
+    );
+    assert_eq!(payment.charges().len(), 1);
+    assert_eq!(payment.refunds(), ["fake-transaction-1"]);
+    assert!(email.sent().is_empty());
+
+    let cart = shop.get("/cart").await;
+    assert!(
+        cart.body.contains(&format!("{} x1", PRODUCT)),
+        "{}",
+        cart.body
+    );
+}
+
+#[tokio::test]
+async fn ad_outage_leaves_a_notice_on_product_page() {
+    let mut shop = Harness::builder()
+        .faults("adservice/get_ads error=1")
+        .boot()
+        .await;
+
+    let page = shop.get(&format!("/product/{}", PRODUCT)).await;
+    assert_eq!(page.status, StatusCode::OK, "{}", page.body);
+    assert!(page.body.contains("Ads are unavailable."), "{}", page.body);
+
+    let page = shop.get("/").await;
+    assert_eq!(page.status, StatusCode::OK);
+    assert!(!page.body.contains("unavailable"), "{}", page.body);
+}
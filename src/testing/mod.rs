@@ -0,0 +1,212 @@
+//! In-process harness for end-to-end tests. It boots the components
+//! `configure_strict_monolith` lists, in the test's own thread: every backend
+//! is served by its real handler (or a fake the test swaps in), RPC clients
+//! call those handlers directly, and the haze CRDT store is replaced by an
+//! in-memory one. Requests go straight into the frontend's axum router, with
+//! cookies kept between them like a browser would.
+//!
+//! The topology is built once per test binary, since building it binds each
+//! component's storage. Building a component's config records how to serve
+//! it here; see `rpc_ops!`.
+//!
+//! Everything lives in thread-locals, so each test gets a fresh app as long
+//! as it runs on a current-thread runtime, which `#[tokio::test]` does.
+
+use std::{
+    any::{Any, TypeId},
+    cell::RefCell,
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, LazyLock, Mutex},
+};
+
+use axum::{
+    Router,
+    body::{Body, to_bytes},
+    http::{Request, StatusCode, header},
+};
+use futures::future::LocalBoxFuture;
+use serde::{Serialize, de::DeserializeOwned};
+use tower::ServiceExt;
+
+use crate::backend::{email, payment};
+use crate::shared::faults;
+use crate::{frontend, topology};
+
+mod crdt;
+mod fakes;
+mod fixtures;
+mod journeys;
+
+pub use crdt::MemoryCrdt;
+pub use fakes::{FakeEmail, FakePayment};
+
+thread_local! {
+    static HANDLERS: RefCell<HashMap<TypeId, Box<dyn Any>>> = RefCell::new(HashMap::new());
+}
+
+/// Registers `handler` as the server of `S` for clients on this thread.
+/// Called through the `serve` function `rpc_ops!` generates.
+pub fn serve<S: 'static, D: ?Sized + 'static>(handler: Arc<D>) {
+    HANDLERS.with(|x| x.borrow_mut().insert(TypeId::of::<S>(), Box::new(handler)));
+}
+
+/// Returns the handler serving `S` on this thread.
+pub fn handler<S: 'static, D: ?Sized + 'static>() -> Arc<D> {
+    HANDLERS
+        .with(|x| {
+            x.borrow()
+                .get(&TypeId::of::<S>())
+                .and_then(|x| x.downcast_ref::<Arc<D>>())
+                .cloned()
+        })
+        .unwrap_or_else(|| {
+            panic!(
+                "nothing serves {} on this thread; boot a Harness first",
+                std::any::type_name::<S>()
+            )
+        })
+}
+
+/// Sends a value through JSON and back, as it would be between components.
+pub fn over_wire<T: Serialize + DeserializeOwned>(x: T) -> T {
+    let json = serde_json::to_value(x).expect("RPC value does not serialize");
+    serde_json::from_value(json).expect("RPC value does not deserialize")
+}
+
+/// Serves a component with a fresh instance of its real handler.
+type Boot = fn() -> LocalBoxFuture<'static, ()>;
+
+static REGISTERED: Mutex<Vec<Boot>> = Mutex::new(Vec::new());
+
+/// The RPC components of the monolith, in the order it lists them.
+static MONOLITH: LazyLock<Vec<Boot>> = LazyLock::new(|| {
+    topology::configure_strict_monolith();
+    std::mem::take(&mut *REGISTERED.lock().unwrap())
+});
+
+/// Records how to serve a component. Called through the `component`
+/// function `rpc_ops!` generates.
+pub fn register(boot: Boot) {
+    REGISTERED.lock().unwrap().push(boot);
+}
+
+#[derive(Default)]
+pub struct HarnessBuilder {
+    payment: Option<FakePayment>,
+    email: Option<FakeEmail>,
+    faults: String,
+}
+
+impl HarnessBuilder {
+    /// Serves the payment service with `fake` instead of the real one.
+    pub fn payment(mut self, fake: FakePayment) -> HarnessBuilder {
+        self.payment = Some(fake);
+        self
+    }
+
+    /// Serves the email service with `fake` instead of the real one.
+    pub fn email(mut self, fake: FakeEmail) -> HarnessBuilder {
+        self.email = Some(fake);
+        self
+    }
+
+    /// Injects faults into the app, written as for `BOUTIQUE_FAULTS`.
+    pub fn faults(mut self, spec: &str) -> HarnessBuilder {
+        self.faults = spec.to_owned();
+        self
+    }
+
+    pub async fn boot(self) -> Harness {
+        crdt::clear();
+        faults::inject_on_thread(&self.faults);
+        for boot in MONOLITH.iter() {
+            boot().await;
+        }
+        if let Some(fake) = self.payment {
+            payment::ops::serve::<payment::PaymentService>(fake);
+        }
+        if let Some(fake) = self.email {
+            email::ops::serve::<email::EmailService>(fake);
+        }
+        Harness {
+            router: frontend::router().await,
+            cookies: BTreeMap::new(),
+        }
+    }
+}
+
+/// A response, read in full.
+pub struct Page {
+    pub status: StatusCode,
+    pub location: Option<String>,
+    pub body: String,
+}
+
+/// The app, with one browser's worth of cookies.
+pub struct Harness {
+    router: Router,
+    cookies: BTreeMap<String, String>,
+}
+
+impl Harness {
+    pub fn builder() -> HarnessBuilder {
+        HarnessBuilder::default()
+    }
+
+    /// Boots the app with every component real.
+    pub async fn boot() -> Harness {
+        Harness::builder().boot().await
+    }
+
+    pub async fn get(&mut self, path: &str) -> Page {
+        let req = Request::get(path).body(Body::empty()).unwrap();
+        self.send(req).await
+    }
+
+    /// Submits a form the way the pages do, including the CSRF token.
+    pub async fn post_form(&mut self, path: &str, fields: &[(&str, &str)]) -> Page {
+        if !self.cookies.contains_key("BOUTIQUE_CSRF") {
+            self.get("/").await;
+        }
+        let body = form_urlencoded::Serializer::new(String::new())
+            .extend_pairs(fields)
+            .append_pair("csrf_token", &self.cookies["BOUTIQUE_CSRF"])
+            .finish();
+        let req = Request::post(path)
+            .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(Body::from(body))
+            .unwrap();
+        self.send(req).await
+    }
+
+    async fn send(&mut self, mut req: Request<Body>) -> Page {
+        let cookies = self
+            .cookies
+            .iter()
+            .map(|(k, v)| format!("{}={}", k, v))
+            .collect::<Vec<_>>()
+            .join("; ");
+        if let Ok(value) = cookies.parse() {
+            req.headers_mut().insert(header::COOKIE, value);
+        }
+
+        let res = self.router.clone().oneshot(req).await.unwrap();
+        for value in res.headers().get_all(header::SET_COOKIE) {
+            let pair = value.to_str().unwrap().split(';').next().unwrap();
+            if let Some((name, value)) = pair.split_once('=') {
+                self.cookies.insert(name.to_owned(), value.to_owned());
+            }
+        }
+        let status = res.status();
+        let location = res
+            .headers()
+            .get(header::LOCATION)
+            .map(|x| x.to_str().unwrap().to_owned());
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        Page {
+            status,
+            location,
+            body: String::from_utf8(body.to_vec()).unwrap(),
+        }
+    }
+}
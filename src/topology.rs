@@ -0,0 +1,205 @@
+//! Which components run together in which jobs. The topology is picked when
+//! the binary starts, with `--topology <name>` or `BOUTIQUE_TOPOLOGY`, so the
+//! same build can run as one process or as many. The name is either one of
+//! the built-in topologies or the path of a topology file:
+//!
+//! ```toml
+//! [[job]]
+//! label = "storefront"
+//! components = ["frontend", "adservice"]
+//!
+//! [[job]]
+//! label = "orders"
+//! components = ["checkoutservice", "paymentservice", "shippingservice"]
+//! ```
+//!
+//! Components a file doesn't mention each run in a job of their own.
+
+use std::{collections::HashSet, fmt};
+
+use amimono::config::{AppBuilder, AppConfig, ComponentConfig, JobBuilder};
+use serde::Deserialize;
+
+use crate::{backend, frontend};
+
+/// A component's label, and the function building its config. Building the
+/// config binds the component's storage, so each topology calls each
+/// constructor exactly once.
+pub type Component = (&'static str, fn() -> ComponentConfig);
+
+/// Every component in the app.
+pub const COMPONENTS: [Component; 14] = [
+    (frontend::LABEL, frontend::component),
+    (backend::ad::LABEL, backend::ad::component),
+    (backend::cart::LABEL, backend::cart::component),
+    (backend::checkout::LABEL, backend::checkout::component),
+    (backend::currency::LABEL, backend::currency::component),
+    (backend::email::LABEL, backend::email::component),
+    (backend::inventory::LABEL, backend::inventory::component),
+    (backend::payment::LABEL, backend::payment::component),
+    (
+        backend::productcatalog::LABEL,
+        backend::productcatalog::component,
+    ),
+    (backend::promotion::LABEL, backend::promotion::component),
+    (
+        backend::recommendation::LABEL,
+        backend::recommendation::component,
+    ),
+    (backend::shipping::LABEL, backend::shipping::component),
+    (backend::tax::LABEL, backend::tax::component),
+    (backend::user::LABEL, backend::user::component),
+];
+
+const DEFAULT_TOPOLOGY: &str = "microservices";
+
+#[derive(Debug)]
+pub enum TopologyError {
+    Read(String, std::io::Error),
+    Parse(String, toml::de::Error),
+    UnknownComponent(String),
+    DuplicateComponent(String),
+    MissingLabel(Vec<String>),
+}
+
+impl fmt::Display for TopologyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TopologyError::Read(path, e) => write!(f, "cannot read {}: {}", path, e),
+            TopologyError::Parse(path, e) => write!(f, "cannot parse {}: {}", path, e),
+            TopologyError::UnknownComponent(name) => write!(
+                f,
+                "unknown component {:?}; the components are: {}",
+                name,
+                component_names().join(", ")
+            ),
+            TopologyError::DuplicateComponent(name) => {
+                write!(f, "component {:?} is in more than one job", name)
+            }
+            TopologyError::MissingLabel(components) => {
+                write!(f, "the job with {} needs a label", components.join(", "))
+            }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct TopologyFile {
+    #[serde(rename = "job")]
+    jobs: Vec<JobSpec>,
+}
+
+#[derive(Deserialize)]
+struct JobSpec {
+    label: Option<String>,
+    components: Vec<String>,
+}
+
+fn component_names() -> Vec<&'static str> {
+    COMPONENTS.iter().map(|(label, _)| *label).collect()
+}
+
+fn app() -> AppBuilder {
+    AppBuilder::new(env!("APP_REVISION")).install(amimono_haze::installer())
+}
+
+/// Every component in one job.
+pub fn configure_strict_monolith() -> AppConfig {
+    let job = COMPONENTS.iter().fold(
+        JobBuilder::new().with_label("boutique"),
+        |job, (_, component)| job.add_component(component()),
+    );
+    app().add_job(job.build()).build()
+}
+
+/// Every component in a job of its own.
+pub fn configure_strict_microservices() -> AppConfig {
+    COMPONENTS
+        .iter()
+        .fold(app(), |app, (_, component)| {
+            app.add_job(JobBuilder::new().add_component(component()))
+        })
+        .build()
+}
+
+/// Builds the topology described by the file at `path`.
+pub fn configure_from_file(path: &str) -> Result<AppConfig, TopologyError> {
+    let text =
+        std::fs::read_to_string(path).map_err(|e| TopologyError::Read(path.to_owned(), e))?;
+    let file: TopologyFile =
+        toml::from_str(&text).map_err(|e| TopologyError::Parse(path.to_owned(), e))?;
+
+    let names = component_names();
+    let mut placed = HashSet::new();
+    for job in file.jobs.iter() {
+        for name in job.components.iter() {
+            if !names.contains(&name.as_str()) {
+                return Err(TopologyError::UnknownComponent(name.clone()));
+            }
+            if !placed.insert(name.as_str()) {
+                return Err(TopologyError::DuplicateComponent(name.clone()));
+            }
+        }
+        if job.label.is_none() && job.components.len() > 1 {
+            return Err(TopologyError::MissingLabel(job.components.clone()));
+        }
+    }
+
+    let specs: Vec<&JobSpec> = file
+        .jobs
+        .iter()
+        .filter(|x| !x.components.is_empty())
+        .collect();
+    let mut jobs: Vec<JobBuilder> = specs
+        .iter()
+        .map(|spec| match &spec.label {
+            Some(label) => JobBuilder::new().with_label(label.as_str()),
+            None => JobBuilder::new(),
+        })
+        .collect();
+    let mut alone = Vec::new();
+    for (label, component) in COMPONENTS.iter() {
+        match specs
+            .iter()
+            .position(|x| x.components.iter().any(|c| c == label))
+        {
+            Some(i) => {
+                let job = std::mem::replace(&mut jobs[i], JobBuilder::new());
+                jobs[i] = job.add_component(component());
+            }
+            None => alone.push(JobBuilder::new().add_component(component())),
+        }
+    }
+    let app = jobs
+        .into_iter()
+        .chain(alone)
+        .fold(app(), |app, job| app.add_job(job.build()));
+    Ok(app.build())
+}
+
+/// Builds the topology with the given name: `monolith`, `microservices`,
+/// or the path of a topology file.
+pub fn configure_named(name: &str) -> Result<AppConfig, TopologyError> {
+    match name {
+        "monolith" => Ok(configure_strict_monolith()),
+        "microservices" => Ok(configure_strict_microservices()),
+        path => configure_from_file(path),
+    }
+}
+
+/// Returns the topology name from `--topology`, then `BOUTIQUE_TOPOLOGY`,
+/// falling back to one job per component.
+pub fn selected() -> String {
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if let Some(name) = arg.strip_prefix("--topology=") {
+            return name.to_owned();
+        }
+        if arg == "--topology"
+            && let Some(name) = args.next()
+        {
+            return name;
+        }
+    }
+    std::env::var("BOUTIQUE_TOPOLOGY").unwrap_or_else(|_| DEFAULT_TOPOLOGY.to_owned())
+}